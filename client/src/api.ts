@@ -1,5 +1,6 @@
 import { z } from 'zod'
-import { useMutation, useQuery } from '@tanstack/react-query'
+import { useMutation, useQuery, useQueryClient } from '@tanstack/react-query'
+import { useEffect } from 'react'
 import {
   useLocation,
   useNavigate,
@@ -7,9 +8,125 @@ import {
   type Location,
 } from 'react-router-dom'
 import { useUpdateError, type UpdateError } from '@/utils/error'
-import { CARD_NAMES } from './pages/Home'
+import { CARD_NAMES, type CardName } from './pages/Home'
+import {
+  apiError,
+  fieldError,
+  oidcRedirect,
+  token,
+  type ApiError,
+  type Credentials,
+  type OidcCallback,
+  type Signup,
+  type Token,
+  type TotpLogin,
+} from './schema'
+
+type Endpoint =
+  | 'login'
+  | 'signup'
+  | 'refresh'
+  | 'oidc'
+  | 'logout'
+  | 'cards'
+  | 'events'
+
+export const storeTokens = ({ token, refreshToken }: Token) => {
+  localStorage.setItem('token', token)
+  localStorage.setItem('refreshToken', refreshToken)
+}
+
+export const clearTokens = () => {
+  localStorage.removeItem('token')
+  localStorage.removeItem('refreshToken')
+}
+
+// shared so that concurrent requests don't reuse (and thereby revoke) the same refresh token
+let refreshing: Promise<boolean> | undefined
+
+const refresh = () =>
+  (refreshing ??= (async () => {
+    const refreshToken = localStorage.getItem('refreshToken')
+    if (!refreshToken) {
+      return false
+    }
+    const res = await fetch('/api/v1/refresh', {
+      method: 'post',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ refreshToken }),
+    })
+    const tokens = res.ok && token.safeParse(await res.json())
+    if (tokens && tokens.success) {
+      storeTokens(tokens.data)
+      return true
+    }
+    return false
+  })().finally(() => {
+    refreshing = undefined
+  }))
+
+// every error the server sends is an ApiError, but proxies in between may send other bodies
+const readError = async (res: Response) => {
+  try {
+    return apiError.parse(await res.clone().json())
+  } catch {
+    return undefined
+  }
+}
+
+// latest version seen per endpoint, sent back as If-Match on writes
+const etags = new Map<Endpoint, string>()
 
-type Endpoint = 'login' | 'signup' | 'logout' | 'cards'
+const authFetch = async (
+  url: string,
+  init: RequestInit,
+  endpoint: Endpoint
+) => {
+  const send = async () => {
+    const headers = new Headers(init.headers)
+    const token = localStorage.getItem('token')
+    if (token) {
+      headers.set('Authorization', `Bearer ${token}`)
+    }
+    const etag = etags.get(endpoint)
+    if (etag && init.method !== 'get') {
+      headers.set('If-Match', etag)
+    }
+    const res = await fetch(url, { ...init, headers })
+    const newEtag = res.headers.get('ETag')
+    if (newEtag) {
+      etags.set(endpoint, newEtag)
+    }
+    return res
+  }
+
+  const res = await send()
+  if (
+    res.status === 401 &&
+    (await readError(res))?.code === 'tokenExpired' &&
+    (await refresh())
+  ) {
+    return send()
+  }
+  return res
+}
+
+// field errors keyed by JSON path, like zod's flattened fieldErrors, new passwords also
+// name the policy rules they broke
+export const fieldErrors = z.record(z.array(fieldError))
+
+// lists the messages of every invalid field, including the rules a new password broke,
+// other errors have a single message
+const errorMessage = ({ code, message, details }: ApiError) => {
+  const fields = fieldErrors.safeParse(details)
+  if (code === 'invalidField' && fields.success) {
+    return Object.values(fields.data)
+      .flat()
+      .map(({ message }) => message)
+      .join('. ')
+  }
+  return message
+}
 
 const handleResponse = async <Res extends z.ZodTypeAny>(
   res: Response,
@@ -33,10 +150,11 @@ const handleResponse = async <Res extends z.ZodTypeAny>(
       }
     }
   } else if (res.status === 401) {
-    localStorage.removeItem('token')
+    clearTokens()
     navigate('/login', { state: { from: location }, replace: true })
   } else {
-    updateError(res.statusText, res.body ? await res.text() : undefined)
+    const error = await readError(res)
+    updateError(res.statusText, error && errorMessage(error))
   }
 }
 
@@ -49,16 +167,12 @@ const query = <Req = null>(endpoint: Endpoint) => {
       const location = useLocation()
       const navigate = useNavigate()
       const updateError = useUpdateError()
-      const url = `/api/${endpoint}?${new URLSearchParams(req ?? {})}`
-      const token = localStorage.getItem('token')
-      const headers: [string, string][] = token
-        ? [['Authorization', `Bearer ${token}`]]
-        : []
+      const url = `/api/v1/${endpoint}?${new URLSearchParams(req ?? {})}`
 
       return useQuery({
         queryKey: ['get', endpoint, req],
         queryFn: async ({ signal }) => {
-          const res = await fetch(url, { signal, method: 'get', headers })
+          const res = await authFetch(url, { signal, method: 'get' }, endpoint)
           return handleResponse(
             res,
             schema,
@@ -72,8 +186,11 @@ const query = <Req = null>(endpoint: Endpoint) => {
     }
 }
 
-const mutate = <Req = null>(method: 'post' | 'delete', endpoint: Endpoint) => {
-  const url = `/api/${endpoint}`
+const mutate = <Req = null>(
+  method: 'post' | 'patch' | 'delete',
+  endpoint: Endpoint,
+  path?: (req: Req) => string
+) => {
   return <Res extends z.ZodTypeAny>(
       schema?: Res,
       cb?: (res: z.infer<Res>) => void
@@ -82,30 +199,42 @@ const mutate = <Req = null>(method: 'post' | 'delete', endpoint: Endpoint) => {
       const location = useLocation()
       const navigate = useNavigate()
       const updateError = useUpdateError()
-      const token = localStorage.getItem('token')
+      const queryClient = useQueryClient()
 
       let headers = new Headers()
-      if (token) {
-        headers.append('Authorization', `Bearer ${token}`)
-      }
-      if (method === 'post') {
+      if (method !== 'delete') {
         headers.append('Content-Type', 'application/json')
       }
       const options = { method, headers }
 
       return useMutation<z.infer<Res>, Error, Req>({
         mutationFn: async (req) => {
+          const url = `/api/v1/${endpoint}${path ? `/${path(req)}` : ''}`
           const res = await (method === 'delete'
-            ? fetch(`${url}?${new URLSearchParams(req ?? {})}`, options)
-            : fetch(
+            ? authFetch(
+                path ? url : `${url}?${new URLSearchParams(req ?? {})}`,
+                options,
+                endpoint
+              )
+            : authFetch(
                 url,
                 req
                   ? {
                       body: JSON.stringify(req),
                       ...options,
                     }
-                  : options
+                  : options,
+                endpoint
               ))
+          if (
+            res.status === 409 &&
+            (await readError(res))?.code === 'staleVersion'
+          ) {
+            // stale write, so start over from the server's version
+            queryClient.invalidateQueries({ queryKey: ['get', endpoint] })
+            updateError('Conflict', 'This was changed in another session')
+            return
+          }
           return handleResponse(
             res,
             schema,
@@ -119,54 +248,141 @@ const mutate = <Req = null>(method: 'post' | 'delete', endpoint: Endpoint) => {
     }
 }
 
-export const MAX_FIELD_LEN = 16
-
-export const credentials = z.object({
-  username: z
-    .string()
-    .min(4, {
-      message: 'Username must be at least 4 characters',
-    })
-    .max(MAX_FIELD_LEN, { message: 'Username is too long' })
-    .regex(/^[a-zA-Z0-9_]*$/, {
-      message: 'Username must only contain letters, numbers, and underscores',
-    }),
-  password: z
-    .string()
-    .min(6, {
-      message: 'Password must be at least 6 characters',
-    })
-    .max(MAX_FIELD_LEN, { message: 'Password is too long' })
-    .regex(/^^\S*$/, { message: "Password can't contain spaces" }),
+const card = z.object({
+  name: z.string().transform((name, ctx) => {
+    const index = (CARD_NAMES as string[]).indexOf(name)
+    if (index === -1) {
+      ctx.addIssue({
+        code: 'invalid_literal',
+        expected: CARD_NAMES.join(' | '),
+        received: name,
+        message: `Invalid card name: ${name}`,
+        path: ['name'],
+      })
+    }
+    return CARD_NAMES[index]
+  }),
+  id: z.string(),
+  state: z.unknown().optional(),
 })
-export type Credentials = z.infer<typeof credentials>
-
-const token = z.object({ token: z.string() })
+const cardsLayout = z.array(card)
+export type CardsLayout = z.infer<typeof cardsLayout>
 
-const cardsLayout = z.array(
+const cardEvent = z.discriminatedUnion('type', [
+  z.object({
+    type: z.literal('cardAdded'),
+    version: z.number(),
+    pos: z.number(),
+    card,
+  }),
+  z.object({
+    type: z.literal('cardUpdated'),
+    version: z.number(),
+    pos: z.number(),
+    card,
+  }),
   z.object({
-    name: z.string().transform((name, ctx) => {
-      const index = (CARD_NAMES as string[]).indexOf(name)
-      if (index === -1) {
-        ctx.addIssue({
-          code: 'invalid_literal',
-          expected: CARD_NAMES.join(' | '),
-          received: name,
-          message: `Invalid card name: ${name}`,
-          path: ['name'],
-        })
+    type: z.literal('cardRemoved'),
+    version: z.number(),
+    id: z.string(),
+  }),
+  z.object({ type: z.literal('resync') }),
+])
+export type CardEvent = Exclude<z.infer<typeof cardEvent>, { type: 'resync' }>
+
+const parseEvents = async function* (body: ReadableStream<Uint8Array>) {
+  const reader = body.pipeThrough(new TextDecoderStream()).getReader()
+  let buffer = ''
+  for (;;) {
+    const { value, done } = await reader.read()
+    if (done) {
+      return
+    }
+    const messages = (buffer + value).split('\n\n')
+    buffer = messages.pop() ?? ''
+    for (const message of messages) {
+      const data = message
+        .split('\n')
+        .filter((line) => line.startsWith('data:'))
+        .map((line) => line.slice('data:'.length).trimStart())
+        .join('\n')
+      if (data) {
+        yield JSON.parse(data) as unknown
       }
-      return CARD_NAMES[index]
-    }),
-    id: z.number(),
-  })
-)
-export type CardsLayout = z.infer<typeof cardsLayout>
+    }
+  }
+}
 
-export const usePostLogin = mutate<Credentials>('post', 'login')(token)
-export const usePostSignup = mutate<Credentials>('post', 'signup')(token)
+// accounts with two-factor authentication get a challenge to complete with a code instead
+const login = z.union([token, z.object({ challenge: z.string() })])
+
+export const usePostLogin = mutate<Credentials>('post', 'login')(login)
+export const usePostLoginTotp = mutate<TotpLogin>(
+  'post',
+  'login',
+  () => 'totp'
+)(token)
+export const usePostSignup = mutate<Signup>('post', 'signup')(token)
+
+// single sign-on sends the user to the identity provider, which redirects back to the login page
+export const usePostOidcAuthorize = mutate('post', 'oidc', () => 'authorize')(
+  oidcRedirect
+)
+export const usePostOidcCallback = mutate<OidcCallback>(
+  'post',
+  'oidc',
+  () => 'callback'
+)(token)
 export const usePostLogout = mutate('post', 'logout')()
 
+// applies card changes made in other sessions, refetching the layout if any were missed
+export const useCardEvents = (cb: (event: CardEvent) => void) => {
+  const queryClient = useQueryClient()
+
+  useEffect(() => {
+    const controller = new AbortController()
+    const resync = () =>
+      queryClient.invalidateQueries({ queryKey: ['get', 'cards'] })
+
+    const listen = async () => {
+      const res = await authFetch(
+        '/api/v1/events',
+        { method: 'get', signal: controller.signal },
+        'events'
+      )
+      if (!res.ok || !res.body) {
+        return
+      }
+      for await (const data of parseEvents(res.body)) {
+        const event = cardEvent.safeParse(data)
+        if (!event.success) {
+          continue
+        }
+        const version = Number(etags.get('cards')?.replaceAll('"', ''))
+        if (event.data.type === 'resync' || event.data.version > version + 1) {
+          resync()
+        } else if (event.data.version === version + 1) {
+          etags.set('cards', `"${event.data.version}"`)
+          cb(event.data)
+        }
+      }
+    }
+    listen().catch(() => {})
+
+    return () => controller.abort()
+  }, [])
+}
+
 export const useGetCardsLayout = (cb: (res: CardsLayout) => void) =>
   query('cards')(cardsLayout, cb)(null)
-export const usePostCardsLayout = mutate<CardsLayout>('post', 'cards')()
+
+type CardRef = { id: string }
+const cardPath = ({ id }: CardRef) => id
+
+export const usePostCard = mutate<
+  CardRef & { name: CardName; pos?: number; state?: unknown }
+>('post', 'cards', cardPath)()
+export const usePatchCard = mutate<
+  CardRef & { pos?: number; state?: unknown }
+>('patch', 'cards', cardPath)()
+export const useDeleteCard = mutate<CardRef>('delete', 'cards', cardPath)()
@@ -38,6 +38,16 @@ const newState = (): State => ({
   decimal: 0,
 })
 
+// decimals are stored on the server as strings
+const reviveState = (state?: State) =>
+  state &&
+  (Object.fromEntries(
+    Object.entries(state).map(([key, value]) => [
+      key,
+      ['first', 'second', 'result'].includes(key) ? new Decimal(value) : value,
+    ])
+  ) as State)
+
 const calculate = ({
   first,
   second,
@@ -164,11 +174,13 @@ export default function Calculator({
   state: maybeState,
   setState,
 }: CardProps<State>) {
-  const state = maybeState ?? newState()
+  const state = reviveState(maybeState) ?? newState()
   const updateError = useUpdateError()
   const onInput = (input: Input) => () => {
     try {
-      setState((maybeState) => updateState(input, maybeState ?? newState()))
+      setState((maybeState) =>
+        updateState(input, reviveState(maybeState) ?? newState())
+      )
     } catch (error) {
       updateError(
         'Calculator',
@@ -1,4 +1,11 @@
-import { useGetCardsLayout, usePostCardsLayout } from '@/api'
+import {
+  useCardEvents,
+  useDeleteCard,
+  useGetCardsLayout,
+  usePatchCard,
+  usePostCard,
+  type CardEvent,
+} from '@/api'
 import { Button } from '@/utils/button'
 import {
   createContext,
@@ -37,7 +44,7 @@ const CARDS = memoize({
   calculator: Calculator,
 })
 
-type CardName = keyof typeof CARDS
+export type CardName = keyof typeof CARDS
 
 export const CARD_NAMES = Object.keys(CARDS) as CardName[]
 
@@ -49,7 +56,7 @@ type CardStates = {
 }
 
 type Card = {
-  [N in CardName]: { name: N; id: number; state?: CardStates[N] }
+  [N in CardName]: { name: N; id: string; state?: CardStates[N] }
 }[CardName]
 
 type SetCardState<N extends CardName> = CardProps<CardStates[N]>['setState']
@@ -60,8 +67,9 @@ type CardsStore = {
   moveDown: (pos: number) => void
   moveUp: (pos: number) => void
   remove: (pos: number) => void
-  setState: <N extends CardName>(name: N, id: number) => SetCardState<N>
-  addCard: (name: CardName) => void
+  setState: <N extends CardName>(name: N, id: string) => SetCardState<N>
+  addCard: (name: CardName, id: string) => void
+  applyEvent: (event: CardEvent) => void
 }
 
 type Resizer = {
@@ -118,21 +126,24 @@ const useCardsStore = create<CardsStore & Resizer>((set) => ({
       ) as Card[],
     })),
 
-  addCard: (name) =>
-    set(({ cards }) => ({
-      cards: [
-        ...cards,
-        {
-          name,
-          id:
-            cards.reduce(
-              (maxId, card) =>
-                card.name === name && card.id > maxId ? card.id : maxId,
-              -1
-            ) + 1,
-        },
-      ],
-    })),
+  addCard: (name, id) =>
+    set(({ cards }) => ({ cards: [...cards, { name, id }] })),
+
+  applyEvent: (event) =>
+    set(({ cards }) => {
+      if (event.type === 'cardRemoved') {
+        return { cards: cards.filter((card) => card.id !== event.id) }
+      }
+      const { id, name, state } = event.card
+      const rest = cards.filter((card) => card.id !== id)
+      return {
+        cards: [
+          ...rest.slice(0, event.pos),
+          { id, name, state: (state ?? undefined) as CardStates[typeof name] },
+          ...rest.slice(event.pos),
+        ] as Card[],
+      }
+    }),
 
   resizeSignal: false,
 
@@ -153,7 +164,16 @@ const useCardsStore = create<CardsStore & Resizer>((set) => ({
     },
 }))
 
-const getSetCardState = <N extends CardName>(name: N, id: number) =>
+const ID_CHARS =
+  'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789'
+
+// matches the server's card id format
+const newCardId = () =>
+  Array.from(crypto.getRandomValues(new Uint8Array(16)))
+    .map((byte) => ID_CHARS[byte % ID_CHARS.length])
+    .join('')
+
+const getSetCardState = <N extends CardName>(name: N, id: string) =>
   useCardsStore.getState().setState(name, id)
 
 const CardContext = createContext({ pos: 0 })
@@ -162,22 +182,41 @@ const useCards = () => {
   const resize = useResize()
   const init = useCardsStore((state) => state.init)
   const cards = useCardsStore((state) => state.cards)
+  const applyEvent = useCardsStore((state) => state.applyEvent)
 
   useGetCardsLayout(
     resize((serverCards) =>
-      init(serverCards.map(({ name, id }) => ({ name, id })))
+      init(
+        serverCards.map(({ name, id, state }) => ({
+          name,
+          id,
+          state: (state ?? undefined) as CardStates[typeof name],
+        }))
+      )
     )
   )
+  useCardEvents(resize(applyEvent))
 
   return cards
 }
 
-const cardsToJsx = (cards: Card[]) =>
+const cardsToJsx = (
+  cards: Card[],
+  save: (card: { id: string; state?: unknown }) => void
+) =>
   cards.map(({ name, state, id }, pos) => {
     const Card = CARDS[name]
-    const setState = getSetCardState(name, id)
+    const setCardState = getSetCardState(name, id)
+    const setState: typeof setCardState = (update) => {
+      setCardState(update)
+      save({
+        id,
+        state: useCardsStore.getState().cards.find((card) => card.id === id)
+          ?.state,
+      })
+    }
     return (
-      <div className='card' key={pos}>
+      <div className='card' key={id}>
         <CardContext.Provider value={{ pos }}>
           {/* typescript can't narrow the card state */}
           <Card
@@ -224,40 +263,47 @@ const useResizer = (cardsRef: RefObject<HTMLDivElement>) => {
 export const useCardActions = () => {
   const { pos } = useContext(CardContext)
   const resize = useResize()
-  const { mutate } = usePostCardsLayout()
+  const { mutate: patch } = usePatchCard()
+  const { mutate: remove } = useDeleteCard()
 
   const moveDown = useCardsStore((state) => state.moveDown)
   const moveUp = useCardsStore((state) => state.moveUp)
-  const remove = useCardsStore((state) => state.remove)
+  const removeCard = useCardsStore((state) => state.remove)
 
-  const newAction = (action: (pos: number) => void) =>
+  const newAction = (
+    action: (pos: number) => void,
+    save: (id: string) => void
+  ) =>
     resize(() => {
+      const { id } = useCardsStore.getState().cards[pos]
       action(pos)
-      mutate(useCardsStore.getState().cards)
+      save(id)
     })
 
   return {
-    moveDown: newAction(moveDown),
-    moveUp: newAction(moveUp),
-    remove: newAction(remove),
+    moveDown: newAction(moveDown, (id) => patch({ id, pos: pos + 1 })),
+    moveUp: newAction(moveUp, (id) => patch({ id, pos: pos - 1 })),
+    remove: newAction(removeCard, (id) => remove({ id })),
   }
 }
 
 const useAddCard = () => {
   const resize = useResize()
   const addCard = useCardsStore((state) => state.addCard)
-  const { mutate } = usePostCardsLayout()
+  const { mutate } = usePostCard()
 
   return (name: CardName) =>
     resize(() => {
-      addCard(name)
-      mutate(useCardsStore.getState().cards)
+      const id = newCardId()
+      addCard(name, id)
+      mutate({ id, name })
     })
 }
 
 export default function Home() {
   const logout = useLogout()
-  const cards = cardsToJsx(useCards())
+  const { mutate } = usePatchCard()
+  const cards = cardsToJsx(useCards(), mutate)
   const cardsRef = useRef<HTMLDivElement>(null)
   const addCard = useAddCard()
 
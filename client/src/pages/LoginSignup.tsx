@@ -1,8 +1,21 @@
-import { MAX_FIELD_LEN, usePostLogin, usePostSignup } from '@/api'
-import { useLocation, useNavigate } from 'react-router-dom'
+import {
+  storeTokens,
+  usePostLogin,
+  usePostLoginTotp,
+  usePostOidcAuthorize,
+  usePostOidcCallback,
+  usePostSignup,
+} from '@/api'
+import { useLocation, useNavigate, useSearchParams } from 'react-router-dom'
 import { zodResolver } from '@hookform/resolvers/zod'
 import { useForm } from 'react-hook-form'
-import { credentials, type Credentials } from '@/api'
+import {
+  MAX_USERNAME_LEN,
+  credentials,
+  signup,
+  type Credentials,
+  type Token,
+} from '@/schema'
 import { Button } from '@/utils/button'
 import { Switch } from '@/utils/switch'
 import { Input } from '@/utils/input'
@@ -15,32 +28,74 @@ import {
   FormLabel,
   FormMessage,
 } from '@/utils/form'
-import { useState, type ChangeEvent } from 'react'
+import { useEffect, useRef, useState, type ChangeEvent } from 'react'
 import Loading from '@/utils/loading'
 
 export default function LoginSignup() {
   const [signingUp, setSigningUp] = useState(false)
   const { mutate: postLogin, isPending: isLoginPending } = usePostLogin()
   const { mutate: postSignup, isPending: isSignupPending } = usePostSignup()
-  const isPending = isLoginPending || isSignupPending
+  const { mutate: postLoginTotp, isPending: isTotpPending } = usePostLoginTotp()
+  const { mutate: postOidcAuthorize } = usePostOidcAuthorize()
+  const { mutate: postOidcCallback, isPending: isOidcPending } =
+    usePostOidcCallback()
+  const isPending =
+    isLoginPending || isSignupPending || isTotpPending || isOidcPending
+  const [searchParams] = useSearchParams()
+  const oidcStarted = useRef(false)
   const { state } = useLocation()
   const navigate = useNavigate()
-  const form = useForm<Credentials>({
-    resolver: zodResolver(credentials),
+  // new passwords are checked against the password policy, the context is updated every render
+  const form = useForm<Credentials, { signingUp: boolean }>({
+    resolver: (values, context, options) =>
+      zodResolver(context?.signingUp ? signup : credentials)(
+        values,
+        context,
+        options
+      ),
+    context: { signingUp },
     defaultValues: { username: '', password: '' },
   })
   const [confirmPassword, setConfirmPassword] = useState('')
   const [passwordMismatch, setPasswordMismatch] = useState(false)
+  const [challenge, setChallenge] = useState<string>()
+  const [code, setCode] = useState('')
+
+  const onTokens = (tokens: Token) => {
+    storeTokens(tokens)
+    navigate(state?.from?.pathname || '/', { replace: true })
+  }
+
+  // the identity provider redirects back here with a code to log in with, which only works once
+  useEffect(() => {
+    const code = searchParams.get('code')
+    const state = searchParams.get('state')
+    if (code && state && !oidcStarted.current) {
+      oidcStarted.current = true
+      postOidcCallback({ code, state }, { onSuccess: onTokens })
+    }
+  })
+
+  const onSingleSignOn = () =>
+    postOidcAuthorize(null, {
+      onSuccess: (res) => res && window.location.assign(res.url),
+    })
 
   const onSubmit = (credentials: Credentials) => {
-    const post = signingUp ? postSignup : postLogin
-    if (signingUp && credentials.password !== confirmPassword) {
+    if (challenge) {
+      postLoginTotp({ challenge, code }, { onSuccess: onTokens })
+    } else if (signingUp && credentials.password !== confirmPassword) {
       setPasswordMismatch(true)
+    } else if (signingUp) {
+      postSignup(credentials, { onSuccess: onTokens })
     } else {
-      post(credentials, {
-        onSuccess: ({ token }) => {
-          localStorage.setItem('token', token)
-          navigate(state?.from?.pathname || '/', { replace: true })
+      postLogin(credentials, {
+        onSuccess: (res) => {
+          if (res && 'challenge' in res) {
+            setChallenge(res.challenge)
+          } else {
+            onTokens(res)
+          }
         },
       })
     }
@@ -58,7 +113,7 @@ export default function LoginSignup() {
             name='username'
             render={({ field }) => {
               field.value = field.value
-                .substring(0, MAX_FIELD_LEN)
+                .substring(0, MAX_USERNAME_LEN)
                 .replace(/[^a-zA-Z0-9]/, '')
               return (
                 <FormItem>
@@ -79,20 +134,15 @@ export default function LoginSignup() {
           <FormField
             control={form.control}
             name='password'
-            render={({ field }) => {
-              field.value = field.value
-                .substring(0, MAX_FIELD_LEN)
-                .replace(' ', '')
-              return (
-                <FormItem>
-                  <FormLabel>Password</FormLabel>
-                  <FormControl>
-                    <Input type='password' {...field} />
-                  </FormControl>
-                  <FormMessage />
-                </FormItem>
-              )
-            }}
+            render={({ field }) => (
+              <FormItem>
+                <FormLabel>Password</FormLabel>
+                <FormControl>
+                  <Input type='password' {...field} />
+                </FormControl>
+                <FormMessage />
+              </FormItem>
+            )}
           />
           {signingUp && (
             <FormItem className='animate-enter'>
@@ -112,15 +162,44 @@ export default function LoginSignup() {
               </FormMessage>
             </FormItem>
           )}
+          {challenge && (
+            <FormItem className='animate-enter'>
+              <FormLabel>Authentication Code</FormLabel>
+              <Input
+                autoComplete='one-time-code'
+                placeholder='code or recovery code...'
+                onChange={(event: ChangeEvent<HTMLInputElement>) =>
+                  setCode(event.target.value)
+                }
+                value={code}
+              />
+            </FormItem>
+          )}
           <div className='flex items-center justify-between'>
             <Button size='md' type='submit'>
-              {isPending ? <Loading /> : signingUp ? 'Sign up' : 'Login'}
+              {isPending ? (
+                <Loading />
+              ) : challenge ? (
+                'Verify'
+              ) : signingUp ? (
+                'Sign up'
+              ) : (
+                'Login'
+              )}
             </Button>
             <div className='flex gap-2 items-center'>
               <Switch checked={signingUp} onCheckedChange={setSigningUp} />
               <Label>Sign up</Label>
             </div>
           </div>
+          <Button
+            className='w-full'
+            variant='outline'
+            type='button'
+            onClick={onSingleSignOn}
+          >
+            Single sign-on
+          </Button>
         </form>
       </Form>
     </div>
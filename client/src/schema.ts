@@ -0,0 +1,381 @@
+// Generated by `cargo run -- generate-client`, change the types in `schema::api` instead
+
+import { z } from 'zod'
+
+export const MIN_USERNAME_LEN = 4
+export const MAX_USERNAME_LEN = 16
+export const MAX_NOTE_TITLE_LEN = 200
+export const MAX_NOTE_BODY_LEN = 65536
+export const MAX_TODO_TEXT_LEN = 1000
+export const MAX_EXPR_LEN = 1000
+
+/** What went wrong with a request, for clients to tell errors apart by */
+export const errorCode = z.enum(['internal', 'payloadTooLarge', 'rateLimited', 'unauthenticated', 'tokenExpired', 'tokenRevoked', 'invalidToken', 'invalidRefreshToken', 'csrfFailed', 'permissionDenied', 'accountDisabled', 'invalidCredentials', 'invalidPassword', 'usernameTaken', 'invalidCode', 'loginExpired', 'totpNotEnabled', 'totpAlreadyEnabled', 'noPendingTotp', 'invalidResetCode', 'oidcDisabled', 'identityLinked', 'cannotDisableSelf', 'userNotFound', 'sessionNotFound', 'cardNotFound', 'noteNotFound', 'todoNotFound', 'cardExists', 'wrongCardKind', 'invalidCardState', 'cardActionFailed', 'invalidMove', 'invalidRequest', 'invalidField', 'totpRequired', 'reauthenticationRequired', 'versionRequired', 'staleVersion', 'invalidExpression'])
+export type ErrorCode = z.infer<typeof errorCode>
+
+/** An error response, along the lines of RFC 7807 problem details */
+export const apiError = z.object({
+  code: errorCode,
+  details: z.unknown().optional(),
+  /** The request field the error is about */
+  field: z.string().nullable().optional(),
+  message: z.string(),
+})
+export type ApiError = z.infer<typeof apiError>
+
+export const calcEval = z.object({
+  expr: z.string(),
+  /** Values of the variables used in the expression, as decimal strings */
+  vars: z.record(z.string()).optional(),
+})
+export type CalcEval = z.infer<typeof calcEval>
+
+export const calculationId = z.string().min(16).max(16)
+export type CalculationId = z.infer<typeof calculationId>
+
+/** An evaluated expression from a user's calculation history */
+export const calculation = z.object({
+  createdAt: z.number().int(),
+  expr: z.string(),
+  id: calculationId,
+  result: z.string(),
+})
+export type Calculation = z.infer<typeof calculation>
+
+export const cardId = z.string().min(16).max(16)
+export type CardId = z.infer<typeof cardId>
+
+export const cardName = z.enum(['calculator', 'notes', 'todo'])
+export type CardName = z.infer<typeof cardName>
+
+export const card = z.object({
+  id: cardId,
+  name: cardName,
+  state: z.unknown().optional(),
+})
+export type Card = z.infer<typeof card>
+
+export const cardAction = z.object({
+  action: z.string(),
+  args: z.unknown().optional(),
+})
+export type CardAction = z.infer<typeof cardAction>
+
+export const cardUpdate = z.object({
+  pos: z.number().int().nullable().optional(),
+  state: z.unknown().optional(),
+})
+export type CardUpdate = z.infer<typeof cardUpdate>
+
+export const credentials = z.object({
+  password: z.string().min(1, { message: 'Password is required' }),
+  username: z.string().min(4, { message: 'Username must be at least 4 characters' }).max(16, { message: 'Username is too long' }).regex(/^[a-zA-Z0-9_]*$/, { message: 'Username must only contain letters, numbers, and underscores' }),
+})
+export type Credentials = z.infer<typeof credentials>
+
+/** The token to repeat in the `X-CSRF-Token` header of requests authenticated by cookie */
+export const csrfToken = z.object({
+  csrfToken: z.string(),
+})
+export type CsrfToken = z.infer<typeof csrfToken>
+
+/** A change to a user's cards, pushed to their open event streams */
+export const event = z.union([
+  z.object({
+    card: card,
+    pos: z.number().int(),
+    type: z.literal('cardAdded'),
+    version: z.number().int(),
+  }),
+  z.object({
+    card: card,
+    pos: z.number().int(),
+    type: z.literal('cardUpdated'),
+    version: z.number().int(),
+  }),
+  z.object({
+    id: cardId,
+    type: z.literal('cardRemoved'),
+    version: z.number().int(),
+  }),
+  z.object({
+    type: z.literal('resync'),
+  }),
+])
+export type Event = z.infer<typeof event>
+
+/** The range of characters of an expression that could not be evaluated */
+export const exprSpan = z.object({
+  end: z.number().int().min(0),
+  start: z.number().int().min(0),
+})
+export type ExprSpan = z.infer<typeof exprSpan>
+
+export const passwordRule = z.enum(['minLength', 'maxLength', 'characterClasses', 'entropy', 'blocklist'])
+export type PasswordRule = z.infer<typeof passwordRule>
+
+/** Something wrong with a field of a request */
+export const fieldError = z.object({
+  message: z.string(),
+  /** The password policy rule a new password broke */
+  rule: z.union([
+    passwordRule,
+    z.null(),
+  ]).optional(),
+})
+export type FieldError = z.infer<typeof fieldError>
+
+/** A user's cards at a layout version */
+export const layout = z.object({
+  cards: z.array(card),
+  version: z.number().int(),
+})
+export type Layout = z.infer<typeof layout>
+
+export const token = z.object({
+  refreshToken: z.string(),
+  token: z.string(),
+})
+export type Token = z.infer<typeof token>
+
+/**
+ * The tokens of a new session, or a challenge to complete with a one-time code
+ * if the account has two-factor authentication enabled
+ */
+export const login = z.union([
+  token,
+  csrfToken,
+  z.object({
+    challenge: z.string(),
+  }),
+])
+export type Login = z.infer<typeof login>
+
+export const newCard = z.object({
+  name: cardName,
+  pos: z.number().int().nullable().optional(),
+  state: z.unknown().optional(),
+})
+export type NewCard = z.infer<typeof newCard>
+
+export const newNote = z.object({
+  body: z.string(),
+  card: cardId,
+  title: z.string(),
+})
+export type NewNote = z.infer<typeof newNote>
+
+/** A password set by an administrator */
+export const newPassword = z.object({
+  password: z.string().min(8, { message: 'Password must be at least 8 characters' }).max(128, { message: 'Password must be at most 128 characters' }),
+})
+export type NewPassword = z.infer<typeof newPassword>
+
+export const newTodo = z.object({
+  card: cardId,
+  dueAt: z.number().int().nullable().optional(),
+  text: z.string(),
+})
+export type NewTodo = z.infer<typeof newTodo>
+
+export const noteId = z.string().min(16).max(16)
+export type NoteId = z.infer<typeof noteId>
+
+export const note = z.object({
+  body: z.string(),
+  card: cardId,
+  createdAt: z.number().int(),
+  id: noteId,
+  title: z.string(),
+  updatedAt: z.number().int(),
+})
+export type Note = z.infer<typeof note>
+
+/** A note matching a search. Its snippets are escaped HTML with the matched words in `<mark>`. */
+export const noteMatch = z.object({
+  body: z.string(),
+  card: cardId,
+  id: noteId,
+  rank: z.number(),
+  title: z.string(),
+})
+export type NoteMatch = z.infer<typeof noteMatch>
+
+export const noteSearch = z.object({
+  q: z.string(),
+})
+export type NoteSearch = z.infer<typeof noteSearch>
+
+export const noteUpdate = z.object({
+  body: z.string().nullable().optional(),
+  title: z.string().nullable().optional(),
+})
+export type NoteUpdate = z.infer<typeof noteUpdate>
+
+export const notesQuery = z.object({
+  card: cardId,
+})
+export type NotesQuery = z.infer<typeof notesQuery>
+
+/** What the identity provider redirected back to the client with */
+export const oidcCallback = z.object({
+  code: z.string(),
+  state: z.string(),
+})
+export type OidcCallback = z.infer<typeof oidcCallback>
+
+/** The identity provider's login page to send the user to */
+export const oidcRedirect = z.object({
+  url: z.string(),
+})
+export type OidcRedirect = z.infer<typeof oidcRedirect>
+
+export const passwordChange = z.object({
+  newPassword: z.string().min(8, { message: 'Password must be at least 8 characters' }).max(128, { message: 'Password must be at most 128 characters' }),
+  /** Ignored for accounts without a password, which set one by logging in recently instead */
+  oldPassword: z.string(),
+})
+export type PasswordChange = z.infer<typeof passwordChange>
+
+/** Confirms a sensitive change to an account, such as deleting it */
+export const passwordConfirmation = z.object({
+  /** Ignored for accounts without a password, which confirm by logging in recently instead */
+  password: z.string(),
+})
+export type PasswordConfirmation = z.infer<typeof passwordConfirmation>
+
+export const passwordReset = z.object({
+  code: z.string(),
+  newPassword: z.string().min(8, { message: 'Password must be at least 8 characters' }).max(128, { message: 'Password must be at most 128 characters' }),
+})
+export type PasswordReset = z.infer<typeof passwordReset>
+
+/** Single-use codes for when the authenticator app is lost, only ever shown once */
+export const recoveryCodes = z.object({
+  codes: z.array(z.string()),
+})
+export type RecoveryCodes = z.infer<typeof recoveryCodes>
+
+export const refresh = z.object({
+  /** Taken from the cookie if missing */
+  refreshToken: z.string().nullable().optional(),
+})
+export type Refresh = z.infer<typeof refresh>
+
+/** A single-use code for a user to set a new password with, to be passed on to them */
+export const resetCode = z.object({
+  code: z.string(),
+  expiresAt: z.number().int(),
+})
+export type ResetCode = z.infer<typeof resetCode>
+
+/** What a user is allowed to do, each role allowing everything the previous ones do */
+export const role = z.enum(['user', 'admin'])
+export type Role = z.infer<typeof role>
+
+export const sessionId = z.string().min(16).max(16)
+export type SessionId = z.infer<typeof sessionId>
+
+/** A device the user is logged in on */
+export const session = z.object({
+  createdAt: z.number().int(),
+  /** Whether this is the session the request was made with */
+  current: z.boolean(),
+  id: sessionId,
+  ip: z.string().nullable().optional(),
+  lastSeenAt: z.number().int().nullable().optional(),
+  userAgent: z.string().nullable().optional(),
+})
+export type Session = z.infer<typeof session>
+
+/** The credentials of a new account, whose password has to meet the password policy */
+export const signup = z.object({
+  password: z.string().min(8, { message: 'Password must be at least 8 characters' }).max(128, { message: 'Password must be at most 128 characters' }),
+  username: z.string().min(4, { message: 'Username must be at least 4 characters' }).max(16, { message: 'Username is too long' }).regex(/^[a-zA-Z0-9_]*$/, { message: 'Username must only contain letters, numbers, and underscores' }),
+})
+export type Signup = z.infer<typeof signup>
+
+export const todoId = z.string().min(16).max(16)
+export type TodoId = z.infer<typeof todoId>
+
+export const todo = z.object({
+  card: cardId,
+  completedAt: z.number().int().nullable().optional(),
+  createdAt: z.number().int(),
+  dueAt: z.number().int().nullable().optional(),
+  id: todoId,
+  text: z.string(),
+})
+export type Todo = z.infer<typeof todo>
+
+/** Moves an item to just after another one of the same card, or to the top */
+export const todoMove = z.object({
+  after: z.union([
+    todoId,
+    z.null(),
+  ]).optional(),
+})
+export type TodoMove = z.infer<typeof todoMove>
+
+export const todoUpdate = z.object({
+  completed: z.boolean().nullable().optional(),
+  dueAt: z.number().int().nullable().optional(),
+  text: z.string().nullable().optional(),
+})
+export type TodoUpdate = z.infer<typeof todoUpdate>
+
+export const todosQuery = z.object({
+  card: cardId,
+})
+export type TodosQuery = z.infer<typeof todosQuery>
+
+export const totpCode = z.object({
+  code: z.string(),
+})
+export type TotpCode = z.infer<typeof totpCode>
+
+export const totpEnrollment = z.object({
+  secret: z.string(),
+  uri: z.string(),
+})
+export type TotpEnrollment = z.infer<typeof totpEnrollment>
+
+export const totpLogin = z.object({
+  challenge: z.string(),
+  /** A code from the authenticator app or one of the recovery codes */
+  code: z.string(),
+})
+export type TotpLogin = z.infer<typeof totpLogin>
+
+export const userId = z.string().min(16).max(16)
+export type UserId = z.infer<typeof userId>
+
+/** A user as administrators see them */
+export const userSummary = z.object({
+  /** Number of cards in their layout */
+  cards: z.number().int(),
+  disabledAt: z.number().int().nullable().optional(),
+  id: userId,
+  role: role,
+  username: z.string(),
+})
+export type UserSummary = z.infer<typeof userSummary>
+
+export const userUpdate = z.object({
+  /** Disabling an account logs it out everywhere and prevents logging in */
+  disabled: z.boolean().nullable().optional(),
+})
+export type UserUpdate = z.infer<typeof userUpdate>
+
+export const usernameChange = z.object({
+  username: z.string().min(4, { message: 'Username must be at least 4 characters' }).max(16, { message: 'Username is too long' }).regex(/^[a-zA-Z0-9_]*$/, { message: 'Username must only contain letters, numbers, and underscores' }),
+})
+export type UsernameChange = z.infer<typeof usernameChange>
+
+export const usersQuery = z.object({
+  limit: z.number().int().nullable().optional(),
+  offset: z.number().int().nullable().optional(),
+  /** Only users whose username contains this */
+  q: z.string().nullable().optional(),
+})
+export type UsersQuery = z.infer<typeof usersQuery>
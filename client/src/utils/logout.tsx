@@ -1,4 +1,4 @@
-import { usePostLogout } from '@/api'
+import { clearTokens, usePostLogout } from '@/api'
 import { useNavigate } from 'react-router-dom'
 import { Button } from '@/utils/button'
 
@@ -12,7 +12,7 @@ export default function useLogout() {
       onClick={() =>
         mutate(null, {
           onSuccess: () => {
-            localStorage.removeItem('token')
+            clearTokens()
             navigate('/login')
           },
         })
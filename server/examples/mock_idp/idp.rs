@@ -0,0 +1,184 @@
+//! The endpoints of the mock provider, which the server's tests run it with too
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rand::{distributions::Alphanumeric, prelude::*};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+const KID: &str = "mock";
+/// Lifetime of an ID token in seconds
+const ID_TOKEN_TTL: u64 = 5 * 60;
+
+/// A code handed out by the authorization endpoint, waiting to be exchanged for tokens
+struct Grant {
+    client_id: String,
+    redirect_uri: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    user: String,
+}
+
+#[derive(Clone)]
+struct Idp {
+    issuer: String,
+    user: String,
+    key: Arc<EncodingKey>,
+    /// Public key as the `x` parameter of a JWK
+    public_key: String,
+    grants: Arc<Mutex<HashMap<String, Grant>>>,
+}
+
+#[derive(Deserialize)]
+struct Authorize {
+    client_id: String,
+    redirect_uri: String,
+    state: String,
+    nonce: Option<String>,
+    code_challenge: String,
+    code_challenge_method: String,
+    login_hint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    code: String,
+    client_id: String,
+    redirect_uri: String,
+    code_verifier: String,
+}
+
+async fn discovery(State(idp): State<Idp>) -> Json<Value> {
+    let issuer = &idp.issuer;
+    Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{issuer}/authorize"),
+        "token_endpoint": format!("{issuer}/token"),
+        "jwks_uri": format!("{issuer}/jwks"),
+        "response_types_supported": ["code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "code_challenge_methods_supported": ["S256"],
+    }))
+}
+
+async fn jwks(State(idp): State<Idp>) -> Json<Value> {
+    Json(json!({
+        "keys": [{ "kty": "OKP", "crv": "Ed25519", "use": "sig", "alg": "EdDSA", "kid": KID, "x": idp.public_key }],
+    }))
+}
+
+async fn authorize(State(idp): State<Idp>, Query(req): Query<Authorize>) -> Response {
+    if req.code_challenge_method != "S256" {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Only S256 code challenges are supported",
+        )
+            .into_response();
+    }
+
+    let code = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect::<String>();
+    let redirect = format!("{}?code={code}&state={}", req.redirect_uri, req.state);
+
+    let grant = Grant {
+        client_id: req.client_id,
+        redirect_uri: req.redirect_uri,
+        nonce: req.nonce,
+        code_challenge: req.code_challenge,
+        user: req.login_hint.unwrap_or_else(|| idp.user.clone()),
+    };
+    idp.grants.lock().unwrap().insert(code, grant);
+
+    Redirect::to(&redirect).into_response()
+}
+
+async fn token(State(idp): State<Idp>, Form(req): Form<TokenRequest>) -> Response {
+    let Some(grant) = idp.grants.lock().unwrap().remove(&req.code) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+            .into_response();
+    };
+
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(req.code_verifier.as_bytes()));
+    if grant.client_id != req.client_id
+        || grant.redirect_uri != req.redirect_uri
+        || grant.code_challenge != challenge
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "error": "invalid_grant" })),
+        )
+            .into_response();
+    }
+
+    let now = jsonwebtoken::get_current_timestamp();
+    let claims = json!({
+        "iss": idp.issuer,
+        "sub": grant.user,
+        "aud": grant.client_id,
+        "iat": now,
+        "exp": now + ID_TOKEN_TTL,
+        "nonce": grant.nonce,
+        "preferred_username": grant.user,
+    });
+    let header = Header {
+        kid: Some(KID.into()),
+        ..Header::new(Algorithm::EdDSA)
+    };
+
+    match jsonwebtoken::encode(&header, &claims, &idp.key) {
+        Ok(id_token) => Json(json!({
+            "access_token": "mock",
+            "token_type": "Bearer",
+            "expires_in": ID_TOKEN_TTL,
+            "id_token": id_token,
+        }))
+        .into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// The provider at `issuer`, logging in as `user` unless there's a `login_hint`.
+/// It signs with a new key every time.
+pub fn router(issuer: String, user: String) -> Result<Router> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())?;
+    let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())?;
+
+    let idp = Idp {
+        issuer,
+        user,
+        key: Arc::new(EncodingKey::from_ed_der(pkcs8.as_ref())),
+        public_key: URL_SAFE_NO_PAD.encode(pair.public_key()),
+        grants: Arc::default(),
+    };
+
+    Ok(Router::new()
+        .route("/.well-known/openid-configuration", get(discovery))
+        .route("/jwks", get(jwks))
+        .route("/authorize", get(authorize))
+        .route("/token", post(token))
+        .with_state(idp))
+}
@@ -0,0 +1,25 @@
+//! A minimal OpenID Connect provider for trying out single sign-on locally. It logs everyone in
+//! right away, as the user given by the `login_hint` parameter or `MOCK_IDP_USER`.
+//!
+//! Run it with `cargo run --example mock_idp`, then start the server with
+//! `OIDC_ISSUER=http://localhost:4000`, `OIDC_CLIENT_ID=personal-page` and `OIDC_REDIRECT_URI`
+//! pointing at the client's login page.
+
+mod idp;
+
+use anyhow::Result;
+use std::env;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let port = env::var("MOCK_IDP_PORT").unwrap_or_else(|_| "4000".into());
+    let issuer = format!("http://localhost:{port}");
+    let user = env::var("MOCK_IDP_USER").unwrap_or_else(|_| "mock_user".into());
+    println!("mock identity provider running at {issuer}");
+
+    let app = idp::router(issuer, user)?;
+    axum::serve(TcpListener::bind(format!("127.0.0.1:{port}")).await?, app).await?;
+
+    Ok(())
+}
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS sessions (
+  id TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  created_at INT NOT NULL,
+  revoked_at INT) STRICT;
+
+CREATE INDEX IF NOT EXISTS sessions_idx ON sessions(user_id);
+
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+  hash TEXT NOT NULL PRIMARY KEY,
+  session_id TEXT NOT NULL REFERENCES sessions(id),
+  expires_at INT NOT NULL,
+  used INT NOT NULL DEFAULT 0) STRICT;
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_idx ON refresh_tokens(session_id);
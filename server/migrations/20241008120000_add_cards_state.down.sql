@@ -0,0 +1 @@
+ALTER TABLE cards DROP COLUMN state;
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE cards ADD COLUMN state TEXT;
\ No newline at end of file
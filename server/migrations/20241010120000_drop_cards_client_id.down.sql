@@ -0,0 +1 @@
+ALTER TABLE cards ADD COLUMN client_id INT NOT NULL DEFAULT 0;
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP TRIGGER notes_fts_update;
+DROP TRIGGER notes_fts_delete;
+DROP TRIGGER notes_fts_insert;
+DROP TABLE notes_fts;
+DROP TABLE notes;
\ No newline at end of file
@@ -0,0 +1,2 @@
+DROP TABLE todo_completions;
+DROP TABLE todos;
\ No newline at end of file
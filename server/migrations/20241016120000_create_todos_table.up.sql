@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS todos (
+  id TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  card_id TEXT NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+  text TEXT NOT NULL,
+  pos INT NOT NULL,
+  due_at INT,
+  completed_at INT,
+  created_at INT NOT NULL) STRICT;
+
+CREATE INDEX IF NOT EXISTS todos_idx ON todos(card_id, pos);
+CREATE INDEX IF NOT EXISTS todos_due_idx ON todos(user_id, due_at) WHERE completed_at IS NULL;
+
+CREATE TABLE IF NOT EXISTS todo_completions (
+  todo_id TEXT NOT NULL REFERENCES todos(id) ON DELETE CASCADE,
+  completed_at INT NOT NULL) STRICT;
+
+CREATE INDEX IF NOT EXISTS todo_completions_idx ON todo_completions(todo_id, completed_at);
@@ -0,0 +1 @@
+DROP TABLE calculations;
\ No newline at end of file
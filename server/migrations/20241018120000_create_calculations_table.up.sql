@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS calculations (
+  id TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  expr TEXT NOT NULL,
+  result TEXT NOT NULL,
+  created_at INT NOT NULL) STRICT;
+
+CREATE INDEX IF NOT EXISTS calculations_idx ON calculations(user_id, created_at);
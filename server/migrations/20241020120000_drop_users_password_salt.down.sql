@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN password_salt_b64 TEXT NOT NULL DEFAULT '';
\ No newline at end of file
@@ -0,0 +1 @@
+ALTER TABLE users DROP COLUMN password_salt_b64;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS rate_limits (
+  key TEXT NOT NULL PRIMARY KEY,
+  tokens REAL NOT NULL,
+  updated_at INT NOT NULL,
+  failures INT NOT NULL,
+  locked_until INT) STRICT;
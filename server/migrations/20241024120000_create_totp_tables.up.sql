@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS totp (
+  user_id TEXT NOT NULL PRIMARY KEY REFERENCES users(id),
+  secret TEXT NOT NULL,
+  confirmed_at INT,
+  last_step INT) STRICT;
+
+CREATE TABLE IF NOT EXISTS recovery_codes (
+  hash TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  used_at INT) STRICT;
+
+CREATE INDEX IF NOT EXISTS recovery_codes_idx ON recovery_codes(user_id);
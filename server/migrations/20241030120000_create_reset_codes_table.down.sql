@@ -0,0 +1 @@
+DROP TABLE reset_codes;
\ No newline at end of file
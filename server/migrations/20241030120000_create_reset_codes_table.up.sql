@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS reset_codes (
+  hash TEXT NOT NULL PRIMARY KEY,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  expires_at INT NOT NULL,
+  used_at INT) STRICT;
+
+CREATE INDEX IF NOT EXISTS reset_codes_idx ON reset_codes(user_id);
\ No newline at end of file
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS identities (
+  issuer TEXT NOT NULL,
+  subject TEXT NOT NULL,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  created_at INT NOT NULL,
+  PRIMARY KEY (issuer, subject)) STRICT;
+
+CREATE INDEX IF NOT EXISTS identities_idx ON identities(user_id);
+
+CREATE TABLE IF NOT EXISTS oidc_logins (
+  state TEXT NOT NULL PRIMARY KEY,
+  nonce TEXT NOT NULL,
+  verifier TEXT NOT NULL,
+  user_id TEXT REFERENCES users(id),
+  expires_at INT NOT NULL) STRICT;
\ No newline at end of file
@@ -0,0 +1,45 @@
+-- The full-text index refers to notes by rowid, which VACUUM may renumber
+-- unless it is aliased by an INTEGER PRIMARY KEY column
+DROP TRIGGER notes_fts_update;
+DROP TRIGGER notes_fts_delete;
+DROP TRIGGER notes_fts_insert;
+DROP TABLE notes_fts;
+
+CREATE TABLE notes_new (
+  seq INTEGER PRIMARY KEY,
+  id TEXT NOT NULL UNIQUE,
+  user_id TEXT NOT NULL REFERENCES users(id),
+  card_id TEXT NOT NULL REFERENCES cards(id) ON DELETE CASCADE,
+  title TEXT NOT NULL,
+  body TEXT NOT NULL,
+  created_at INT NOT NULL,
+  updated_at INT NOT NULL) STRICT;
+
+INSERT INTO notes_new (id, user_id, card_id, title, body, created_at, updated_at)
+SELECT id, user_id, card_id, title, body, created_at, updated_at FROM notes;
+
+DROP TABLE notes;
+ALTER TABLE notes_new RENAME TO notes;
+
+CREATE INDEX IF NOT EXISTS notes_idx ON notes(card_id, created_at);
+
+CREATE VIRTUAL TABLE IF NOT EXISTS notes_fts USING fts5(
+  title,
+  body,
+  content='notes',
+  content_rowid='seq');
+
+INSERT INTO notes_fts (notes_fts) VALUES ('rebuild');
+
+CREATE TRIGGER IF NOT EXISTS notes_fts_insert AFTER INSERT ON notes BEGIN
+  INSERT INTO notes_fts (rowid, title, body) VALUES (new.seq, new.title, new.body);
+END;
+
+CREATE TRIGGER IF NOT EXISTS notes_fts_delete AFTER DELETE ON notes BEGIN
+  INSERT INTO notes_fts (notes_fts, rowid, title, body) VALUES ('delete', old.seq, old.title, old.body);
+END;
+
+CREATE TRIGGER IF NOT EXISTS notes_fts_update AFTER UPDATE ON notes BEGIN
+  INSERT INTO notes_fts (notes_fts, rowid, title, body) VALUES ('delete', old.seq, old.title, old.body);
+  INSERT INTO notes_fts (rowid, title, body) VALUES (new.seq, new.title, new.body);
+END;
@@ -0,0 +1,2 @@
+ALTER TABLE totp DROP COLUMN locked_until;
+ALTER TABLE totp DROP COLUMN failures;
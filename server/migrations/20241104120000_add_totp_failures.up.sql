@@ -0,0 +1,2 @@
+ALTER TABLE totp ADD COLUMN failures INT NOT NULL DEFAULT 0;
+ALTER TABLE totp ADD COLUMN locked_until INT;
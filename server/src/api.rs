@@ -1,24 +1,50 @@
 use crate::{
-    extract::{payload::Payload, user::User},
-    jwt::Claim,
+    cards::{
+        self,
+        calculator::{self, eval},
+        notes, todo, Card,
+    },
+    error::MapServerError,
+    events::Events,
+    extract::{
+        cookie::{self, AuthMode, Authenticated, ClearCookies, OidcStarted, REFRESH_COOKIE},
+        device::Device,
+        params::{Path, Query},
+        payload::Payload,
+        user::{Admin, RequireRole, Session, User},
+        version::{IfMatch, Version, Versioned},
+    },
+    jwt::{self, Challenge, Claim},
+    oidc::{self, Oidc},
+    openapi::{self, OpenApi},
+    password::{self, Verified},
+    reset,
     schema::{
-        api, db,
-        ids::{CardId, UserId},
+        api::{self, ApiError, ErrorCode},
+        db,
+        ids::{CalculationId, CardId, NoteId, SessionId, TodoId, UserId},
     },
+    session,
+    state::AppState,
+    totp,
 };
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
 use axum::{
     extract::State,
-    http::StatusCode,
-    response::{ErrorResponse, IntoResponse},
+    http::{HeaderMap, HeaderValue, Method},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        Response,
+    },
     Router,
 };
+use serde_json::Value;
 use sqlx::{
     error::{Error as SqlxError, ErrorKind},
-    query, query_as, Connection, QueryBuilder, SqlitePool,
+    query, query_as, query_scalar, Connection, Executor, Sqlite, SqliteConnection, SqlitePool,
 };
-use std::fmt::Debug;
-use tracing::error;
+use std::{collections::HashMap, ops::RangeBounds};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+use tracing::warn;
 
 trait IntoApiResult {
     type Result;
@@ -29,35 +55,707 @@ impl IntoApiResult for [()] {
 impl<T: Sized> IntoApiResult for T {
     type Result = Payload<T>;
 }
-type ApiResult<T = [()]> = axum::response::Result<<T as IntoApiResult>::Result>;
+type ApiResult<T = [()]> = Result<<T as IntoApiResult>::Result, ApiError>;
+type VersionedResult<T = [()]> = Result<Versioned<<T as IntoApiResult>::Result>, ApiError>;
+type AuthResult = Result<Authenticated, ApiError>;
+
+/// Checks a password against the hash stored for a user, upgrading the hash if it is outdated
+async fn check_password(
+    pool: &SqlitePool,
+    user: &db::User,
+    password: &str,
+    error: &'static str,
+) -> Result<bool, ApiError> {
+    if user.password_hash.is_empty() {
+        password::verify_nothing(password)
+            .await
+            .map_server_err(error)?;
+        return Ok(false);
+    }
+
+    match password::verify(password, &user.password_hash)
+        .await
+        .map_server_err(error)?
+    {
+        Verified::Invalid => Ok(false),
+        Verified::Valid => Ok(true),
+        Verified::Rehashed(hash) => {
+            let res = query!(
+                "UPDATE users SET password_hash = ? WHERE id = ?",
+                hash,
+                user.id
+            )
+            .execute(pool)
+            .await;
+            if let Err(err) = res {
+                warn!("Failed to upgrade password hash: {err:?}");
+            }
+            Ok(true)
+        }
+    }
+}
+
+async fn fetch_user(pool: &SqlitePool, id: UserId) -> Result<Option<db::User>, SqlxError> {
+    query_as!(
+        db::User,
+        r#"
+        SELECT id as "id: _", username, password_hash, layout_version, role as "role: _", disabled_at
+        FROM users WHERE id = ?
+        "#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+const ACCOUNT_DISABLED: ApiError =
+    ApiError::new(ErrorCode::AccountDisabled, "This account is disabled");
+
+/// Seconds after logging in that accounts without a password can make sensitive changes
+const REAUTH_WINDOW: i64 = 5 * 60;
+
+/// Fetches the user behind a request, checking the password they confirmed it with.
+/// Accounts that only log in with single sign-on have no password, so they confirm by having
+/// logged in within [`REAUTH_WINDOW`] instead.
+async fn confirm_password(
+    pool: &SqlitePool,
+    claim: &Claim,
+    password: &str,
+    error: &'static str,
+) -> Result<db::User, ApiError> {
+    let user = fetch_user(pool, claim.sub)
+        .await
+        .map_server_err(error)?
+        .ok_or(ApiError::new(ErrorCode::Unauthenticated, "User not found"))?;
+
+    if user.password_hash.is_empty() {
+        let created_at = query_scalar!("SELECT created_at FROM sessions WHERE id = ?", claim.sid)
+            .fetch_optional(pool)
+            .await
+            .map_server_err(error)?;
+
+        return if created_at.is_some_and(|created_at| created_at + REAUTH_WINDOW > jwt::now()) {
+            Ok(user)
+        } else {
+            Err(ApiError::new(
+                ErrorCode::ReauthenticationRequired,
+                "Log in again to confirm this change",
+            ))
+        };
+    }
+
+    if check_password(pool, &user, password, error).await? {
+        Ok(user)
+    } else {
+        Err(ApiError::new(
+            ErrorCode::InvalidPassword,
+            "Invalid password",
+        ))
+    }
+}
+
+async fn fetch_totp(pool: &SqlitePool, user: UserId) -> Result<Option<db::Totp>, SqlxError> {
+    query_as!(
+        db::Totp,
+        r#"SELECT user_id as "user_id: _", secret, confirmed_at, last_step, locked_until FROM totp WHERE user_id = ?"#,
+        user,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Uses up a code from the authenticator app or a recovery code, returning whether it was valid
+async fn use_code(pool: &SqlitePool, totp: &db::Totp, code: &str) -> Result<bool, SqlxError> {
+    let now = jwt::now();
+    let code = code.trim();
+
+    let res = if code.chars().all(|c| c.is_ascii_digit()) {
+        let Some(step) = totp::verify(&totp.secret, code, now, totp.last_step) else {
+            return Ok(false);
+        };
+
+        // Guards against the same code being used concurrently
+        query!(
+            "UPDATE totp SET last_step = ?1 WHERE user_id = ?2 AND (last_step IS NULL OR last_step < ?1)",
+            step,
+            totp.user_id,
+        )
+        .execute(pool)
+        .await?
+    } else {
+        let hash = session::hash(&totp::normalize_recovery_code(code));
+
+        query!(
+            "UPDATE recovery_codes SET used_at = ? WHERE hash = ? AND user_id = ? AND used_at IS NULL",
+            now,
+            hash,
+            totp.user_id,
+        )
+        .execute(pool)
+        .await?
+    };
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Consecutive invalid codes before code logins are locked out
+const MAX_CODE_FAILURES: i64 = 5;
+/// Seconds a code lockout lasts, longer than challenges live so the ones the codes were guessed
+/// with expire before it ends
+const TOTP_LOCKOUT: i64 = 15 * 60;
+const TOO_MANY_CODES: ApiError = ApiError::new(
+    ErrorCode::RateLimited,
+    "Too many invalid codes, log in again later",
+);
+const _: () = assert!(TOTP_LOCKOUT > jwt::CHALLENGE_TTL);
+
+/// Counts a failed login code against the user, locking out code logins after too many.
+/// Returns whether this failure started a lockout.
+async fn record_code_failure(pool: &SqlitePool, user: UserId) -> Result<bool, SqlxError> {
+    let locked_until = jwt::now() + TOTP_LOCKOUT;
+    let row = query!(
+        r#"
+        UPDATE totp SET
+          failures = CASE WHEN failures + 1 >= ?1 THEN 0 ELSE failures + 1 END,
+          locked_until = CASE WHEN failures + 1 >= ?1 THEN ?2 ELSE locked_until END
+        WHERE user_id = ?3
+        RETURNING locked_until
+        "#,
+        MAX_CODE_FAILURES,
+        locked_until,
+        user,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.is_some_and(|row| row.locked_until == Some(locked_until)))
+}
+
+const CARD_NOT_FOUND: ApiError = ApiError::new(ErrorCode::CardNotFound, "Card not found");
+
+/// A stored card resolved through the card registry
+struct LoadedCard {
+    card: api::Card,
+    pos: i64,
+    /// The serialized state, upgraded if the stored one was obsolete
+    state: Option<String>,
+    /// Whether the stored name or state was obsolete
+    migrated: bool,
+}
+
+/// Resolves a stored card through the registry, returning `None` for unknown card names
+fn load_card(
+    db::Card {
+        id,
+        name,
+        pos,
+        state: stored,
+        ..
+    }: db::Card,
+) -> Option<LoadedCard> {
+    let Some((kind, renamed)) = cards::resolve(&name) else {
+        warn!("Skipping card {id} with unknown name {name}");
+        return None;
+    };
+
+    let state = stored
+        .as_deref()
+        .and_then(|state| serde_json::from_str(state).ok())
+        .and_then(|state| kind.migrate_state(state));
+    let serialized = state.as_ref().map(Value::to_string);
+
+    Some(LoadedCard {
+        migrated: renamed || serialized != stored,
+        card: api::Card {
+            id,
+            name: kind.name(),
+            state,
+        },
+        pos,
+        state: serialized,
+    })
+}
+
+fn to_api_cards(cards: Vec<db::Card>) -> Vec<api::Card> {
+    cards
+        .into_iter()
+        .filter_map(load_card)
+        .map(|loaded| loaded.card)
+        .collect()
+}
+
+/// Fetches a user's card layout along with its version
+async fn fetch_layout(
+    pool: &SqlitePool,
+    user: UserId,
+) -> Result<(Version, Vec<db::Card>), SqlxError> {
+    let mut conn = pool.acquire().await?;
+
+    conn.transaction(|transact| {
+        Box::pin(async move {
+            let version = query_scalar!("SELECT layout_version FROM users WHERE id = ?", user)
+                .fetch_one(&mut **transact)
+                .await?;
+
+            let cards = query_as!(
+                db::Card,
+                r#"
+            SELECT id as "id: _", user_id as "user_id: _", name, pos, state
+            FROM cards
+            WHERE user_id = ?
+            ORDER BY pos
+            "#,
+                user,
+            )
+            .fetch_all(&mut **transact)
+            .await?;
+
+            Ok((Version(version), cards))
+        })
+    })
+    .await
+}
+
+/// Bumps a user's layout version, returning `None` if it no longer matches the expected one
+async fn bump_version(
+    conn: &mut SqliteConnection,
+    user: UserId,
+    Version(expected): Version,
+) -> Result<Option<Version>, SqlxError> {
+    query_scalar!(
+        r#"
+        UPDATE users SET layout_version = layout_version + 1
+        WHERE id = ? AND layout_version = ?
+        RETURNING layout_version
+        "#,
+        user,
+        expected,
+    )
+    .fetch_optional(conn)
+    .await
+    .map(|version| version.map(Version))
+}
+
+/// Rejects a stale write with the current layout so the client can merge its changes
+async fn stale_layout(pool: &SqlitePool, user: UserId, error: &'static str) -> ApiError {
+    match fetch_layout(pool, user).await.map_server_err(error) {
+        Ok((Version(version), cards)) => {
+            let layout = api::Layout {
+                version,
+                cards: to_api_cards(cards),
+            };
+            ApiError::new(
+                ErrorCode::StaleVersion,
+                "This was changed in another session",
+            )
+            .details(layout)
+        }
+        Err(err) => err,
+    }
+}
+
+async fn fetch_card(
+    pool: &SqlitePool,
+    user: UserId,
+    id: CardId,
+) -> Result<Option<db::Card>, SqlxError> {
+    query_as!(
+        db::Card,
+        r#"
+        SELECT id as "id: _", user_id as "user_id: _", name, pos, state
+        FROM cards
+        WHERE id = ? AND user_id = ?
+        "#,
+        id,
+        user,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Checks a card's state against the registry, serializing it for storage
+fn validate_state(
+    card: &dyn Card,
+    state: Option<&Value>,
+    error: &'static str,
+) -> Result<Option<String>, ApiError> {
+    let Some(state) = state else {
+        return Ok(None);
+    };
+
+    let serialized = serde_json::to_string(state).map_server_err(error)?;
+    if serialized.len() > card.max_state_len() {
+        Err(ApiError::new(
+            ErrorCode::InvalidCardState,
+            "Card state is too large",
+        ))?
+    }
+    if !card.validate_state(state) {
+        Err(ApiError::new(
+            ErrorCode::InvalidCardState,
+            "Invalid card state",
+        ))?
+    }
+
+    Ok(Some(serialized))
+}
+
+/// Moves a card and/or replaces its state, returning `None` if the layout version is stale
+async fn update_card(
+    pool: &SqlitePool,
+    user: UserId,
+    id: CardId,
+    expected: Version,
+    old_pos: i64,
+    pos: Option<i64>,
+    state: Option<String>,
+) -> Result<Option<Version>, SqlxError> {
+    let mut conn = pool.acquire().await?;
+
+    conn.transaction(|transact| {
+        Box::pin(async move {
+            let Some(version) = bump_version(transact, user, expected).await? else {
+                return Ok(None);
+            };
+
+            if let Some(pos) = pos {
+                let count = query_scalar!("SELECT COUNT(*) FROM cards WHERE user_id = ?", user)
+                    .fetch_one(&mut **transact)
+                    .await?;
+                let pos = pos.clamp(0, count - 1);
+
+                // shift the cards between the old and new positions towards the old one
+                query!(
+                    r#"
+                UPDATE cards SET pos = pos + (CASE WHEN pos < ?2 THEN 1 ELSE -1 END)
+                WHERE user_id = ?1 AND pos BETWEEN MIN(?2, ?3) AND MAX(?2, ?3) AND id != ?4
+                "#,
+                    user,
+                    old_pos,
+                    pos,
+                    id,
+                )
+                .execute(&mut **transact)
+                .await?;
+
+                query!("UPDATE cards SET pos = ? WHERE id = ?", pos, id)
+                    .execute(&mut **transact)
+                    .await?;
+            }
+
+            if let Some(state) = state {
+                query!("UPDATE cards SET state = ? WHERE id = ?", state, id)
+                    .execute(&mut **transact)
+                    .await?;
+            }
+
+            Ok(Some(version))
+        })
+    })
+    .await
+}
+
+/// Responds with an updated card and notifies the user's other sessions
+async fn updated_card(
+    pool: &SqlitePool,
+    events: &Events,
+    user: UserId,
+    id: CardId,
+    version: Option<Version>,
+    error: &'static str,
+) -> VersionedResult<api::Card> {
+    let Some(version) = version else {
+        return Err(stale_layout(pool, user, error).await);
+    };
+
+    let card = fetch_card(pool, user, id)
+        .await
+        .map_server_err(error)?
+        .ok_or(CARD_NOT_FOUND)?;
+    let LoadedCard { card, pos, .. } = load_card(card).ok_or(CARD_NOT_FOUND)?;
+    events.publish(
+        user,
+        api::Event::CardUpdated {
+            version: version.0,
+            pos,
+            card: card.clone(),
+        },
+    );
+
+    Ok(Versioned(version, Payload(card)))
+}
+
+/// Checks that a card exists, belongs to the user and is of the expected kind
+async fn check_card(
+    pool: &SqlitePool,
+    user: UserId,
+    id: CardId,
+    expected: api::CardName,
+    error: &'static str,
+) -> Result<(), ApiError> {
+    let LoadedCard { card, .. } = fetch_card(pool, user, id)
+        .await
+        .map_server_err(error)?
+        .and_then(load_card)
+        .ok_or(CARD_NOT_FOUND)?;
+
+    if card.name != expected {
+        Err(ApiError::new(
+            ErrorCode::WrongCardKind,
+            "Wrong kind of card",
+        ))?
+    }
+    Ok(())
+}
+
+const NOTE_NOT_FOUND: ApiError = ApiError::new(ErrorCode::NoteNotFound, "Note not found");
+
+fn to_api_note(
+    db::Note {
+        id,
+        card_id,
+        title,
+        body,
+        created_at,
+        updated_at,
+        ..
+    }: db::Note,
+) -> api::Note {
+    api::Note {
+        id,
+        card: card_id,
+        title,
+        body,
+        created_at,
+        updated_at,
+    }
+}
+
+const TODO_NOT_FOUND: ApiError = ApiError::new(ErrorCode::TodoNotFound, "Todo item not found");
+
+fn to_api_todo(
+    db::Todo {
+        id,
+        card_id,
+        text,
+        due_at,
+        completed_at,
+        created_at,
+        ..
+    }: db::Todo,
+) -> api::Todo {
+    api::Todo {
+        id,
+        card: card_id,
+        text,
+        due_at,
+        completed_at,
+        created_at,
+    }
+}
 
-trait MapServerError {
-    type Output;
-    fn map_server_err(self, error: impl IntoResponse) -> Self::Output;
+async fn fetch_todo<'c>(
+    conn: impl Executor<'c, Database = Sqlite>,
+    user: UserId,
+    id: TodoId,
+) -> Result<Option<db::Todo>, SqlxError> {
+    query_as!(
+        db::Todo,
+        r#"
+        SELECT id as "id: _", user_id as "user_id: _", card_id as "card_id: _", text, pos, due_at, completed_at, created_at
+        FROM todos
+        WHERE id = ? AND user_id = ?
+        "#,
+        id,
+        user,
+    )
+    .fetch_optional(conn)
+    .await
 }
-impl<T, E: Debug> MapServerError for Result<T, E> {
-    type Output = Result<T, ErrorResponse>;
-    fn map_server_err(self, error: impl IntoResponse) -> Self::Output {
-        self.inspect_err(|err| error!("Internal error: {err:?}"))
-            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, error).into())
+
+/// Finds a position for a todo item right after another one, or at the top of the card.
+/// The card's items are only renumbered once there is no room left between two of them.
+/// Returns `None` if `after` is not an item of the card.
+async fn todo_pos(
+    conn: &mut SqliteConnection,
+    card: CardId,
+    moving: Option<TodoId>,
+    after: Option<TodoId>,
+) -> Result<Option<i64>, SqlxError> {
+    loop {
+        let prev = match after {
+            Some(after) => {
+                let prev = query_scalar!(
+                    "SELECT pos FROM todos WHERE id = ? AND card_id = ?",
+                    after,
+                    card,
+                )
+                .fetch_optional(&mut *conn)
+                .await?;
+
+                match prev {
+                    Some(prev) => Some(prev),
+                    None => return Ok(None),
+                }
+            }
+            None => None,
+        };
+
+        let next = query_scalar!(
+            r#"
+            SELECT pos FROM todos
+            WHERE card_id = ?1 AND (?2 IS NULL OR pos > ?2) AND id IS NOT ?3
+            ORDER BY pos
+            LIMIT 1
+            "#,
+            card,
+            prev,
+            moving,
+        )
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        if let Some(pos) = todo::pos_between(prev, next) {
+            return Ok(Some(pos));
+        }
+
+        query!(
+            r#"
+            UPDATE todos SET pos = ranked.rank * ?1
+            FROM (
+                SELECT id, ROW_NUMBER() OVER (ORDER BY pos, id) AS rank
+                FROM todos
+                WHERE card_id = ?2
+            ) AS ranked
+            WHERE todos.id = ranked.id
+            "#,
+            todo::POS_GAP,
+            card,
+        )
+        .execute(&mut *conn)
+        .await?;
     }
 }
 
+/// Routes taking a password or a one-time code, which are rate limited against guessing
+pub const CREDENTIAL_ROUTES: &[&str] = &[
+    "/login",
+    "/login/totp",
+    "/signup",
+    "/account",
+    "/account/password",
+    "/account/totp",
+    "/account/totp/confirm",
+    "/password-reset",
+];
+
+const OIDC_DISABLED: ApiError =
+    ApiError::new(ErrorCode::OidcDisabled, "Single sign-on is not configured");
+
+const USER_NOT_FOUND: ApiError = ApiError::new(ErrorCode::UserNotFound, "User not found");
+/// Users listed at once unless a limit is given
+const USERS_PAGE: i64 = 50;
+const MAX_USERS_PAGE: i64 = 500;
+
+/// Matches usernames containing a search term, which is escaped as it's taken literally
+fn contains_pattern(q: &str) -> String {
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
+
+async fn fetch_user_summary(
+    pool: &SqlitePool,
+    id: UserId,
+) -> Result<Option<api::UserSummary>, SqlxError> {
+    query_as!(
+        api::UserSummary,
+        r#"
+        SELECT
+            id as "id: _",
+            username,
+            role as "role: _",
+            disabled_at,
+            (SELECT COUNT(*) FROM cards WHERE cards.user_id = users.id) as "cards!: i64"
+        FROM users WHERE id = ?
+        "#,
+        id,
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Where the routes are nested, each version under its own prefix, see [`base`]
+pub const BASE: &str = "/api";
+/// The versions of the API being served
+pub const VERSIONS: [u32; 1] = [1];
+/// The version the routes directly under [`BASE`] serve, for clients from before versioning
+pub const UNVERSIONED: u32 = 1;
+
+/// When the unversioned routes were deprecated, as a date in seconds (RFC 9745)
+const DEPRECATED_AT: &str = "@1792281600";
+/// When the unversioned routes will be removed (RFC 8594)
+const SUNSET: &str = "Sun, 18 Apr 2027 00:00:00 GMT";
+
+/// Where the routes of a version are nested, e.g. `/api/v1`
+pub fn base(version: u32) -> String {
+    format!("{BASE}/v{version}")
+}
+
+/// Marks a response of the unversioned routes as deprecated
+pub async fn deprecated(mut res: Response) -> Response {
+    let headers = res.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static(DEPRECATED_AT));
+    headers.insert("sunset", HeaderValue::from_static(SUNSET));
+    res
+}
+
+/// The versions a route serves, all of them unless it says otherwise
+macro_rules! versions {
+    () => {
+        ..
+    };
+    ($versions:expr) => {
+        $versions
+    };
+}
+
+/// Declares the routes with their handlers, which serve every version unless they are
+/// limited to a range of them, e.g. `#[versions(2..)]`. A route changing between versions
+/// is declared once for each range with a handler of its own.
 macro_rules! routes {
-    ($($method:ident $endpoint:ident$args:tt -> $ret:ty $body:block)*) => {
-        pub fn routes<S>(pool: SqlitePool) -> Router<S> {
-            Router::new()
+    ($(
+        $(#[versions($versions:expr)])?
+        $method:ident $endpoint:ident $(- $word:ident)* $(/ $(:$param:ident)? $($segment:ident $(- $segment_word:ident)*)?)* ($($args:tt)*) -> $ret:ty $body:block
+    )*) => {
+        pub fn routes<S>(state: AppState, version: u32) -> Router<S> {
+            let mut openapi = OpenApi::default();
+            let router = Router::new();
             $(
-                .route(
-                    concat!("/", stringify!($endpoint)),
-                    axum::routing::$method({
-                        async fn $endpoint$args -> $ret
-                        $body
-                        $endpoint
-                    }),
-                )
+                let router = 'route: {
+                    if !versions!($($versions)?).contains(&version) {
+                        break 'route router;
+                    }
+
+                    const PATH: &str = concat!(
+                        "/",
+                        stringify!($endpoint)
+                        $(, "-", stringify!($word))*
+                        $(, "/" $(, ":", stringify!($param))? $(, stringify!($segment) $(, "-", stringify!($segment_word))*)?)*
+                    );
+                    async fn $endpoint($($args)*) -> $ret
+                    $body
+
+                    openapi.operation(stringify!($method), PATH, &$endpoint);
+                    router.route(PATH, axum::routing::$method($endpoint))
+                };
             )*
-                .with_state(pool)
+
+            router
+                .merge(openapi::routes(&openapi.document(&base(version))))
+                .with_state(state)
         }
     }
 }
@@ -65,149 +763,1478 @@ macro_rules! routes {
 routes! {
     post login(
         State(pool): State<SqlitePool>,
+        device: Device,
+        mode: AuthMode,
         Payload(api::Credentials { username, password }): Payload<api::Credentials>,
-    ) -> ApiResult<api::Token> {
+    ) -> AuthResult {
         let error = "Failed to log in";
-        let invalid_login = (StatusCode::BAD_REQUEST, "Invalid username/password");
+        const INVALID_LOGIN: ApiError = ApiError::new(ErrorCode::InvalidCredentials, "Invalid username/password");
 
-        let db::User { id, password_hash, password_salt_b64, .. } = query_as!(
+        let user = query_as!(
             db::User,
             r#"
-            SELECT id as "id: _", username, password_hash, password_salt_b64
+            SELECT id as "id: _", username, password_hash, layout_version, role as "role: _", disabled_at
             FROM users WHERE username = ?
             "#,
             username,
         )
             .fetch_optional(&pool)
+            .await
+            .map_server_err(error)?;
+        let Some(user) = user else {
+            password::verify_nothing(&password).await.map_server_err(error)?;
+            return Err(INVALID_LOGIN);
+        };
+
+        if !check_password(&pool, &user, &password, error).await? {
+            Err(INVALID_LOGIN)?
+        }
+        if user.disabled_at.is_some() {
+            Err(ACCOUNT_DISABLED)?
+        }
+
+        let totp = fetch_totp(&pool, user.id).await.map_server_err(error)?;
+        if totp.is_some_and(|totp| totp.confirmed_at.is_some()) {
+            let challenge = Challenge::new(user.id).encode().map_server_err(error)?;
+            return Ok(Authenticated(mode, api::Login::Challenge { challenge }));
+        }
+
+        session::start(&pool, user.id, device)
+            .await
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .map_server_err("Failed to create token")
+    }
+
+    post login/totp(
+        State(pool): State<SqlitePool>,
+        device: Device,
+        mode: AuthMode,
+        Payload(api::TotpLogin { challenge, code }): Payload<api::TotpLogin>,
+    ) -> AuthResult {
+        let error = "Failed to log in";
+
+        let Challenge { sub: user, .. } = Challenge::decode(&challenge)
+            .map_err(|_| ApiError::new(ErrorCode::LoginExpired, "Login expired, log in again"))?;
+        let totp = fetch_totp(&pool, user)
             .await
             .map_server_err(error)?
-            .ok_or(invalid_login)?;
+            .filter(|totp| totp.confirmed_at.is_some())
+            .ok_or(ApiError::new(ErrorCode::TotpNotEnabled, "Two-factor authentication is not enabled"))?;
 
-        let salt = SaltString::from_b64(&password_salt_b64).map_err(|_| error)?;
-        let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|_| error)?.to_string();
+        if totp.locked_until.is_some_and(|until| until > jwt::now()) {
+            Err(TOO_MANY_CODES)?
+        }
 
-        if hash != password_hash {
-            Err(invalid_login.into())
-        } else {
-            Claim::new(id)
-                .encode()
-                .map(|token| Payload(api::Token { token }))
-                .map_server_err("Failed to create token")
+        if !use_code(&pool, &totp, &code).await.map_server_err(error)? {
+            if record_code_failure(&pool, user).await.map_server_err(error)? {
+                Err(TOO_MANY_CODES)?
+            }
+            Err(ApiError::new(ErrorCode::InvalidCode, "Invalid code"))?
         }
+        query!("UPDATE totp SET failures = 0 WHERE user_id = ?", user)
+            .execute(&pool)
+            .await
+            .map_server_err(error)?;
+
+        // The account may have been disabled since the password was checked
+        if fetch_user(&pool, user).await.map_server_err(error)?.is_none_or(|user| user.disabled_at.is_some()) {
+            Err(ACCOUNT_DISABLED)?
+        }
+
+        session::start(&pool, user, device)
+            .await
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .map_server_err("Failed to create token")
     }
 
     post signup(
         State(pool): State<SqlitePool>,
-        Payload(api::Credentials { username, password }): Payload<api::Credentials>,
-    ) -> ApiResult<api::Token> {
+        device: Device,
+        mode: AuthMode,
+        Payload(api::Signup { username, password }): Payload<api::Signup>,
+    ) -> AuthResult {
         let error = "Failed to sign up";
 
-        let salt = SaltString::generate(&mut rand::thread_rng());
-        let hash = Argon2::default()
-            .hash_password(password.as_bytes(), &salt)
-            .map_server_err(error)?
-            .to_string();
+        let hash = password::hash(&password).await.map_server_err(error)?;
 
         let id = UserId::default();
-        let salt = salt.as_str();
         let res = query!(
-            r#"
-            INSERT INTO users (id, username, password_hash, password_salt_b64)
-            VALUES (?, ?, ?, ?)
-            "#,
+            "INSERT INTO users (id, username, password_hash) VALUES (?, ?, ?)",
             id,
             username,
             hash,
-            salt,
         )
             .execute(&pool)
             .await
             .map_err(|err|
                 match err {
                     SqlxError::Database(err) if err.kind() == ErrorKind::UniqueViolation =>
-                        (StatusCode::BAD_REQUEST, "This username is taken"),
-                    _ => (StatusCode::INTERNAL_SERVER_ERROR, error),
+                        ApiError::new(ErrorCode::UsernameTaken, "This username is taken"),
+                    _ => ApiError::new(ErrorCode::Internal, error),
                 }
             )?;
 
         if res.rows_affected() != 1 {
-            Err(error.into())
+            Err(ApiError::new(ErrorCode::Internal, error))
         } else {
-            Claim::new(id)
-                .encode()
-                .map(|token| Payload(api::Token { token }))
+            session::start(&pool, id, device)
+                .await
+                .map(|token| Authenticated(mode, api::Login::Token(token)))
                 .map_server_err("Failed to create token")
         }
     }
 
-    post logout(User(_user): User) -> ApiResult {
-        Ok(())
+    post oidc/authorize(
+        State(oidc): State<Oidc>,
+        State(pool): State<SqlitePool>,
+    ) -> Result<OidcStarted, ApiError> {
+        let client = oidc.client().ok_or(OIDC_DISABLED)?;
+
+        client.authorize(&pool, None)
+            .await
+            .map(OidcStarted)
+            .map_server_err("Failed to start single sign-on")
     }
 
-    get cards(User(user): User, State(pool): State<SqlitePool>) -> ApiResult<Vec<api::Card>> {
-        let error = "Failed to get card layout";
+    post oidc/callback(
+        State(oidc): State<Oidc>,
+        State(pool): State<SqlitePool>,
+        device: Device,
+        mode: AuthMode,
+        headers: HeaderMap,
+        Payload(api::OidcCallback { code, state }): Payload<api::OidcCallback>,
+    ) -> AuthResult {
+        let error = "Failed to log in with single sign-on";
+        let client = oidc.client().ok_or(OIDC_DISABLED)?;
+
+        if !cookie::started_oidc(&headers, &state) {
+            Err(ApiError::new(ErrorCode::LoginExpired, "This login was started elsewhere, try again"))?
+        }
+
+        let oidc::Login { user, identity } = client.callback(&pool, &code, &state)
+            .await
+            .map_server_err(error)?
+            .ok_or(ApiError::new(ErrorCode::LoginExpired, "Login expired, try again"))?;
+
+        let user = match user {
+            Some(user) => {
+                if !client.link(&pool, &identity, user).await.map_server_err(error)? {
+                    Err(ApiError::new(ErrorCode::IdentityLinked, "This identity is linked to another account"))?
+                }
+                user
+            }
+            None => client.user(&pool, &identity).await.map_server_err(error)?,
+        };
+
+        if fetch_user(&pool, user).await.map_server_err(error)?.is_none_or(|user| user.disabled_at.is_some()) {
+            Err(ACCOUNT_DISABLED)?
+        }
+
+        session::start(&pool, user, device)
+            .await
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .map_server_err("Failed to create token")
+    }
+
+    post refresh(
+        State(pool): State<SqlitePool>,
+        method: Method,
+        headers: HeaderMap,
+        Payload(api::Refresh { refresh_token }): Payload<api::Refresh>,
+    ) -> AuthResult {
+        // A refresh token from a cookie is rotated into new cookies
+        let (mode, refresh_token) = match refresh_token {
+            Some(refresh_token) => (AuthMode::Bearer, refresh_token),
+            None => cookie::token(&method, &headers, REFRESH_COOKIE)?
+                .map(|refresh_token| (AuthMode::Cookie, refresh_token))
+                .ok_or(ApiError::new(ErrorCode::InvalidRefreshToken, "Invalid refresh token"))?,
+        };
 
-        let cards = query_as!(
-            db::Card,
+        session::refresh(&pool, &refresh_token)
+            .await
+            .map_server_err("Failed to refresh token")?
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .ok_or(ApiError::new(ErrorCode::InvalidRefreshToken, "Invalid refresh token"))
+    }
+
+    post logout(Session(claim): Session, State(pool): State<SqlitePool>) -> Result<ClearCookies, ApiError> {
+        let error = "Failed to log out";
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+        session::revoke(&mut conn, claim.sid).await.map_server_err(error)?;
+
+        Ok(ClearCookies)
+    }
+
+    get sessions(Session(claim): Session, State(pool): State<SqlitePool>) -> ApiResult<Vec<api::Session>> {
+        let now = jwt::now();
+
+        // Sessions whose refresh tokens all expired are as good as logged out
+        let sessions = query_as!(
+            api::Session,
             r#"
-            SELECT id as "id: _", user_id as "user_id: _", name, client_id, pos
-            FROM cards
-            WHERE user_id = ?
-            ORDER BY pos
+            SELECT
+                id as "id: _",
+                user_agent,
+                ip,
+                created_at,
+                last_seen_at,
+                id = ?1 as "current!: bool"
+            FROM sessions
+            WHERE user_id = ?2 AND revoked_at IS NULL AND EXISTS (
+                SELECT 1 FROM refresh_tokens
+                WHERE session_id = sessions.id AND used = 0 AND expires_at > ?3
+            )
+            ORDER BY last_seen_at DESC
             "#,
-            user,
+            claim.sid,
+            claim.sub,
+            now,
         )
             .fetch_all(&pool)
             .await
-            .map_server_err(error)?;
+            .map_server_err("Failed to get sessions")?;
 
-        Ok(Payload(cards.into_iter().map(|db::Card { name, client_id, .. }|
-            Ok(api::Card {
-                name: serde_json::from_str(&name).map_server_err(error)?,
-                id: client_id,
-            }),
-        ).collect::<Result<Vec<_>, ErrorResponse>>()?))
+        Ok(Payload(sessions))
     }
 
-    post cards(
+    delete sessions/:id(
         User(user): User,
         State(pool): State<SqlitePool>,
-        Payload(cards): Payload<Vec<api::Card>>,
+        Path(id): Path<SessionId>,
+    ) -> ApiResult {
+        let now = jwt::now();
+        let res = query!(
+            "UPDATE sessions SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+            now,
+            id,
+            user,
+        )
+            .execute(&pool)
+            .await
+            .map_server_err("Failed to log out session")?;
+
+        if res.rows_affected() != 1 {
+            Err(ApiError::new(ErrorCode::SessionNotFound, "Session not found"))?
+        }
+
+        Ok(())
+    }
+
+    post password-reset(
+        State(pool): State<SqlitePool>,
+        Payload(api::PasswordReset { code, new_password }): Payload<api::PasswordReset>,
     ) -> ApiResult {
-        let error = "Failed to update card layout";
+        let error = "Failed to reset password";
 
-        let values = cards
-            .into_iter()
-            .enumerate()
-            .map(|(i, api::Card { name, id })| Ok((i, serde_json::to_string(&name).map_server_err(error)?, id)))
-            .collect::<Result<Vec<_>, ErrorResponse>>()?;
+        let hash = password::hash(&new_password).await.map_server_err(error)?;
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let reset = conn.transaction(|transact| Box::pin(async move {
+            let Some(user) = reset::redeem(transact, &code).await? else {
+                return Ok(false);
+            };
 
+            query!("UPDATE users SET password_hash = ? WHERE id = ?", hash, user)
+                .execute(&mut **transact)
+                .await?;
+
+            session::revoke_all(transact, user).await?;
+
+            Result::<_, anyhow::Error>::Ok(true)
+        })).await.map_server_err(error)?;
+
+        if reset {
+            Ok(())
+        } else {
+            Err(ApiError::new(ErrorCode::InvalidResetCode, "Invalid or expired reset code"))
+        }
+    }
+
+    post account/oidc(
+        User(user): User,
+        State(oidc): State<Oidc>,
+        State(pool): State<SqlitePool>,
+    ) -> Result<OidcStarted, ApiError> {
+        let client = oidc.client().ok_or(OIDC_DISABLED)?;
+
+        client.authorize(&pool, Some(user))
+            .await
+            .map(OidcStarted)
+            .map_server_err("Failed to start single sign-on")
+    }
+
+    patch account/password(
+        Session(claim): Session,
+        State(pool): State<SqlitePool>,
+        device: Device,
+        mode: AuthMode,
+        Payload(api::PasswordChange { old_password, new_password }): Payload<api::PasswordChange>,
+    ) -> AuthResult {
+        let error = "Failed to change password";
+        let user = claim.sub;
+
+        confirm_password(&pool, &claim, &old_password, error).await?;
+
+        let hash = password::hash(&new_password).await.map_server_err(error)?;
         let mut conn = pool.acquire().await.map_server_err(error)?;
 
         conn.transaction(|transact| Box::pin(async move {
-            let conn = &mut **transact;
-
-            query!("DELETE FROM cards WHERE user_id = ?", user).execute(conn).await?;
-
-            if !values.is_empty() {
-                QueryBuilder::new("INSERT INTO cards (id, user_id, name, client_id, pos)")
-                    .push_values(values, |mut values_builder, (pos, name, id)| {
-                        values_builder
-                            .push_bind(CardId::default())
-                            .push_bind(user)
-                            .push_bind(name)
-                            .push_bind(id)
-                            .push_bind(pos as i64);
-                    })
-                    .build()
-                    .persistent(false) // don't cache dynamically sized query
-                    .execute(&mut **transact)
-                    .await?;
-            }
+            query!("UPDATE users SET password_hash = ? WHERE id = ?", hash, user)
+                .execute(&mut **transact)
+                .await?;
 
-            Result::<_, SqlxError>::Ok(())
+            session::revoke_all(transact, user).await
         })).await.map_server_err(error)?;
 
-        Ok(())
+        session::start(&pool, user, device)
+            .await
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .map_server_err("Failed to create token")
+    }
+
+    patch account/username(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        device: Device,
+        mode: AuthMode,
+        Payload(api::UsernameChange { username }): Payload<api::UsernameChange>,
+    ) -> AuthResult {
+        let error = "Failed to change username";
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let res = conn.transaction(|transact| Box::pin(async move {
+            query!("UPDATE users SET username = ? WHERE id = ?", username, user)
+                .execute(&mut **transact)
+                .await?;
+
+            session::revoke_all(transact, user).await
+        })).await;
+
+        match res {
+            Err(err) if matches!(
+                err.downcast_ref(),
+                Some(SqlxError::Database(err)) if err.kind() == ErrorKind::UniqueViolation
+            ) => Err(ApiError::new(ErrorCode::UsernameTaken, "This username is taken"))?,
+            res => res.map_server_err(error)?,
+        }
+
+        session::start(&pool, user, device)
+            .await
+            .map(|token| Authenticated(mode, api::Login::Token(token)))
+            .map_server_err("Failed to create token")
+    }
+
+    delete account(
+        Session(claim): Session,
+        State(pool): State<SqlitePool>,
+        Payload(api::PasswordConfirmation { password }): Payload<api::PasswordConfirmation>,
+    ) -> Result<ClearCookies, ApiError> {
+        let error = "Failed to delete account";
+        let user = claim.sub;
+
+        confirm_password(&pool, &claim, &password, error).await?;
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        conn.transaction(|transact| Box::pin(async move {
+            // Notes and todo items go along with their cards
+            query!("DELETE FROM cards WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM calculations WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM recovery_codes WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM totp WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM reset_codes WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM identities WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM oidc_logins WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!(
+                "DELETE FROM refresh_tokens WHERE session_id IN (SELECT id FROM sessions WHERE user_id = ?)",
+                user,
+            )
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM sessions WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM users WHERE id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+
+            Result::<_, SqlxError>::Ok(())
+        })).await.map_server_err(error)?;
+
+        Ok(ClearCookies)
+    }
+
+    post account/totp(
+        Session(claim): Session,
+        State(pool): State<SqlitePool>,
+        Payload(api::PasswordConfirmation { password }): Payload<api::PasswordConfirmation>,
+    ) -> ApiResult<api::TotpEnrollment> {
+        let error = "Failed to enable two-factor authentication";
+        let user = claim.sub;
+
+        let db::User { username, .. } = confirm_password(&pool, &claim, &password, error).await?;
+
+        // Starting over replaces a pending enrollment, but not a confirmed one
+        let secret = totp::secret();
+        let res = query!(
+            r#"
+            INSERT INTO totp (user_id, secret) VALUES (?1, ?2)
+            ON CONFLICT (user_id) DO UPDATE SET secret = ?2 WHERE confirmed_at IS NULL
+            "#,
+            user,
+            secret,
+        )
+            .execute(&pool)
+            .await
+            .map_server_err(error)?;
+
+        if res.rows_affected() != 1 {
+            Err(ApiError::new(ErrorCode::TotpAlreadyEnabled, "Two-factor authentication is already enabled"))?
+        }
+
+        Ok(Payload(api::TotpEnrollment { uri: totp::uri(&secret, &username), secret }))
+    }
+
+    post account/totp/confirm(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Payload(api::TotpCode { code }): Payload<api::TotpCode>,
+    ) -> ApiResult<api::RecoveryCodes> {
+        let error = "Failed to enable two-factor authentication";
+
+        let totp = fetch_totp(&pool, user)
+            .await
+            .map_server_err(error)?
+            .filter(|totp| totp.confirmed_at.is_none())
+            .ok_or(ApiError::new(ErrorCode::NoPendingTotp, "No two-factor authentication to confirm"))?;
+        let step = totp::verify(&totp.secret, &code, jwt::now(), totp.last_step)
+            .ok_or(ApiError::new(ErrorCode::InvalidCode, "Invalid code"))?;
+
+        let codes = totp::recovery_codes();
+        let hashes = codes
+            .iter()
+            .map(|code| session::hash(&totp::normalize_recovery_code(code)))
+            .collect::<Vec<_>>();
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        conn.transaction(|transact| Box::pin(async move {
+            let now = jwt::now();
+            query!(
+                "UPDATE totp SET confirmed_at = ?, last_step = ? WHERE user_id = ?",
+                now,
+                step,
+                user,
+            )
+                .execute(&mut **transact)
+                .await?;
+
+            query!("DELETE FROM recovery_codes WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            for hash in hashes {
+                query!("INSERT INTO recovery_codes (hash, user_id) VALUES (?, ?)", hash, user)
+                    .execute(&mut **transact)
+                    .await?;
+            }
+
+            Result::<_, SqlxError>::Ok(())
+        })).await.map_server_err(error)?;
+
+        Ok(Payload(api::RecoveryCodes { codes }))
+    }
+
+    delete account/totp(
+        Session(claim): Session,
+        State(pool): State<SqlitePool>,
+        Payload(api::PasswordConfirmation { password }): Payload<api::PasswordConfirmation>,
+    ) -> ApiResult {
+        let error = "Failed to disable two-factor authentication";
+        let user = claim.sub;
+
+        confirm_password(&pool, &claim, &password, error).await?;
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        conn.transaction(|transact| Box::pin(async move {
+            query!("DELETE FROM recovery_codes WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+            query!("DELETE FROM totp WHERE user_id = ?", user)
+                .execute(&mut **transact)
+                .await?;
+
+            Result::<_, SqlxError>::Ok(())
+        })).await.map_server_err(error)
+    }
+
+    get cards(User(user): User, State(pool): State<SqlitePool>) -> VersionedResult<Vec<api::Card>> {
+        let error = "Failed to get card layout";
+
+        let (version, cards) = fetch_layout(&pool, user).await.map_server_err(error)?;
+        let cards = cards.into_iter().filter_map(load_card).collect::<Vec<_>>();
+
+        // upgrade obsolete cards in place, which doesn't change the layout itself
+        for LoadedCard { card: api::Card { id, name, .. }, state, .. } in cards.iter().filter(|loaded| loaded.migrated) {
+            let name = serde_json::to_string(name).map_server_err(error)?;
+            query!("UPDATE cards SET name = ?, state = ? WHERE id = ?", name, state, id)
+                .execute(&pool)
+                .await
+                .map_server_err(error)?;
+        }
+
+        Ok(Versioned(version, Payload(cards.into_iter().map(|loaded| loaded.card).collect())))
+    }
+
+    post cards/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        State(events): State<Events>,
+        Path(id): Path<CardId>,
+        IfMatch(expected): IfMatch,
+        Payload(api::NewCard { name, pos, state }): Payload<api::NewCard>,
+    ) -> VersionedResult<api::Card> {
+        let error = "Failed to add card";
+
+        let card = name.card();
+        let state = validate_state(card, state.or_else(|| card.default_state()).as_ref(), error)?;
+        let name = serde_json::to_string(&name).map_server_err(error)?;
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let added = conn.transaction(|transact| Box::pin(async move {
+            let Some(version) = bump_version(transact, user, expected).await? else {
+                return Ok(None);
+            };
+
+            let count = query_scalar!("SELECT COUNT(*) FROM cards WHERE user_id = ?", user)
+                .fetch_one(&mut **transact)
+                .await?;
+            let pos = pos.unwrap_or(count).clamp(0, count);
+
+            query!("UPDATE cards SET pos = pos + 1 WHERE user_id = ? AND pos >= ?", user, pos)
+                .execute(&mut **transact)
+                .await?;
+
+            query_as!(
+                db::Card,
+                r#"
+                INSERT INTO cards (id, user_id, name, pos, state)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING id as "id: _", user_id as "user_id: _", name, pos, state
+                "#,
+                id,
+                user,
+                name,
+                pos,
+                state,
+            )
+                .fetch_one(&mut **transact)
+                .await
+                .map(|card| Some((version, card)))
+        }))
+            .await
+            .map_err(|err|
+                match err {
+                    SqlxError::Database(err) if err.kind() == ErrorKind::UniqueViolation =>
+                        ApiError::new(ErrorCode::CardExists, "This card already exists"),
+                    _ => ApiError::new(ErrorCode::Internal, error),
+                }
+            )?;
+
+        let Some((version, card)) = added else {
+            return Err(stale_layout(&pool, user, error).await);
+        };
+
+        let LoadedCard { card, pos, .. } = load_card(card).ok_or("Unknown card name").map_server_err(error)?;
+        events.publish(user, api::Event::CardAdded { version: version.0, pos, card: card.clone() });
+
+        Ok(Versioned(version, Payload(card)))
+    }
+
+    patch cards/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        State(events): State<Events>,
+        Path(id): Path<CardId>,
+        IfMatch(expected): IfMatch,
+        Payload(api::CardUpdate { pos, state }): Payload<api::CardUpdate>,
+    ) -> VersionedResult<api::Card> {
+        let error = "Failed to update card";
+
+        let card = fetch_card(&pool, user, id).await.map_server_err(error)?.ok_or(CARD_NOT_FOUND)?;
+        let LoadedCard { card, pos: old_pos, .. } = load_card(card).ok_or(CARD_NOT_FOUND)?;
+        let state = validate_state(card.name.card(), state.as_ref(), error)?;
+
+        let version = update_card(&pool, user, id, expected, old_pos, pos, state).await.map_server_err(error)?;
+
+        updated_card(&pool, &events, user, id, version, error).await
+    }
+
+    post cards/:id/action(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        State(events): State<Events>,
+        Path(id): Path<CardId>,
+        IfMatch(expected): IfMatch,
+        Payload(api::CardAction { action, args }): Payload<api::CardAction>,
+    ) -> VersionedResult<api::Card> {
+        let error = "Failed to run card action";
+
+        let card = fetch_card(&pool, user, id).await.map_server_err(error)?.ok_or(CARD_NOT_FOUND)?;
+        let LoadedCard { card: api::Card { name, state, .. }, pos, .. } = load_card(card).ok_or(CARD_NOT_FOUND)?;
+
+        let card = name.card();
+        let state = card.action(&action, state, args).map_err(|err| ApiError::custom(ErrorCode::CardActionFailed, err))?;
+        let state = validate_state(card, Some(&state), error)?;
+
+        let version = update_card(&pool, user, id, expected, pos, None, state).await.map_server_err(error)?;
+
+        updated_card(&pool, &events, user, id, version, error).await
+    }
+
+    delete cards/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        State(events): State<Events>,
+        Path(id): Path<CardId>,
+        IfMatch(expected): IfMatch,
+    ) -> VersionedResult {
+        let error = "Failed to remove card";
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let removed = conn.transaction(|transact| Box::pin(async move {
+            let Some(pos) = query_scalar!("SELECT pos FROM cards WHERE id = ? AND user_id = ?", id, user)
+                .fetch_optional(&mut **transact)
+                .await?
+            else {
+                return Ok(Err(CARD_NOT_FOUND));
+            };
+
+            let Some(version) = bump_version(transact, user, expected).await? else {
+                return Ok(Ok(None));
+            };
+
+            query!("DELETE FROM cards WHERE id = ?", id)
+                .execute(&mut **transact)
+                .await?;
+
+            query!("UPDATE cards SET pos = pos - 1 WHERE user_id = ? AND pos > ?", user, pos)
+                .execute(&mut **transact)
+                .await?;
+
+            Result::<_, SqlxError>::Ok(Ok(Some(version)))
+        })).await.map_server_err(error)??;
+
+        let Some(version) = removed else {
+            return Err(stale_layout(&pool, user, error).await);
+        };
+
+        events.publish(user, api::Event::CardRemoved { version: version.0, id });
+
+        Ok(Versioned(version, ()))
+    }
+
+    get notes(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Query(api::NotesQuery { card }): Query<api::NotesQuery>,
+    ) -> ApiResult<Vec<api::Note>> {
+        let error = "Failed to get notes";
+
+        check_card(&pool, user, card, api::CardName::Notes, error).await?;
+
+        let notes = query_as!(
+            db::Note,
+            r#"
+            SELECT id as "id: _", user_id as "user_id: _", card_id as "card_id: _", title, body, created_at, updated_at
+            FROM notes
+            WHERE card_id = ? AND user_id = ?
+            ORDER BY created_at
+            "#,
+            card,
+            user,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err(error)?;
+
+        Ok(Payload(notes.into_iter().map(to_api_note).collect()))
+    }
+
+    post notes(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Payload(api::NewNote { card, title, body }): Payload<api::NewNote>,
+    ) -> ApiResult<api::Note> {
+        let error = "Failed to add note";
+
+        check_card(&pool, user, card, api::CardName::Notes, error).await?;
+
+        let id = NoteId::default();
+        let now = jwt::now();
+        let note = query_as!(
+            db::Note,
+            r#"
+            INSERT INTO notes (id, user_id, card_id, title, body, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING id as "id: _", user_id as "user_id: _", card_id as "card_id: _", title, body, created_at, updated_at
+            "#,
+            id,
+            user,
+            card,
+            title,
+            body,
+            now,
+            now,
+        )
+            .fetch_one(&pool)
+            .await
+            .map_server_err(error)?;
+
+        Ok(Payload(to_api_note(note)))
+    }
+
+    patch notes/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<NoteId>,
+        Payload(api::NoteUpdate { title, body }): Payload<api::NoteUpdate>,
+    ) -> ApiResult<api::Note> {
+        let error = "Failed to update note";
+
+        let now = jwt::now();
+        let note = query_as!(
+            db::Note,
+            r#"
+            UPDATE notes SET title = COALESCE(?, title), body = COALESCE(?, body), updated_at = ?
+            WHERE id = ? AND user_id = ?
+            RETURNING id as "id: _", user_id as "user_id: _", card_id as "card_id: _", title, body, created_at, updated_at
+            "#,
+            title,
+            body,
+            now,
+            id,
+            user,
+        )
+            .fetch_optional(&pool)
+            .await
+            .map_server_err(error)?
+            .ok_or(NOTE_NOT_FOUND)?;
+
+        Ok(Payload(to_api_note(note)))
+    }
+
+    delete notes/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<NoteId>,
+    ) -> ApiResult {
+        let res = query!("DELETE FROM notes WHERE id = ? AND user_id = ?", id, user)
+            .execute(&pool)
+            .await
+            .map_server_err("Failed to remove note")?;
+
+        if res.rows_affected() != 1 {
+            Err(NOTE_NOT_FOUND)
+        } else {
+            Ok(())
+        }
+    }
+
+    get notes/search(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Query(api::NoteSearch { q }): Query<api::NoteSearch>,
+    ) -> ApiResult<Vec<api::NoteMatch>> {
+        let query = notes::search_query(&q);
+        if query.is_empty() {
+            return Ok(Payload(vec![]));
+        }
+
+        let (start, end) = (notes::MARKERS.0.to_string(), notes::MARKERS.1.to_string());
+        let matches = query_as!(
+            api::NoteMatch,
+            r#"
+            SELECT
+                notes.id as "id!: _",
+                notes.card_id as "card!: _",
+                snippet(notes_fts, 0, ?1, ?2, '…', 8) as "title!: String",
+                snippet(notes_fts, 1, ?1, ?2, '…', 24) as "body!: String",
+                bm25(notes_fts) as "rank!: f64"
+            FROM notes_fts
+            JOIN notes ON notes.seq = notes_fts.rowid
+            WHERE notes_fts MATCH ?3 AND notes.user_id = ?4
+            ORDER BY rank
+            LIMIT 50
+            "#,
+            start,
+            end,
+            query,
+            user,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err("Failed to search notes")?;
+
+        Ok(Payload(matches.into_iter().map(|note| api::NoteMatch {
+            title: notes::highlight(&note.title),
+            body: notes::highlight(&note.body),
+            ..note
+        }).collect()))
+    }
+
+    get todos(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Query(api::TodosQuery { card }): Query<api::TodosQuery>,
+    ) -> ApiResult<Vec<api::Todo>> {
+        let error = "Failed to get todo items";
+
+        check_card(&pool, user, card, api::CardName::Todo, error).await?;
+
+        let todos = query_as!(
+            db::Todo,
+            r#"
+            SELECT id as "id: _", user_id as "user_id: _", card_id as "card_id: _", text, pos, due_at, completed_at, created_at
+            FROM todos
+            WHERE card_id = ? AND user_id = ?
+            ORDER BY pos
+            "#,
+            card,
+            user,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err(error)?;
+
+        Ok(Payload(todos.into_iter().map(to_api_todo).collect()))
+    }
+
+    post todos(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Payload(api::NewTodo { card, text, due_at }): Payload<api::NewTodo>,
+    ) -> ApiResult<api::Todo> {
+        let error = "Failed to add todo item";
+
+        check_card(&pool, user, card, api::CardName::Todo, error).await?;
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let todo = conn.transaction(|transact| Box::pin(async move {
+            let last = query_scalar!(
+                r#"SELECT id as "id: TodoId" FROM todos WHERE card_id = ? ORDER BY pos DESC LIMIT 1"#,
+                card,
+            )
+                .fetch_optional(&mut **transact)
+                .await?;
+            let pos = todo_pos(transact, card, None, last).await?.unwrap_or_default();
+
+            let id = TodoId::default();
+            let now = jwt::now();
+            query_as!(
+                db::Todo,
+                r#"
+                INSERT INTO todos (id, user_id, card_id, text, pos, due_at, created_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                RETURNING id as "id: _", user_id as "user_id: _", card_id as "card_id: _", text, pos, due_at, completed_at, created_at
+                "#,
+                id,
+                user,
+                card,
+                text,
+                pos,
+                due_at,
+                now,
+            )
+                .fetch_one(&mut **transact)
+                .await
+        })).await.map_server_err(error)?;
+
+        Ok(Payload(to_api_todo(todo)))
+    }
+
+    patch todos/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<TodoId>,
+        Payload(api::TodoUpdate { text, due_at, completed }): Payload<api::TodoUpdate>,
+    ) -> ApiResult<api::Todo> {
+        let error = "Failed to update todo item";
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let todo = conn.transaction(|transact| Box::pin(async move {
+            let Some(todo) = fetch_todo(&mut **transact, user, id).await? else {
+                return Ok(None);
+            };
+
+            if let Some(text) = text {
+                query!("UPDATE todos SET text = ? WHERE id = ?", text, id)
+                    .execute(&mut **transact)
+                    .await?;
+            }
+
+            if let Some(due_at) = due_at {
+                query!("UPDATE todos SET due_at = ? WHERE id = ?", due_at, id)
+                    .execute(&mut **transact)
+                    .await?;
+            }
+
+            match (completed, todo.completed_at) {
+                (Some(true), None) => {
+                    let now = jwt::now();
+                    query!("UPDATE todos SET completed_at = ? WHERE id = ?", now, id)
+                        .execute(&mut **transact)
+                        .await?;
+                    query!("INSERT INTO todo_completions (todo_id, completed_at) VALUES (?, ?)", id, now)
+                        .execute(&mut **transact)
+                        .await?;
+                }
+                (Some(false), Some(_)) => {
+                    query!("UPDATE todos SET completed_at = NULL WHERE id = ?", id)
+                        .execute(&mut **transact)
+                        .await?;
+                }
+                _ => {}
+            }
+
+            fetch_todo(&mut **transact, user, id).await
+        })).await.map_server_err(error)?.ok_or(TODO_NOT_FOUND)?;
+
+        Ok(Payload(to_api_todo(todo)))
+    }
+
+    post todos/:id/move(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<TodoId>,
+        Payload(api::TodoMove { after }): Payload<api::TodoMove>,
+    ) -> ApiResult<api::Todo> {
+        let error = "Failed to move todo item";
+
+        if after == Some(id) {
+            Err(ApiError::new(ErrorCode::InvalidMove, "Can't move a todo item after itself"))?
+        }
+
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let todo = conn.transaction(|transact| Box::pin(async move {
+            let Some(todo) = fetch_todo(&mut **transact, user, id).await? else {
+                return Ok(Err(TODO_NOT_FOUND));
+            };
+            let Some(pos) = todo_pos(transact, todo.card_id, Some(id), after).await? else {
+                return Ok(Err(ApiError::new(ErrorCode::InvalidMove, "Can only move a todo item within its card")));
+            };
+
+            query!("UPDATE todos SET pos = ? WHERE id = ?", pos, id)
+                .execute(&mut **transact)
+                .await?;
+
+            fetch_todo(&mut **transact, user, id).await.map(|todo| todo.ok_or(TODO_NOT_FOUND))
+        })).await.map_server_err(error)??;
+
+        Ok(Payload(to_api_todo(todo)))
+    }
+
+    delete todos/:id(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<TodoId>,
+    ) -> ApiResult {
+        let res = query!("DELETE FROM todos WHERE id = ? AND user_id = ?", id, user)
+            .execute(&pool)
+            .await
+            .map_server_err("Failed to remove todo item")?;
+
+        if res.rows_affected() != 1 {
+            Err(TODO_NOT_FOUND)
+        } else {
+            Ok(())
+        }
+    }
+
+    get todos/overdue(User(user): User, State(pool): State<SqlitePool>) -> ApiResult<Vec<api::Todo>> {
+        let now = jwt::now();
+        let todos = query_as!(
+            db::Todo,
+            r#"
+            SELECT id as "id: _", user_id as "user_id: _", card_id as "card_id: _", text, pos, due_at, completed_at, created_at
+            FROM todos
+            WHERE user_id = ? AND completed_at IS NULL AND due_at < ?
+            ORDER BY due_at
+            "#,
+            user,
+            now,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err("Failed to get overdue todo items")?;
+
+        Ok(Payload(todos.into_iter().map(to_api_todo).collect()))
+    }
+
+    get todos/:id/history(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<TodoId>,
+    ) -> ApiResult<Vec<i64>> {
+        let error = "Failed to get todo item history";
+
+        fetch_todo(&pool, user, id).await.map_server_err(error)?.ok_or(TODO_NOT_FOUND)?;
+
+        let completions = query_scalar!(
+            "SELECT completed_at FROM todo_completions WHERE todo_id = ? ORDER BY completed_at",
+            id,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err(error)?;
+
+        Ok(Payload(completions))
+    }
+
+    post calc/eval(
+        User(user): User,
+        State(pool): State<SqlitePool>,
+        Payload(api::CalcEval { expr, vars }): Payload<api::CalcEval>,
+    ) -> ApiResult<api::Calculation> {
+        let error = "Failed to evaluate expression";
+
+        // The payload was validated, so every value parses
+        let mut vars = vars
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, eval::parse_number(&value)?.into())))
+            .collect::<HashMap<_, _>>();
+
+        let ans = query_scalar!(
+            "SELECT result FROM calculations WHERE user_id = ? ORDER BY created_at DESC, rowid DESC LIMIT 1",
+            user,
+        )
+            .fetch_optional(&pool)
+            .await
+            .map_server_err(error)?;
+        // Results are formatted the way quantities are typed, units and all
+        if let Some(ans) = ans.and_then(|ans| eval::eval(&ans, &HashMap::new()).ok()) {
+            vars.insert(eval::ANS.into(), ans);
+        }
+
+        let result = eval::eval(&expr, &vars).map_err(|eval::Error { message, span }| {
+            let span = api::ExprSpan { start: span.start, end: span.end };
+            ApiError::custom(ErrorCode::InvalidExpression, message).field("expr").details(span)
+        })?;
+        let result = eval::format(&result);
+
+        let id = CalculationId::default();
+        let now = jwt::now();
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        conn.transaction(|transact| Box::pin(async move {
+            query!(
+                "INSERT INTO calculations (id, user_id, expr, result, created_at) VALUES (?, ?, ?, ?, ?)",
+                id,
+                user,
+                expr,
+                result,
+                now,
+            )
+                .execute(&mut **transact)
+                .await?;
+
+            query!(
+                r#"
+                DELETE FROM calculations
+                WHERE user_id = ?1 AND id NOT IN (
+                    SELECT id FROM calculations
+                    WHERE user_id = ?1
+                    ORDER BY created_at DESC, rowid DESC
+                    LIMIT ?2
+                )
+                "#,
+                user,
+                calculator::HISTORY_LEN,
+            )
+                .execute(&mut **transact)
+                .await?;
+
+            Result::<_, SqlxError>::Ok(api::Calculation { id, expr, result, created_at: now })
+        })).await.map_server_err(error).map(Payload)
+    }
+
+    get calc/history(User(user): User, State(pool): State<SqlitePool>) -> ApiResult<Vec<api::Calculation>> {
+        let history = query_as!(
+            db::Calculation,
+            r#"
+            SELECT id as "id: _", user_id as "user_id: _", expr, result, created_at
+            FROM calculations
+            WHERE user_id = ?
+            ORDER BY created_at DESC, rowid DESC
+            "#,
+            user,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err("Failed to get calculation history")?;
+
+        Ok(Payload(
+            history
+                .into_iter()
+                .map(|db::Calculation { id, expr, result, created_at, .. }| api::Calculation { id, expr, result, created_at })
+                .collect(),
+        ))
+    }
+
+    delete calc/history(User(user): User, State(pool): State<SqlitePool>) -> ApiResult {
+        query!("DELETE FROM calculations WHERE user_id = ?", user)
+            .execute(&pool)
+            .await
+            .map_server_err("Failed to clear calculation history")?;
+
+        Ok(())
+    }
+
+    get events(
+        User(user): User,
+        State(events): State<Events>,
+    ) -> Sse<impl Stream<Item = Result<SseEvent, axum::Error>>> {
+        let stream = BroadcastStream::new(events.subscribe(user))
+            // a lagging stream has missed events, so tell the client to start over
+            .map(|event| SseEvent::default().json_data(event.unwrap_or(api::Event::Resync)));
+
+        Sse::new(stream).keep_alive(KeepAlive::default())
+    }
+
+    get admin/users(
+        _: RequireRole<Admin>,
+        State(pool): State<SqlitePool>,
+        Query(api::UsersQuery { q, limit, offset }): Query<api::UsersQuery>,
+    ) -> ApiResult<Vec<api::UserSummary>> {
+        let pattern = q.as_deref().map(contains_pattern);
+        let limit = limit.unwrap_or(USERS_PAGE).clamp(0, MAX_USERS_PAGE);
+        let offset = offset.unwrap_or_default().max(0);
+
+        let users = query_as!(
+            api::UserSummary,
+            r#"
+            SELECT
+                id as "id: _",
+                username,
+                role as "role: _",
+                disabled_at,
+                (SELECT COUNT(*) FROM cards WHERE cards.user_id = users.id) as "cards!: i64"
+            FROM users
+            WHERE ?1 IS NULL OR username LIKE ?1 ESCAPE '\'
+            ORDER BY username
+            LIMIT ?2 OFFSET ?3
+            "#,
+            pattern,
+            limit,
+            offset,
+        )
+            .fetch_all(&pool)
+            .await
+            .map_server_err("Failed to get users")?;
+
+        Ok(Payload(users))
+    }
+
+    get admin/users/:id(
+        _: RequireRole<Admin>,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<UserId>,
+    ) -> ApiResult<api::UserSummary> {
+        fetch_user_summary(&pool, id)
+            .await
+            .map_server_err("Failed to get user")?
+            .map(Payload)
+            .ok_or(USER_NOT_FOUND)
+    }
+
+    patch admin/users/:id(
+        RequireRole(admin, ..): RequireRole<Admin>,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<UserId>,
+        Payload(api::UserUpdate { disabled }): Payload<api::UserUpdate>,
+    ) -> ApiResult<api::UserSummary> {
+        let error = "Failed to update user";
+
+        if id == admin && disabled == Some(true) {
+            Err(ApiError::new(ErrorCode::CannotDisableSelf, "Can't disable your own account"))?
+        }
+
+        if let Some(disabled) = disabled {
+            let disabled_at = disabled.then(jwt::now);
+            let mut conn = pool.acquire().await.map_server_err(error)?;
+
+            let found = conn.transaction(|transact| Box::pin(async move {
+                let res = query!("UPDATE users SET disabled_at = ? WHERE id = ?", disabled_at, id)
+                    .execute(&mut **transact)
+                    .await?;
+
+                if disabled {
+                    session::revoke_all(transact, id).await?;
+                }
+
+                Result::<_, anyhow::Error>::Ok(res.rows_affected() == 1)
+            })).await.map_server_err(error)?;
+
+            if !found {
+                Err(USER_NOT_FOUND)?
+            }
+        }
+
+        fetch_user_summary(&pool, id)
+            .await
+            .map_server_err(error)?
+            .map(Payload)
+            .ok_or(USER_NOT_FOUND)
+    }
+
+    post admin/users/:id/reset-code(
+        _: RequireRole<Admin>,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<UserId>,
+    ) -> ApiResult<api::ResetCode> {
+        let error = "Failed to create reset code";
+
+        fetch_user(&pool, id).await.map_server_err(error)?.ok_or(USER_NOT_FOUND)?;
+
+        reset::mint(&pool, id).await.map(Payload).map_server_err(error)
+    }
+
+    post admin/users/:id/password(
+        _: RequireRole<Admin>,
+        State(pool): State<SqlitePool>,
+        Path(id): Path<UserId>,
+        Payload(api::NewPassword { password }): Payload<api::NewPassword>,
+    ) -> ApiResult {
+        let error = "Failed to reset password";
+
+        let hash = password::hash(&password).await.map_server_err(error)?;
+        let mut conn = pool.acquire().await.map_server_err(error)?;
+
+        let found = conn.transaction(|transact| Box::pin(async move {
+            let res = query!("UPDATE users SET password_hash = ? WHERE id = ?", hash, id)
+                .execute(&mut **transact)
+                .await?;
+
+            session::revoke_all(transact, id).await?;
+
+            Result::<_, anyhow::Error>::Ok(res.rows_affected() == 1)
+        })).await.map_server_err(error)?;
+
+        if found {
+            Ok(())
+        } else {
+            Err(USER_NOT_FOUND)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestApp, PASSWORD};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    /// Enables two-factor authentication for a user, returning the secret
+    async fn enable_totp(app: &TestApp, token: &str) -> String {
+        let secret = totp::secret();
+        let user = testing::user(token);
+        let now = jwt::now();
+        query!(
+            "INSERT INTO totp (user_id, secret, confirmed_at) VALUES (?, ?, ?)",
+            user,
+            secret,
+            now,
+        )
+        .execute(&app.pool)
+        .await
+        .unwrap();
+        secret
+    }
+
+    async fn challenge(app: &TestApp, username: &str) -> String {
+        let credentials = json!({ "username": username, "password": PASSWORD });
+        let (status, res) = app.request("POST", "/login", None, Some(credentials)).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        res["challenge"].as_str().unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_a_code_once() {
+        let app = TestApp::new().await;
+        let token = app.signup("alice").await;
+        let secret = enable_totp(&app, &token).await;
+
+        let challenge = challenge(&app, "alice").await;
+        let code = totp::generate(&secret, jwt::now());
+        let login = json!({ "challenge": challenge, "code": code });
+        let (status, res) = app
+            .request("POST", "/login/totp", None, Some(login.clone()))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        assert!(res["token"].is_string());
+
+        let (status, res) = app.request("POST", "/login/totp", None, Some(login)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{res}");
+        assert_eq!(res["code"], "invalidCode");
+    }
+
+    /// Makes a session look like it was logged in to before the reauthentication window
+    async fn age_session(app: &TestApp, token: &str) {
+        let claim = Claim::decode(token).unwrap();
+        query!(
+            "UPDATE sessions SET created_at = created_at - ? WHERE id = ?",
+            REAUTH_WINDOW,
+            claim.sid,
+        )
+        .execute(&app.pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn password_less_accounts_confirm_by_logging_in() {
+        let app = TestApp::with_mock_idp().await;
+        let confirmation = json!({ "password": "" });
+
+        let token = app.oidc_login("carol").await;
+        let (status, res) = app
+            .request(
+                "POST",
+                "/account/totp",
+                Some(&token),
+                Some(confirmation.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+
+        age_session(&app, &token).await;
+        let (status, res) = app
+            .request("POST", "/account/totp", Some(&token), Some(confirmation))
+            .await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{res}");
+        assert_eq!(res["code"], "reauthenticationRequired");
+    }
+
+    #[tokio::test]
+    async fn password_less_accounts_set_a_password() {
+        let app = TestApp::with_mock_idp().await;
+        let change = json!({ "oldPassword": "", "newPassword": PASSWORD });
+
+        let token = app.oidc_login("carol").await;
+        age_session(&app, &token).await;
+        let (status, res) = app
+            .request(
+                "PATCH",
+                "/account/password",
+                Some(&token),
+                Some(change.clone()),
+            )
+            .await;
+        assert_eq!(res["code"], "reauthenticationRequired", "{status} {res}");
+
+        let token = app.oidc_login("carol").await;
+        let (status, res) = app
+            .request(
+                "PATCH",
+                "/account/password",
+                Some(&token),
+                Some(change.clone()),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        let token = res["token"].as_str().unwrap();
+
+        // From now on, the password is what confirms changes
+        let (status, res) = app
+            .request("PATCH", "/account/password", Some(token), Some(change))
+            .await;
+        assert_eq!(res["code"], "invalidPassword", "{status} {res}");
+        let credentials = json!({ "username": "carol", "password": PASSWORD });
+        let (status, res) = app.request("POST", "/login", None, Some(credentials)).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+    }
+
+    #[tokio::test]
+    async fn deletes_password_less_accounts() {
+        let app = TestApp::with_mock_idp().await;
+        let confirmation = json!({ "password": "" });
+
+        let token = app.oidc_login("carol").await;
+        let user = testing::user(&token);
+        app.add_card(&token, api::CardName::Notes).await;
+
+        age_session(&app, &token).await;
+        let (status, res) = app
+            .request(
+                "DELETE",
+                "/account",
+                Some(&token),
+                Some(confirmation.clone()),
+            )
+            .await;
+        assert_eq!(res["code"], "reauthenticationRequired", "{status} {res}");
+        assert!(fetch_user(&app.pool, user).await.unwrap().is_some());
+
+        let token = app.oidc_login("carol").await;
+        let (status, res) = app
+            .request("DELETE", "/account", Some(&token), Some(confirmation))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        assert!(fetch_user(&app.pool, user).await.unwrap().is_none());
+        let identities = query_scalar!("SELECT COUNT(*) FROM identities")
+            .fetch_one(&app.pool)
+            .await
+            .unwrap();
+        assert_eq!(identities, 0);
+
+        // The identity signs up a new account from then on
+        let token = app.oidc_login("carol").await;
+        assert_ne!(testing::user(&token), user);
+    }
+
+    #[tokio::test]
+    async fn locks_out_codes_after_failures() {
+        let app = TestApp::new().await;
+        let token = app.signup("alice").await;
+        let secret = enable_totp(&app, &token).await;
+
+        let challenge = challenge(&app, "alice").await;
+        for attempt in 1..=MAX_CODE_FAILURES {
+            let login = json!({ "challenge": challenge, "code": "000000" });
+            let (status, res) = app.request("POST", "/login/totp", None, Some(login)).await;
+            if attempt < MAX_CODE_FAILURES {
+                assert_eq!(res["code"], "invalidCode", "{res}");
+            } else {
+                assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{res}");
+            }
+        }
+
+        // Neither the challenge nor a new one take valid codes anymore
+        for challenge in [challenge.clone(), self::challenge(&app, "alice").await] {
+            let code = totp::generate(&secret, jwt::now());
+            let login = json!({ "challenge": challenge, "code": code });
+            let (status, res) = app.request("POST", "/login/totp", None, Some(login)).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{res}");
+        }
     }
 }
@@ -0,0 +1,127 @@
+pub mod calculator;
+pub mod notes;
+pub mod todo;
+
+use crate::schema::api::CardName;
+use calculator::Calculator;
+use notes::Notes;
+use serde_json::Value;
+use todo::Todo;
+
+/// A kind of card that can be placed on the dashboard
+pub trait Card: Send + Sync {
+    fn name(&self) -> CardName;
+
+    /// Names this card was previously stored under, which get migrated to the current one
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// State of a newly added card
+    fn default_state(&self) -> Option<Value> {
+        None
+    }
+
+    /// Maximum size of the serialized state in bytes
+    fn max_state_len(&self) -> usize {
+        1024
+    }
+
+    fn validate_state(&self, state: &Value) -> bool;
+
+    /// Upgrades state stored by an older version of this card, or discards it
+    fn migrate_state(&self, state: Value) -> Option<Value> {
+        self.validate_state(&state).then_some(state)
+    }
+
+    /// Runs a server-side action on the card's state, returning the new state
+    fn action(&self, action: &str, _state: Option<Value>, _args: Value) -> Result<Value, String> {
+        Err(format!("Unknown action: {action}"))
+    }
+}
+
+/// Lists every kind of card once, by its name and its implementation. The `match` makes
+/// sure each [`CardName`] has a card.
+macro_rules! registry {
+    ($($name:ident => $card:expr),* $(,)?) => {
+        const REGISTRY: &[&dyn Card] = &[$(&$card),*];
+
+        impl CardName {
+            pub fn card(&self) -> &'static dyn Card {
+                match self {
+                    $(Self::$name => &$card,)*
+                }
+            }
+        }
+    };
+}
+
+registry! {
+    Calculator => Calculator,
+    Notes => Notes,
+    Todo => Todo,
+}
+
+/// Looks up the card a stored name belongs to, along with whether the name is obsolete
+pub fn resolve(stored: &str) -> Option<(&'static dyn Card, bool)> {
+    if let Ok(name) = serde_json::from_str::<CardName>(stored) {
+        return Some((name.card(), false));
+    }
+
+    let stored = serde_json::from_str::<String>(stored).ok()?;
+    REGISTRY
+        .iter()
+        .find(|card| card.aliases().contains(&stored.as_str()))
+        .map(|&card| (card, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schemars::schema_for;
+
+    /// Every [`CardName`], as listed by its schema rather than by the registry
+    fn names() -> Vec<CardName> {
+        schema_for!(CardName)
+            .get("enum")
+            .and_then(Value::as_array)
+            .unwrap()
+            .iter()
+            .map(|name| serde_json::from_value(name.clone()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn registry_matches_names() {
+        let names = names();
+        assert_eq!(REGISTRY.len(), names.len());
+
+        for name in names {
+            assert_eq!(name.card().name(), name.clone());
+            assert_eq!(
+                REGISTRY.iter().filter(|card| card.name() == name).count(),
+                1,
+                "{name:?}",
+            );
+        }
+        for card in REGISTRY {
+            assert_eq!(card.name().card().name(), card.name());
+        }
+    }
+
+    #[test]
+    fn resolves_names_and_aliases() {
+        for name in names() {
+            let stored = serde_json::to_string(&name).unwrap();
+            let (card, renamed) = resolve(&stored).unwrap();
+            assert_eq!((card.name(), renamed), (name.clone(), false));
+
+            for alias in card.aliases() {
+                let stored = serde_json::to_string(alias).unwrap();
+                let (card, renamed) = resolve(&stored).unwrap();
+                assert_eq!((card.name(), renamed), (name.clone(), true), "{alias}");
+            }
+        }
+        assert!(resolve("\"timer\"").is_none());
+    }
+}
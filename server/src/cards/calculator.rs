@@ -0,0 +1,108 @@
+pub mod eval;
+
+use super::Card;
+use crate::schema::api::CardName;
+use serde::{de::Error, Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+/// Number of calculations kept in a user's history
+pub const HISTORY_LEN: i64 = 100;
+
+pub struct Calculator;
+
+/// A finite decimal the evaluator can compute with, unlike `NaN`, `inf` or `1e999999`
+fn decimal<'de, D: Deserializer<'de>>(deser: D) -> Result<String, D::Error> {
+    let decimal = <&str>::deserialize(deser)?;
+
+    if eval::parse_number(decimal).is_some() {
+        Ok(decimal.into())
+    } else {
+        Err(D::Error::custom("Invalid decimal"))
+    }
+}
+
+#[derive(Deserialize)]
+enum Op {
+    #[serde(rename = "+")]
+    Add,
+    #[serde(rename = "-")]
+    Sub,
+    #[serde(rename = "*")]
+    Mul,
+    #[serde(rename = "/")]
+    Div,
+    #[serde(rename = "^")]
+    Pow,
+}
+
+#[allow(unused)]
+#[derive(Deserialize)]
+#[serde(tag = "state", rename_all = "camelCase")]
+enum State {
+    First {
+        #[serde(deserialize_with = "decimal")]
+        first: String,
+        decimal: u32,
+    },
+    Op {
+        #[serde(deserialize_with = "decimal")]
+        first: String,
+        op: Op,
+    },
+    Second {
+        op: Op,
+        #[serde(deserialize_with = "decimal")]
+        first: String,
+        #[serde(deserialize_with = "decimal")]
+        second: String,
+        decimal: u32,
+    },
+    Result {
+        #[serde(deserialize_with = "decimal")]
+        result: String,
+    },
+}
+
+impl Card for Calculator {
+    fn name(&self) -> CardName {
+        CardName::Calculator
+    }
+
+    fn default_state(&self) -> Option<Value> {
+        Some(json!({ "state": "first", "first": "0", "decimal": 0 }))
+    }
+
+    fn validate_state(&self, state: &Value) -> bool {
+        State::deserialize(state).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first(first: &str) -> Value {
+        json!({ "state": "first", "first": first, "decimal": 0 })
+    }
+
+    #[test]
+    fn accepts_decimals() {
+        for decimal in ["0", "-12.5", "0.000001", "1e+21", "3.14159e-7"] {
+            assert!(Calculator.validate_state(&first(decimal)), "{decimal}");
+        }
+    }
+
+    #[test]
+    fn rejects_non_finite_decimals() {
+        for decimal in [
+            "NaN", "nan", "inf", "-inf", "infinity", "Infinity", "1e999999", "",
+        ] {
+            assert!(!Calculator.validate_state(&first(decimal)), "{decimal}");
+        }
+    }
+
+    #[test]
+    fn rejects_long_decimals() {
+        assert!(!Calculator.validate_state(&first(&"1".repeat(129))));
+    }
+}
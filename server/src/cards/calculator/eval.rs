@@ -0,0 +1,869 @@
+//! Evaluation of typed calculator expressions with arbitrary-precision decimals.
+//! Supports `+ - * / ^`, parentheses, the constants `pi` and `e`, variables and
+//! the functions in [`FUNCTIONS`]. Angles are in radians.
+//!
+//! Numbers may be followed by the [`UNITS`] they are measured in, e.g. `3 km`, `9.81 m/s^2`
+//! or `2 m^2`, where the `^` belongs to the unit. Quantities are computed in base units and
+//! only added to or compared with ones of the same dimension.
+
+use bigdecimal::{BigDecimal, Context, FromPrimitive, ToPrimitive, Zero};
+use std::{collections::HashMap, f64::consts::LN_10, ops::Range, str::FromStr};
+
+/// Significant digits of a result
+pub const PRECISION: u64 = 50;
+/// Extra digits carried through intermediate results so their rounding errors don't show
+const GUARD: u64 = 10;
+pub const MAX_EXPR_LEN: usize = 1000;
+const MAX_NUMBER_LEN: usize = 128;
+const MAX_DEPTH: usize = 64;
+/// Results may not exceed this many orders of magnitude, smaller ones round to zero
+const MAX_MAGNITUDE: i64 = 10_000;
+/// Largest integer exponent computed by repeated multiplication rather than logarithms
+const MAX_POWI: i64 = 1000;
+/// Trigonometric functions lose all precision for arguments beyond this magnitude
+const MAX_TRIG_MAGNITUDE: i64 = 30;
+const PI: &str = "3.14159265358979323846264338327950288419716939937510582097494459230781640628620899862803482534211706798214808651328230664709384460955058223172535940812848111745028410270193852110555964462294895493038196";
+const E: &str = "2.71828182845904523536028747135266249775724709369995957496696762772407663035354759457138217852516642742746639193200305992181741359662904357290033429526059563073813232862794349076323382988075319525101901";
+
+pub const FUNCTIONS: &[&str] = &[
+    "abs", "sqrt", "cbrt", "exp", "ln", "log", "sin", "cos", "tan",
+];
+const CONSTANTS: &[&str] = &["pi", "π", "e"];
+
+/// Exponents of the base units a quantity is measured in, in the order of [`BASE_UNITS`]
+pub type Dims = [i8; 3];
+const BASE_UNITS: [&str; 3] = ["m", "kg", "s"];
+const NO_DIMS: Dims = [0; 3];
+
+/// Units numbers can be given in, with their size in base units
+pub const UNITS: &[(&str, &str, Dims)] = &[
+    ("m", "1", [1, 0, 0]),
+    ("km", "1000", [1, 0, 0]),
+    ("cm", "0.01", [1, 0, 0]),
+    ("mm", "0.001", [1, 0, 0]),
+    ("in", "0.0254", [1, 0, 0]),
+    ("ft", "0.3048", [1, 0, 0]),
+    ("mi", "1609.344", [1, 0, 0]),
+    ("kg", "1", [0, 1, 0]),
+    ("g", "0.001", [0, 1, 0]),
+    ("mg", "0.000001", [0, 1, 0]),
+    ("lb", "0.45359237", [0, 1, 0]),
+    ("s", "1", [0, 0, 1]),
+    ("ms", "0.001", [0, 0, 1]),
+    ("min", "60", [0, 0, 1]),
+    ("h", "3600", [0, 0, 1]),
+];
+/// Holds the previous result, provided by the server
+pub const ANS: &str = "ans";
+
+/// Why an expression could not be evaluated, with the range of characters it refers to
+#[derive(Debug)]
+pub struct Error {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl Error {
+    fn new(message: impl Into<String>, span: Range<usize>) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, Error>;
+type Spanned = (Quantity, Range<usize>);
+
+/// A number measured in base units
+#[derive(Clone, PartialEq, Debug)]
+pub struct Quantity {
+    pub value: BigDecimal,
+    pub dims: Dims,
+}
+
+impl From<BigDecimal> for Quantity {
+    fn from(value: BigDecimal) -> Self {
+        Self {
+            value,
+            dims: NO_DIMS,
+        }
+    }
+}
+
+impl Quantity {
+    fn is_number(&self) -> bool {
+        self.dims == NO_DIMS
+    }
+}
+
+/// Writes dimensions in base units the way they are typed, e.g. `m*kg/s^2` or `s^-1`
+fn format_dims(dims: &Dims) -> String {
+    let unit = |(name, exp): (&str, i8)| match exp {
+        1 => name.to_owned(),
+        exp => format!("{name}^{exp}"),
+    };
+    let units = || BASE_UNITS.into_iter().zip(dims.iter().copied());
+
+    let numerator = units()
+        .filter(|&(_, exp)| exp > 0)
+        .map(unit)
+        .collect::<Vec<_>>();
+    if numerator.is_empty() {
+        return units()
+            .filter(|&(_, exp)| exp != 0)
+            .map(unit)
+            .collect::<Vec<_>>()
+            .join("*");
+    }
+
+    let mut formatted = numerator.join("*");
+    for (name, exp) in units().filter(|&(_, exp)| exp < 0) {
+        formatted.push('/');
+        formatted.push_str(&unit((name, -exp)));
+    }
+    formatted
+}
+
+/// Describes what a quantity is measured in for errors
+fn describe(dims: &Dims) -> String {
+    if *dims == NO_DIMS {
+        "a plain number".into()
+    } else {
+        format_dims(dims)
+    }
+}
+
+fn unit(name: &str) -> Option<Quantity> {
+    UNITS
+        .iter()
+        .find(|(unit, ..)| *unit == name)
+        .map(|&(_, size, dims)| Quantity {
+            value: size.parse().expect("unit sizes are decimals"),
+            dims,
+        })
+}
+
+/// Multiplies the dimensions of a quantity by a whole power
+fn scale_dims(dims: &Dims, exp: i64, span: &Range<usize>) -> Result<Dims> {
+    let mut scaled = NO_DIMS;
+    for (scaled, &dim) in scaled.iter_mut().zip(dims) {
+        *scaled = i64::from(dim)
+            .checked_mul(exp)
+            .and_then(|dim| i8::try_from(dim).ok())
+            .ok_or_else(|| Error::new("Unit exponent is too large", span.clone()))?;
+    }
+    Ok(scaled)
+}
+
+/// Combines the dimensions of quantities being multiplied, or divided with `sign` -1
+fn combine_dims(a: &Dims, b: &Dims, sign: i8, span: &Range<usize>) -> Result<Dims> {
+    let mut combined = NO_DIMS;
+    for ((combined, &a), &b) in combined.iter_mut().zip(a).zip(b) {
+        *combined = b
+            .checked_mul(sign)
+            .and_then(|b| a.checked_add(b))
+            .ok_or_else(|| Error::new("Unit exponent is too large", span.clone()))?;
+    }
+    Ok(combined)
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Number(BigDecimal),
+    Ident(String),
+    Op(char),
+    Open,
+    Close,
+    Comma,
+    End,
+}
+
+/// Parses a decimal number, rejecting ones too long or too large to compute with
+pub fn parse_number(number: &str) -> Option<BigDecimal> {
+    if number.len() > MAX_NUMBER_LEN {
+        return None;
+    }
+
+    let number = BigDecimal::from_str(number).ok()?;
+    (number.order_of_magnitude().abs() <= MAX_MAGNITUDE).then_some(number)
+}
+
+/// Checks that a name can be used for a variable, i.e. is an identifier
+/// that isn't taken by a function, a constant or the previous result
+pub fn variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_')
+        && !FUNCTIONS.contains(&name)
+        && !CONSTANTS.contains(&name)
+        && name != ANS
+}
+
+/// Formats a result rounded to [`PRECISION`] significant digits, followed by its base units
+pub fn format(Quantity { value, dims }: &Quantity) -> String {
+    let value = value.with_prec(PRECISION).normalized();
+    let number = if value.order_of_magnitude().abs() <= 20 {
+        value.to_plain_string()
+    } else {
+        value.to_scientific_notation()
+    };
+
+    if *dims == NO_DIMS {
+        number
+    } else {
+        format!("{number} {}", format_dims(dims))
+    }
+}
+
+/// Evaluates an expression, looking up any variables it uses in `vars`
+pub fn eval(expr: &str, vars: &HashMap<String, Quantity>) -> Result<Quantity> {
+    let chars = expr.chars().collect::<Vec<_>>();
+    let mut parser = Parser {
+        tokens: tokenize(&chars)?,
+        chars,
+        next: 0,
+        depth: 0,
+        vars,
+        ctx: Context::default()
+            .with_prec(PRECISION + GUARD)
+            .expect("precision is non-zero"),
+    };
+
+    let (value, _) = parser.expr()?;
+    match parser.bump() {
+        (Token::End, _) => Ok(value),
+        (_, span) => Err(parser.unexpected(span)),
+    }
+}
+
+fn tokenize(chars: &[char]) -> Result<Vec<(Token, Range<usize>)>> {
+    let is_digit = |i: usize| chars.get(i).is_some_and(char::is_ascii_digit);
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while let Some(&c) = chars.get(i) {
+        let start = i;
+        i += 1;
+
+        let token = if c.is_whitespace() {
+            continue;
+        } else if c.is_ascii_digit() || c == '.' {
+            while is_digit(i) || chars.get(i) == Some(&'.') {
+                i += 1;
+            }
+            if matches!(chars.get(i), Some('e' | 'E')) {
+                let exp = i + 1 + usize::from(matches!(chars.get(i + 1), Some('+' | '-')));
+                if is_digit(exp) {
+                    i = exp;
+                    while is_digit(i) {
+                        i += 1;
+                    }
+                }
+            }
+
+            let number = chars[start..i].iter().collect::<String>();
+            Token::Number(
+                parse_number(&number).ok_or_else(|| Error::new("Invalid number", start..i))?,
+            )
+        } else if c.is_alphabetic() || c == '_' {
+            while chars
+                .get(i)
+                .is_some_and(|&c| c.is_alphanumeric() || c == '_')
+            {
+                i += 1;
+            }
+            Token::Ident(chars[start..i].iter().collect())
+        } else {
+            match c {
+                '+' | '-' | '*' | '/' | '^' => Token::Op(c),
+                '×' => Token::Op('*'),
+                '÷' => Token::Op('/'),
+                '(' => Token::Open,
+                ')' => Token::Close,
+                ',' => Token::Comma,
+                _ => return Err(Error::new(format!("Unexpected `{c}`"), start..i)),
+            }
+        };
+
+        tokens.push((token, start..i));
+    }
+
+    tokens.push((Token::End, chars.len()..chars.len()));
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    tokens: Vec<(Token, Range<usize>)>,
+    next: usize,
+    depth: usize,
+    vars: &'a HashMap<String, Quantity>,
+    ctx: Context,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].0
+    }
+
+    fn bump(&mut self) -> (Token, Range<usize>) {
+        let token = self.tokens[self.next].clone();
+        if token.0 != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self, span: Range<usize>) -> Error {
+        if span.is_empty() {
+            Error::new("Unexpected end of expression", span)
+        } else {
+            let token = self.chars[span.clone()].iter().collect::<String>();
+            Error::new(format!("Unexpected `{token}`"), span)
+        }
+    }
+
+    fn expect_close(&mut self) -> Result<Range<usize>> {
+        match self.bump() {
+            (Token::Close, span) => Ok(span),
+            (_, span) if span.is_empty() => Err(Error::new("Missing `)`", span)),
+            (_, span) => Err(self.unexpected(span)),
+        }
+    }
+
+    /// Rejects results too large to keep computing with and flushes tiny ones to zero
+    fn check(&self, value: BigDecimal, span: &Range<usize>) -> Result<BigDecimal> {
+        let magnitude = value.order_of_magnitude();
+
+        if magnitude > MAX_MAGNITUDE {
+            Err(Error::new("Result is too large", span.clone()))
+        } else if magnitude < -MAX_MAGNITUDE {
+            Ok(BigDecimal::zero())
+        } else {
+            Ok(self.ctx.round_decimal(value))
+        }
+    }
+
+    fn expr(&mut self) -> Result<Spanned> {
+        let (mut lhs, mut span) = self.term()?;
+
+        while let &Token::Op(op @ ('+' | '-')) = self.peek() {
+            self.bump();
+            let (rhs, rhs_span) = self.term()?;
+            span = span.start..rhs_span.end;
+
+            if lhs.dims != rhs.dims {
+                let verb = if op == '+' { "add" } else { "subtract" };
+                let (lhs, rhs) = (describe(&lhs.dims), describe(&rhs.dims));
+                return Err(Error::new(format!("Can't {verb} {lhs} and {rhs}"), span));
+            }
+            let value = if op == '+' {
+                lhs.value + rhs.value
+            } else {
+                lhs.value - rhs.value
+            };
+            lhs.value = self.check(value, &span)?;
+        }
+
+        Ok((lhs, span))
+    }
+
+    fn term(&mut self) -> Result<Spanned> {
+        let (mut lhs, mut span) = self.unary()?;
+
+        while let &Token::Op(op @ ('*' | '/')) = self.peek() {
+            self.bump();
+            let (rhs, rhs_span) = self.unary()?;
+            span = span.start..rhs_span.end;
+
+            lhs = if op == '*' {
+                Quantity {
+                    dims: combine_dims(&lhs.dims, &rhs.dims, 1, &span)?,
+                    value: self.check(lhs.value * rhs.value, &span)?,
+                }
+            } else if rhs.value.is_zero() {
+                return Err(Error::new("Division by zero", rhs_span));
+            } else {
+                Quantity {
+                    dims: combine_dims(&lhs.dims, &rhs.dims, -1, &span)?,
+                    value: self.check(lhs.value / rhs.value, &span)?,
+                }
+            };
+        }
+
+        Ok((lhs, span))
+    }
+
+    fn unary(&mut self) -> Result<Spanned> {
+        if self.depth == MAX_DEPTH {
+            let span = self.tokens[self.next].1.clone();
+            return Err(Error::new("Expression is nested too deeply", span));
+        }
+        self.depth += 1;
+
+        let value = match *self.peek() {
+            Token::Op(op @ ('+' | '-')) => {
+                let (_, op_span) = self.bump();
+                let (mut quantity, span) = self.unary()?;
+                if op == '-' {
+                    quantity.value = -quantity.value;
+                }
+                (quantity, op_span.start..span.end)
+            }
+            _ => self.power()?,
+        };
+
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    /// Exponentiation is right associative and binds tighter than a leading minus
+    fn power(&mut self) -> Result<Spanned> {
+        let (base, span) = self.atom()?;
+        if *self.peek() != Token::Op('^') {
+            return Ok((base, span));
+        }
+
+        self.bump();
+        let (exp, exp_span) = self.unary()?;
+        let span = span.start..exp_span.end;
+
+        if !exp.is_number() {
+            return Err(Error::new("Exponents can't have units", exp_span));
+        }
+        let dims = if base.is_number() {
+            NO_DIMS
+        } else {
+            let exp = exp.value.is_integer().then(|| exp.value.to_i64()).flatten();
+            let message = "Quantities with units can only be raised to whole powers";
+            let exp = exp.ok_or_else(|| Error::new(message, exp_span.clone()))?;
+            scale_dims(&base.dims, exp, &span)?
+        };
+
+        let value = self.pow(base.value, exp.value, &span)?;
+        Ok((Quantity { value, dims }, span))
+    }
+
+    /// Parses the units following a number, e.g. `km/h` or `m^2`, into their size in base units,
+    /// if the number has any
+    fn units(&mut self) -> Result<Option<Spanned>> {
+        let mut units: Option<Spanned> = None;
+
+        loop {
+            let start = self.next;
+            // The first unit directly follows the number, further ones are multiplied or divided in
+            let sign = match (&units, self.peek()) {
+                (None, _) => 1,
+                (Some(_), Token::Op('*')) => 1,
+                (Some(_), Token::Op('/')) => -1,
+                _ => break,
+            };
+            if units.is_some() {
+                self.bump();
+            }
+
+            let (unit, mut unit_span) = match self.bump() {
+                (Token::Ident(name), span) if *self.peek() != Token::Open => match unit(&name) {
+                    Some(unit) => (unit, span),
+                    None if units.is_none() && !self.known(&name) => {
+                        return Err(Error::new(format!("Unknown unit `{name}`"), span));
+                    }
+                    None => {
+                        self.next = start;
+                        break;
+                    }
+                },
+                _ => {
+                    self.next = start;
+                    break;
+                }
+            };
+
+            let unit = match self.unit_exponent() {
+                Some((exp, end)) => {
+                    unit_span.end = end;
+                    Quantity {
+                        dims: scale_dims(&unit.dims, exp, &unit_span)?,
+                        value: self.pow(unit.value, exp.into(), &unit_span)?,
+                    }
+                }
+                None => unit,
+            };
+
+            units = Some(match units {
+                None => (unit, unit_span),
+                Some((units, span)) => {
+                    let span = span.start..unit_span.end;
+                    let value = if sign == 1 {
+                        units.value * unit.value
+                    } else {
+                        units.value / unit.value
+                    };
+                    let dims = combine_dims(&units.dims, &unit.dims, sign, &span)?;
+                    (Quantity { value, dims }, span)
+                }
+            });
+        }
+
+        Ok(units)
+    }
+
+    /// Parses the whole power a unit is raised to, e.g. the `^-1` of `s^-1`, returning it along
+    /// with where it ends
+    fn unit_exponent(&mut self) -> Option<(i64, usize)> {
+        if *self.peek() != Token::Op('^') {
+            return None;
+        }
+        let negative = self.tokens.get(self.next + 1)?.0 == Token::Op('-');
+        let number = self.next + 1 + usize::from(negative);
+
+        let (Token::Number(exp), span) = self.tokens.get(number)? else {
+            return None;
+        };
+        let exp = exp.is_integer().then(|| exp.to_i64()).flatten()?;
+        let end = span.end;
+
+        self.next = number + 1;
+        Some((if negative { -exp } else { exp }, end))
+    }
+
+    /// Whether a name is taken by a function, a constant or a variable rather than unknown
+    fn known(&self, name: &str) -> bool {
+        FUNCTIONS.contains(&name) || CONSTANTS.contains(&name) || self.vars.contains_key(name)
+    }
+
+    fn atom(&mut self) -> Result<Spanned> {
+        match self.bump() {
+            (Token::Number(number), span) => match self.units()? {
+                Some((units, units_span)) => {
+                    let span = span.start..units_span.end;
+                    let value = self.check(number * units.value, &span)?;
+                    Ok((
+                        Quantity {
+                            value,
+                            dims: units.dims,
+                        },
+                        span,
+                    ))
+                }
+                None => Ok((number.into(), span)),
+            },
+            (Token::Open, open) => {
+                let (value, _) = self.expr()?;
+                let close = self.expect_close()?;
+                Ok((value, open.start..close.end))
+            }
+            (Token::Ident(name), span) if *self.peek() == Token::Open => {
+                if !FUNCTIONS.contains(&name.as_str()) {
+                    return Err(Error::new(format!("Unknown function `{name}`"), span));
+                }
+
+                self.bump();
+                let mut args = vec![self.expr()?];
+                while *self.peek() == Token::Comma {
+                    self.bump();
+                    args.push(self.expr()?);
+                }
+                let span = span.start..self.expect_close()?.end;
+
+                let Ok([(arg, arg_span)]) = <[Spanned; 1]>::try_from(args) else {
+                    return Err(Error::new(format!("`{name}` takes one argument"), span));
+                };
+                Ok((self.call(&name, arg, &arg_span, &span)?, span))
+            }
+            (Token::Ident(name), span) => match name.as_str() {
+                "pi" | "π" => Ok((self.ctx.round_decimal(PI.parse().unwrap()).into(), span)),
+                "e" => Ok((self.ctx.round_decimal(E.parse().unwrap()).into(), span)),
+                _ => match self.vars.get(&name) {
+                    Some(value) => Ok((value.clone(), span)),
+                    None => Err(Error::new(format!("Unknown variable `{name}`"), span)),
+                },
+            },
+            (_, span) => Err(self.unexpected(span)),
+        }
+    }
+
+    fn call(
+        &self,
+        name: &str,
+        x: Quantity,
+        arg_span: &Range<usize>,
+        span: &Range<usize>,
+    ) -> Result<Quantity> {
+        let root = match name {
+            "sqrt" => Some(2),
+            "cbrt" => Some(3),
+            _ => None,
+        };
+        let dims = match root {
+            _ if name == "abs" || x.is_number() => x.dims,
+            Some(n) if x.dims.iter().all(|dim| dim % n == 0) => x.dims.map(|dim| dim / n),
+            Some(_) => {
+                let message = format!("Can't take `{name}` of {}", format_dims(&x.dims));
+                return Err(Error::new(message, arg_span.clone()));
+            }
+            None => {
+                let message = format!(
+                    "`{name}` takes a plain number, not {}",
+                    format_dims(&x.dims)
+                );
+                return Err(Error::new(message, arg_span.clone()));
+            }
+        };
+        let x = x.value;
+
+        let positive = || {
+            if x > BigDecimal::zero() {
+                Ok(())
+            } else {
+                let message = "Logarithm of a number that isn't positive";
+                Err(Error::new(message, arg_span.clone()))
+            }
+        };
+        let trig = || {
+            if x.order_of_magnitude() > MAX_TRIG_MAGNITUDE {
+                Err(Error::new("Angle is too large", arg_span.clone()))
+            } else {
+                Ok(())
+            }
+        };
+
+        match name {
+            "abs" => Ok(x.abs()),
+            "sqrt" => x.sqrt_with_context(&self.ctx).ok_or_else(|| {
+                let message = "Square root of a negative number";
+                Error::new(message, arg_span.clone())
+            }),
+            "cbrt" => Ok(x.cbrt_with_context(&self.ctx)),
+            "exp" => self.exp(&x, span),
+            "ln" => positive().map(|_| self.ln(&x)),
+            "log" => positive().map(|_| self.ln(&x) / self.ln(&BigDecimal::from(10))),
+            "sin" => trig().map(|_| self.sin(&x)),
+            "cos" => trig().map(|_| self.cos(&x)),
+            "tan" => {
+                trig()?;
+                let cos = self.cos(&x);
+                if cos.is_zero() {
+                    Err(Error::new("Tangent is undefined here", arg_span.clone()))
+                } else {
+                    self.check(self.sin(&x) / cos, span)
+                }
+            }
+            _ => unreachable!("unknown functions are rejected while parsing"),
+        }
+        .map(|value| Quantity {
+            value: self.ctx.round_decimal(value),
+            dims,
+        })
+    }
+
+    fn pow(&self, base: BigDecimal, exp: BigDecimal, span: &Range<usize>) -> Result<BigDecimal> {
+        let exact = exp
+            .is_integer()
+            .then(|| exp.to_i64())
+            .flatten()
+            .filter(|exp| exp.abs() <= MAX_POWI);
+
+        if base.is_zero() {
+            return if exp < BigDecimal::zero() {
+                Err(Error::new("Division by zero", span.clone()))
+            } else if exp.is_zero() {
+                Ok(1.into())
+            } else {
+                Ok(base)
+            };
+        }
+        if let Some(exp) = exact {
+            return self.check(base.powi_with_context(exp, &self.ctx), span);
+        }
+        if base < BigDecimal::zero() && !exp.is_integer() {
+            let message = "Negative number to a fractional power";
+            return Err(Error::new(message, span.clone()));
+        }
+
+        let odd = base < BigDecimal::zero() && !exp.half().is_integer();
+        let value = self.exp(&(exp * self.ln(&base.abs())), span)?;
+        Ok(if odd { -value } else { value })
+    }
+
+    fn exp(&self, x: &BigDecimal, span: &Range<usize>) -> Result<BigDecimal> {
+        let limit = MAX_MAGNITUDE as f64 * LN_10;
+
+        match x.to_f64() {
+            Some(f) if f.abs() <= limit => Ok(x.exp_with_context(&self.ctx)),
+            _ if *x > BigDecimal::zero() => Err(Error::new("Result is too large", span.clone())),
+            _ => Ok(BigDecimal::zero()),
+        }
+    }
+
+    /// Refines a floating point estimate with Halley's method, which triples the correct digits per step
+    fn ln(&self, x: &BigDecimal) -> BigDecimal {
+        let magnitude = x.order_of_magnitude();
+        let (digits, scale) = x.as_bigint_and_exponent();
+        let mantissa = BigDecimal::new(digits, scale + magnitude);
+        let estimate = mantissa.to_f64().unwrap_or(1.0).ln() + magnitude as f64 * LN_10;
+
+        let mut y = BigDecimal::from_f64(estimate).unwrap_or_default();
+        for _ in 0..3 {
+            let exp = y.exp_with_context(&self.ctx);
+            y += self.ctx.round_decimal((x - &exp).double() / (x + &exp));
+        }
+        y
+    }
+
+    fn sin(&self, x: &BigDecimal) -> BigDecimal {
+        let two_pi = BigDecimal::from_str(PI).unwrap().double();
+        let x = self.ctx.round_decimal(x - (x / &two_pi).round(0) * &two_pi);
+        let epsilon = BigDecimal::new(1.into(), (PRECISION + GUARD) as i64 + 1);
+        let x2 = self.ctx.round_decimal(x.square());
+
+        let mut sum = x.clone();
+        let mut term = x.clone();
+        for n in 1u64.. {
+            term = self
+                .ctx
+                .round_decimal(-(term * &x2) / BigDecimal::from(2 * n * (2 * n + 1)));
+            if term.abs() < epsilon {
+                break;
+            }
+            sum += &term;
+        }
+
+        // What's left of the sine of a multiple of pi is noise from the working precision
+        let noise = BigDecimal::new(1.into(), PRECISION as i64);
+        if sum.abs() < noise && x.abs() > noise {
+            BigDecimal::zero()
+        } else {
+            sum
+        }
+    }
+
+    fn cos(&self, x: &BigDecimal) -> BigDecimal {
+        let half_pi = BigDecimal::from_str(PI).unwrap().half();
+        self.sin(&(x + half_pi))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(expr: &str, vars: &[(&str, &str)]) -> Result<String> {
+        let vars = vars
+            .iter()
+            .map(|&(name, value)| (name.into(), parse_number(value).unwrap().into()))
+            .collect();
+        eval(expr, &vars).map(|result| format(&result))
+    }
+
+    fn ok(expr: &str) -> String {
+        eval_with(expr, &[]).unwrap()
+    }
+
+    fn err(expr: &str) -> String {
+        eval_with(expr, &[]).unwrap_err().message
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(ok("1 + 2 * 3"), "7");
+        assert_eq!(ok("(1 + 2) * 3"), "9");
+        assert_eq!(ok("2 * 3 ^ 2"), "18");
+        assert_eq!(ok("10 - 4 - 3"), "3");
+        assert_eq!(ok("8 / 4 / 2"), "1");
+        assert_eq!(ok("2 × 3 ÷ 4"), "1.5");
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        assert_eq!(ok("2 ^ 3 ^ 2"), "512");
+        assert_eq!(ok("-2 ^ 2"), "-4");
+        assert_eq!(ok("2 ^ -1"), "0.5");
+        assert_eq!(ok("(-8) ^ 3"), "-512");
+        assert_eq!(err("(-8) ^ 0.5"), "Negative number to a fractional power");
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(ok("sqrt(16)"), "4");
+        assert_eq!(ok("cbrt(27)"), "3");
+        assert_eq!(ok("abs(-3)"), "3");
+        assert_eq!(ok("log(1000)"), "3");
+        assert_eq!(ok("ln(e)"), "1");
+        assert_eq!(ok("sin(pi)"), "0");
+        assert_eq!(ok("cos(0)"), "1");
+        assert_eq!(err("sqrt(-1)"), "Square root of a negative number");
+        assert_eq!(err("ln(0)"), "Logarithm of a number that isn't positive");
+        assert_eq!(err("sqrt(1, 2)"), "`sqrt` takes one argument");
+        assert_eq!(err("max(1)"), "Unknown function `max`");
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let error = eval_with("1 / (2 - 2)", &[]).unwrap_err();
+        assert_eq!(error.message, "Division by zero");
+        assert_eq!(error.span, 4..11);
+        assert_eq!(err("0 ^ -1"), "Division by zero");
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(
+            eval_with("x * y", &[("x", "2"), ("y", "3.5")]).unwrap(),
+            "7"
+        );
+        assert_eq!(err("x + 1"), "Unknown variable `x`");
+        assert!(!variable_name("sin") && !variable_name("ans") && !variable_name("1x"));
+    }
+
+    #[test]
+    fn errors_point_at_tokens() {
+        let error = eval_with("1 + * 2", &[]).unwrap_err();
+        assert_eq!(
+            (error.message.as_str(), error.span),
+            ("Unexpected `*`", 4..5)
+        );
+        assert_eq!(err("(1 + 2"), "Missing `)`");
+        assert_eq!(err("10 ^ 20000"), "Result is too large");
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(ok("3 km + 200 m"), "3200 m");
+        assert_eq!(ok("2 m^2"), "2 m^2");
+        assert_eq!(ok("(2 m) ^ 2"), "4 m^2");
+        assert_eq!(ok("9.81 m/s^2 * 2 kg"), "19.62 m*kg/s^2");
+        assert_eq!(ok("1 / 2 s"), "0.5 s^-1");
+        assert_eq!(ok("sqrt(4 m^2)"), "2 m");
+        assert_eq!(ok("90 km / 1 h"), "25 m/s");
+        assert_eq!(ok("6 m / 3 m"), "2");
+        assert_eq!(ok("2 m * 3"), "6 m");
+    }
+
+    #[test]
+    fn formatted_units_evaluate_to_themselves() {
+        for expr in ["19.62 m*kg/s^2", "0.5 s^-1", "25 m/s", "2 m^2"] {
+            assert_eq!(ok(expr), expr);
+        }
+    }
+
+    #[test]
+    fn unit_mismatches() {
+        let error = eval_with("3m + 2s", &[]).unwrap_err();
+        assert_eq!(
+            (error.message.as_str(), error.span),
+            ("Can't add m and s", 0..7)
+        );
+        assert_eq!(err("1 - 2 kg"), "Can't subtract a plain number and kg");
+        assert_eq!(err("sin(2 m)"), "`sin` takes a plain number, not m");
+        assert_eq!(err("sqrt(2 m)"), "Can't take `sqrt` of m");
+        assert_eq!(err("2 ^ (1 s)"), "Exponents can't have units");
+        assert_eq!(
+            err("(2 m) ^ 0.5"),
+            "Quantities with units can only be raised to whole powers"
+        );
+        assert_eq!(err("3 meters"), "Unknown unit `meters`");
+    }
+}
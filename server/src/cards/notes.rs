@@ -0,0 +1,155 @@
+use super::Card;
+use crate::schema::{api::CardName, ids::NoteId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const MAX_TITLE_LEN: usize = 200;
+pub const MAX_BODY_LEN: usize = 64 * 1024;
+
+/// Marks the matched words in search snippets
+pub const HIGHLIGHT: (&str, &str) = ("<mark>", "</mark>");
+/// What SQLite marks the matched words with before the snippets are escaped. Notes can't
+/// contain these noncharacters, so they can't be mistaken for the note's own text.
+pub const MARKERS: (char, char) = ('\u{FDD0}', '\u{FDD1}');
+
+pub struct Notes;
+
+#[allow(unused)]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct State {
+    /// The note currently open in the card
+    open: Option<NoteId>,
+}
+
+impl Card for Notes {
+    fn name(&self) -> CardName {
+        CardName::Notes
+    }
+
+    fn default_state(&self) -> Option<Value> {
+        Some(json!({ "open": null }))
+    }
+
+    fn validate_state(&self, state: &Value) -> bool {
+        State::deserialize(state).is_ok()
+    }
+}
+
+/// Turns free text into an FTS5 query that matches notes containing every word as a prefix
+pub fn search_query(text: &str) -> String {
+    text.split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escapes a search snippet as HTML, highlighting the words SQLite marked in it
+pub fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c if c == MARKERS.0 => html.push_str(HIGHLIGHT.0),
+            c if c == MARKERS.1 => html.push_str(HIGHLIGHT.1),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestApp;
+    use axum::http::StatusCode;
+    use sqlx::query;
+
+    #[test]
+    fn highlight_escapes_html() {
+        let snippet = format!("<img onerror='x'> & {}match{}", MARKERS.0, MARKERS.1);
+        assert_eq!(
+            highlight(&snippet),
+            "&lt;img onerror=&#39;x&#39;&gt; &amp; <mark>match</mark>"
+        );
+    }
+
+    #[tokio::test]
+    async fn search_escapes_notes() {
+        let app = TestApp::new().await;
+        let token = app.signup("alice").await;
+        let card = app.add_card(&token, CardName::Notes).await;
+
+        let note = json!({ "card": card, "title": "<b>xss</b>", "body": "<img src=x onerror=alert(1)> banana" });
+        let (status, _) = app
+            .request("POST", "/notes", Some(&token), Some(note))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, matches) = app
+            .request("GET", "/notes/search?q=banana", Some(&token), None)
+            .await;
+        assert_eq!(matches[0]["title"], "&lt;b&gt;xss&lt;/b&gt;");
+        assert_eq!(
+            matches[0]["body"],
+            "&lt;img src=x onerror=alert(1)&gt; <mark>banana</mark>"
+        );
+
+        let marked = json!({ "card": card, "title": "a", "body": format!("{}b", MARKERS.0) });
+        let (status, _) = app
+            .request("POST", "/notes", Some(&token), Some(marked))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn search_survives_rowids_changing() {
+        let app = TestApp::new().await;
+        let token = app.signup("alice").await;
+        let card = app.add_card(&token, CardName::Notes).await;
+
+        let mut ids = vec![];
+        for word in ["apple", "banana", "cherry"] {
+            let note = json!({ "card": card, "title": word, "body": word });
+            let (_, note) = app
+                .request("POST", "/notes", Some(&token), Some(note))
+                .await;
+            ids.push(note["id"].clone());
+        }
+        let (status, _) = app
+            .request(
+                "DELETE",
+                &format!("/notes/{}", ids[0].as_str().unwrap()),
+                Some(&token),
+                None,
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Copying the table renumbers its rowids, like restoring a dump does and VACUUM may
+        for statement in [
+            "CREATE TABLE notes_copy AS SELECT * FROM notes",
+            "DROP TABLE notes",
+            "ALTER TABLE notes_copy RENAME TO notes",
+        ] {
+            query(statement).execute(&app.pool).await.unwrap();
+        }
+
+        for (word, id) in [("banana", &ids[1]), ("cherry", &ids[2])] {
+            let (_, matches) = app
+                .request(
+                    "GET",
+                    &format!("/notes/search?q={word}"),
+                    Some(&token),
+                    None,
+                )
+                .await;
+            assert_eq!(matches.as_array().unwrap().len(), 1, "{word}");
+            assert_eq!(&matches[0]["id"], id, "{word}");
+        }
+    }
+}
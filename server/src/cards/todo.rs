@@ -0,0 +1,42 @@
+use super::Card;
+use crate::schema::api::CardName;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const MAX_TEXT_LEN: usize = 1000;
+
+/// Space left between consecutive items so that moving one only rewrites its own position
+pub const POS_GAP: i64 = 1 << 16;
+
+pub struct Todo;
+
+#[allow(unused)]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct State {
+    hide_completed: bool,
+}
+
+impl Card for Todo {
+    fn name(&self) -> CardName {
+        CardName::Todo
+    }
+
+    fn default_state(&self) -> Option<Value> {
+        Some(json!({ "hideCompleted": false }))
+    }
+
+    fn validate_state(&self, state: &Value) -> bool {
+        State::deserialize(state).is_ok()
+    }
+}
+
+/// Picks a position between two neighbours, or `None` if there is no room left between them
+pub fn pos_between(prev: Option<i64>, next: Option<i64>) -> Option<i64> {
+    match (prev, next) {
+        (None, None) => Some(0),
+        (Some(prev), None) => prev.checked_add(POS_GAP),
+        (None, Some(next)) => next.checked_sub(POS_GAP),
+        (Some(prev), Some(next)) => (next - prev > 1).then(|| prev + (next - prev) / 2),
+    }
+}
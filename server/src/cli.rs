@@ -0,0 +1,57 @@
+use crate::{
+    codegen, reset,
+    schema::{api::Role, ids::UserId},
+};
+use anyhow::{bail, Context, Result};
+use sqlx::{query, query_scalar, SqlitePool};
+use std::path::PathBuf;
+
+/// Administrative tasks to run instead of the server
+#[derive(clap::Subcommand)]
+pub enum Command {
+    /// Set a user's role, e.g. to make the first admin
+    SetRole { username: String, role: Role },
+    /// Mint a one-time code for a user to set a new password with
+    ResetCode { username: String },
+    /// Generate the client's TypeScript types and zod schemas, e.g. into `../client/src/schema.ts`
+    GenerateClient { out: PathBuf },
+}
+
+impl Command {
+    pub async fn run(self, pool: &SqlitePool) -> Result<()> {
+        match self {
+            Self::SetRole { username, role } => {
+                let res = query!(
+                    "UPDATE users SET role = ? WHERE username = ?",
+                    role,
+                    username
+                )
+                .execute(pool)
+                .await?;
+
+                if res.rows_affected() != 1 {
+                    bail!("no user named {username}");
+                }
+                println!("Set the role of {username} to {role:?}");
+            }
+            Self::ResetCode { username } => {
+                let user = query_scalar!(
+                    r#"SELECT id as "id: UserId" FROM users WHERE username = ?"#,
+                    username,
+                )
+                .fetch_optional(pool)
+                .await?
+                .with_context(|| format!("no user named {username}"))?;
+
+                let code = reset::mint(pool, user).await?;
+                println!("{}", code.code);
+            }
+            Self::GenerateClient { out } => {
+                codegen::write(&out)?;
+                println!("Wrote {}", out.display());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+//! TypeScript types and zod schemas for the client, generated from the JSON schemas of [`api`]
+//! types so that the two sides can't drift apart
+
+use crate::{
+    cards::{calculator::eval, notes, todo},
+    schema::api,
+    validate::{MAX_USERNAME_LEN, MIN_USERNAME_LEN},
+};
+use anyhow::{Context, Result};
+use schemars::generate::SchemaSettings;
+use serde_json::{Map, Value};
+use std::{collections::HashSet, fmt::Write, fs, path::Path};
+
+const HEADER: &str = "\
+// Generated by `cargo run -- generate-client`, change the types in `schema::api` instead
+
+import { z } from 'zod'
+";
+
+/// Limits the client checks input against, exported as constants
+const CONSTANTS: &[(&str, usize)] = &[
+    ("MIN_USERNAME_LEN", MIN_USERNAME_LEN),
+    ("MAX_USERNAME_LEN", MAX_USERNAME_LEN),
+    ("MAX_NOTE_TITLE_LEN", notes::MAX_TITLE_LEN),
+    ("MAX_NOTE_BODY_LEN", notes::MAX_BODY_LEN),
+    ("MAX_TODO_TEXT_LEN", todo::MAX_TEXT_LEN),
+    ("MAX_EXPR_LEN", eval::MAX_EXPR_LEN),
+];
+
+const INDENT: &str = "  ";
+
+/// Writes a module with a zod schema and an inferred type for every type in [`api`]
+pub fn write(out: &Path) -> Result<()> {
+    let mut gen = SchemaSettings::draft2020_12().into_generator();
+    api::definitions(&mut gen);
+    let definitions = gen.take_definitions(true);
+
+    let mut ts = String::from(HEADER);
+    ts.push('\n');
+    for (name, value) in CONSTANTS {
+        writeln!(ts, "export const {name} = {value}")?;
+    }
+
+    let mut written = HashSet::new();
+    for name in definitions.keys() {
+        definition(&definitions, name, &mut written, &mut ts)?;
+    }
+
+    fs::write(out, ts).with_context(|| format!("failed to write {}", out.display()))
+}
+
+/// Writes a definition after the ones it refers to, since zod schemas are values
+fn definition(
+    definitions: &Map<String, Value>,
+    name: &str,
+    written: &mut HashSet<String>,
+    ts: &mut String,
+) -> Result<()> {
+    if !written.insert(name.into()) {
+        return Ok(());
+    }
+    let schema = definitions
+        .get(name)
+        .with_context(|| format!("no definition for {name}"))?;
+
+    let mut refs = vec![];
+    collect_refs(schema, &mut refs);
+    for reference in refs {
+        definition(definitions, &reference, written, ts)?;
+    }
+
+    let ident = ident(name);
+    ts.push('\n');
+    if let Some(description) = schema.get("description").and_then(Value::as_str) {
+        doc_comment(description, 0, ts);
+    }
+    writeln!(ts, "export const {ident} = {}", zod(schema, 0))?;
+    writeln!(ts, "export type {name} = z.infer<typeof {ident}>")?;
+    Ok(())
+}
+
+fn collect_refs(schema: &Value, refs: &mut Vec<String>) {
+    match schema {
+        Value::Object(schema) => {
+            for (key, value) in schema {
+                match (key.as_str(), value) {
+                    ("$ref", Value::String(reference)) => refs.push(ref_name(reference).into()),
+                    _ => collect_refs(value, refs),
+                }
+            }
+        }
+        Value::Array(schemas) => schemas.iter().for_each(|schema| collect_refs(schema, refs)),
+        _ => {}
+    }
+}
+
+fn ref_name(reference: &str) -> &str {
+    reference.rsplit('/').next().unwrap_or(reference)
+}
+
+/// The name of a schema's value, e.g. `apiError` for `ApiError`
+fn ident(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_ascii_lowercase())
+        .into_iter()
+        .chain(chars)
+        .collect()
+}
+
+fn doc_comment(description: &str, depth: usize, ts: &mut String) {
+    let indent = INDENT.repeat(depth);
+    let lines = description.lines().collect::<Vec<_>>();
+
+    if let [line] = lines[..] {
+        let _ = writeln!(ts, "{indent}/** {line} */");
+    } else {
+        let _ = writeln!(ts, "{indent}/**");
+        for line in lines {
+            let _ = writeln!(ts, "{indent} * {line}");
+        }
+        let _ = writeln!(ts, "{indent} */");
+    }
+}
+
+/// A single quoted string, as prettier formats them
+fn string(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn literal(value: &Value) -> String {
+    match value {
+        Value::String(value) => string(value),
+        value => value.to_string(),
+    }
+}
+
+/// The `{ message }` argument of a check, from the `errorMessage` keyword if it has one for it
+fn message(schema: &Map<String, Value>, keyword: &str) -> String {
+    schema
+        .get("errorMessage")
+        .and_then(|messages| messages.get(keyword))
+        .and_then(Value::as_str)
+        .map(|message| format!(", {{ message: {} }}", string(message)))
+        .unwrap_or_default()
+}
+
+/// Translates the subset of JSON schema that schemars generates for our types
+fn zod(schema: &Value, depth: usize) -> String {
+    let Some(schema) = schema.as_object() else {
+        return "z.unknown()".into();
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        return ident(ref_name(reference));
+    }
+    if let Some(value) = schema.get("const") {
+        return format!("z.literal({})", literal(value));
+    }
+    if let Some(values) = schema.get("enum").and_then(Value::as_array) {
+        return enumeration(values.iter());
+    }
+    if let Some(variants) = ["oneOf", "anyOf"]
+        .iter()
+        .find_map(|key| schema.get(*key).and_then(Value::as_array))
+    {
+        // Unit variants with doc comments get a schema each, the rest are grouped in an enum
+        let values = variants
+            .iter()
+            .map(
+                |variant| match (variant.get("const"), variant.get("enum")) {
+                    (Some(value), _) => Some(vec![value]),
+                    (_, Some(Value::Array(values))) => Some(values.iter().collect()),
+                    _ => None,
+                },
+            )
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.concat());
+        if let Some(values) = values.filter(|values| values.iter().all(|v| v.is_string())) {
+            return enumeration(values.into_iter());
+        }
+
+        let variants = variants
+            .iter()
+            .map(|variant| format!("{}{},\n", INDENT.repeat(depth + 1), zod(variant, depth + 1)))
+            .collect::<String>();
+        return format!("z.union([\n{variants}{}])", INDENT.repeat(depth));
+    }
+
+    let types = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    let ty = types.iter().find(|&&ty| ty != "null").or(types.first());
+    let nullable = ty != Some(&"null") && types.contains(&"null");
+
+    let mut zod = match ty.copied() {
+        Some("string") => {
+            let mut zod = "z.string()".to_owned();
+            if let Some(min) = schema.get("minLength") {
+                let _ = write!(zod, ".min({min}{})", message(schema, "minLength"));
+            }
+            if let Some(max) = schema.get("maxLength") {
+                let _ = write!(zod, ".max({max}{})", message(schema, "maxLength"));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                let pattern = pattern.replace('/', "\\/");
+                let _ = write!(zod, ".regex(/{pattern}/{})", message(schema, "pattern"));
+            }
+            zod
+        }
+        Some(ty @ ("integer" | "number")) => {
+            let mut zod = "z.number()".to_owned();
+            if ty == "integer" {
+                zod.push_str(".int()");
+            }
+            if let Some(min) = schema.get("minimum") {
+                let _ = write!(zod, ".min({min})");
+            }
+            zod
+        }
+        Some("boolean") => "z.boolean()".into(),
+        Some("null") => "z.null()".into(),
+        Some("array") => format!(
+            "z.array({})",
+            schema
+                .get("items")
+                .map_or("z.unknown()".into(), |items| zod(items, depth))
+        ),
+        Some("object") => object(schema, depth),
+        _ => "z.unknown()".into(),
+    };
+
+    if nullable {
+        zod.push_str(".nullable()");
+    }
+    zod
+}
+
+fn enumeration<'v>(values: impl Iterator<Item = &'v Value>) -> String {
+    let values = values.map(literal).collect::<Vec<_>>();
+    format!("z.enum([{}])", values.join(", "))
+}
+
+fn object(schema: &Map<String, Value>, depth: usize) -> String {
+    let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+        return match schema.get("additionalProperties") {
+            Some(values) => format!("z.record({})", zod(values, depth)),
+            None => "z.object({})".into(),
+        };
+    };
+    let required = schema.get("required").and_then(Value::as_array);
+
+    let mut zod = "z.object({\n".to_owned();
+    for (name, property) in properties {
+        if let Some(description) = property.get("description").and_then(Value::as_str) {
+            doc_comment(description, depth + 1, &mut zod);
+        }
+
+        let key = if name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            name.clone()
+        } else {
+            string(name)
+        };
+        let mut value = self::zod(property, depth + 1);
+        if !required.is_some_and(|required| required.contains(&Value::from(name.as_str()))) {
+            value.push_str(".optional()");
+        }
+        let _ = writeln!(zod, "{}{key}: {value},", INDENT.repeat(depth + 1));
+    }
+    zod.push_str(&INDENT.repeat(depth));
+    zod.push_str("})");
+    zod
+}
@@ -0,0 +1,121 @@
+use crate::{
+    extract::payload::Payload,
+    schema::api::{ApiError, ErrorCode},
+};
+use axum::{
+    extract::rejection::{PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use std::{borrow::Cow, fmt::Debug};
+use tracing::error;
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidRequest
+            | Self::InvalidField
+            | Self::InvalidCredentials
+            | Self::InvalidPassword
+            | Self::UsernameTaken
+            | Self::InvalidCode
+            | Self::LoginExpired
+            | Self::TotpNotEnabled
+            | Self::InvalidResetCode
+            | Self::CannotDisableSelf
+            | Self::WrongCardKind
+            | Self::InvalidCardState
+            | Self::CardActionFailed
+            | Self::InvalidMove => StatusCode::BAD_REQUEST,
+            Self::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unauthenticated
+            | Self::TokenExpired
+            | Self::TokenRevoked
+            | Self::InvalidToken
+            | Self::InvalidRefreshToken
+            | Self::TotpRequired => StatusCode::UNAUTHORIZED,
+            Self::CsrfFailed
+            | Self::PermissionDenied
+            | Self::AccountDisabled
+            | Self::ReauthenticationRequired => StatusCode::FORBIDDEN,
+            Self::NoPendingTotp
+            | Self::OidcDisabled
+            | Self::UserNotFound
+            | Self::SessionNotFound
+            | Self::CardNotFound
+            | Self::NoteNotFound
+            | Self::TodoNotFound => StatusCode::NOT_FOUND,
+            Self::TotpAlreadyEnabled
+            | Self::IdentityLinked
+            | Self::StaleVersion
+            | Self::CardExists => StatusCode::CONFLICT,
+            Self::VersionRequired => StatusCode::PRECONDITION_REQUIRED,
+            Self::InvalidExpression => StatusCode::UNPROCESSABLE_ENTITY,
+        }
+    }
+}
+
+impl ApiError {
+    pub const fn new(code: ErrorCode, message: &'static str) -> Self {
+        Self {
+            code,
+            message: Cow::Borrowed(message),
+            field: None,
+            details: None,
+        }
+    }
+
+    /// An error with a message that is only known at runtime
+    pub fn custom(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            message: Cow::Owned(message.into()),
+            ..Self::new(code, "")
+        }
+    }
+
+    /// Points the error at a field of the request, by its JSON path
+    pub fn field(mut self, field: impl Into<String>) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details)
+            .inspect_err(|err| error!("Failed to serialize error details: {err:?}"))
+            .ok();
+        self
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Payload(self)).into_response()
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::custom(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::custom(ErrorCode::InvalidRequest, rejection.body_text())
+    }
+}
+
+pub trait MapServerError {
+    type Output;
+    fn map_server_err(self, error: &'static str) -> Self::Output;
+}
+impl<T, E: Debug> MapServerError for Result<T, E> {
+    type Output = Result<T, ApiError>;
+    fn map_server_err(self, error: &'static str) -> Self::Output {
+        self.inspect_err(|err| error!("Internal error: {err:?}"))
+            .map_err(|_| ApiError::new(ErrorCode::Internal, error))
+    }
+}
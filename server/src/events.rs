@@ -0,0 +1,37 @@
+use crate::schema::{api, ids::UserId};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+/// How many events a slow stream may fall behind before it has to resync
+const CAPACITY: usize = 64;
+
+/// Broadcasts card changes to every open event stream of a user
+#[derive(Clone, Default)]
+pub struct Events(Arc<Mutex<HashMap<UserId, Sender<api::Event>>>>);
+
+impl Events {
+    pub fn subscribe(&self, user: UserId) -> Receiver<api::Event> {
+        let mut channels = self.0.lock().expect("events lock poisoned");
+
+        // drop channels whose streams have all closed
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+
+        channels
+            .entry(user)
+            .or_insert_with(|| broadcast::channel(CAPACITY).0)
+            .subscribe()
+    }
+
+    pub fn publish(&self, user: UserId, event: api::Event) {
+        let mut channels = self.0.lock().expect("events lock poisoned");
+
+        if let Some(sender) = channels.get(&user) {
+            if sender.send(event).is_err() {
+                channels.remove(&user);
+            }
+        }
+    }
+}
@@ -1,2 +1,6 @@
+pub mod cookie;
+pub mod device;
+pub mod params;
 pub mod payload;
 pub mod user;
+pub mod version;
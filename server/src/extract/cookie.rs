@@ -0,0 +1,197 @@
+use super::payload::Payload;
+use crate::{
+    oidc::{self, Authorization},
+    schema::api::{self, ApiError, ErrorCode},
+    session::{self, REFRESH_TTL},
+};
+use axum::{
+    extract::FromRequestParts,
+    http::{header::SET_COOKIE, request::Parts, HeaderMap, Method},
+    response::{AppendHeaders, IntoResponse, Response},
+};
+use headers::{Cookie, HeaderMapExt};
+use rand::{distributions::Alphanumeric, prelude::*};
+use std::{convert::Infallible, future::Future, pin::Pin};
+
+pub const ACCESS_COOKIE: &str = "token";
+pub const REFRESH_COOKIE: &str = "refresh_token";
+/// The one cookie scripts can read, to repeat it in the CSRF header
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+/// Ties a single sign-on login to the browser that started it
+pub const OIDC_COOKIE: &str = "oidc_state";
+/// Clients opt into cookies by sending `cookie` in this header when they get tokens
+pub const AUTH_MODE_HEADER: &str = "x-auth-mode";
+
+/// Auth cookies are only sent to the API
+const PATH: &str = "/api";
+const CSRF_LEN: usize = 32;
+
+/// How a client keeps its tokens
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum AuthMode {
+    /// Tokens are sent as JSON, and access tokens back as `Authorization: Bearer`
+    Bearer,
+    /// Tokens are set as `HttpOnly` cookies out of reach of scripts,
+    /// with a double-submit CSRF token guarding them
+    Cookie,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for AuthMode {
+    type Rejection = Infallible;
+
+    fn from_request_parts<'p, 's, 'fut>(
+        parts: &'p mut Parts,
+        _: &'s S,
+    ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
+    where
+        'p: 'fut,
+        's: 'fut,
+        Self: 'fut,
+    {
+        Box::pin(async {
+            let cookie = parts
+                .headers
+                .get(AUTH_MODE_HEADER)
+                .is_some_and(|mode| mode.as_bytes().eq_ignore_ascii_case(b"cookie"));
+
+            Ok(if cookie { Self::Cookie } else { Self::Bearer })
+        })
+    }
+}
+
+/// Compares in constant time, so that the CSRF token can't be guessed byte by byte
+fn matches(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Takes a token from a cookie. Browsers send cookies along with cross-site requests,
+/// so unless the request is safe, it must prove to be same-site by repeating the CSRF cookie
+/// in a header, which other sites can't read.
+pub fn token(method: &Method, headers: &HeaderMap, name: &str) -> Result<Option<String>, ApiError> {
+    let Some(cookies) = headers.typed_get::<Cookie>() else {
+        return Ok(None);
+    };
+    let Some(token) = cookies.get(name) else {
+        return Ok(None);
+    };
+
+    if !method.is_safe() {
+        let csrf_token = headers
+            .get(CSRF_HEADER)
+            .and_then(|csrf_token| csrf_token.to_str().ok())
+            .ok_or(ApiError::new(ErrorCode::CsrfFailed, "No CSRF token"))?;
+
+        if !cookies
+            .get(CSRF_COOKIE)
+            .is_some_and(|cookie| matches(cookie, csrf_token))
+        {
+            return Err(ApiError::new(ErrorCode::CsrfFailed, "Invalid CSRF token"));
+        }
+    }
+
+    Ok(Some(token.into()))
+}
+
+fn set_cookie(name: &str, value: &str, path: &str, http_only: bool, max_age: i64) -> String {
+    let http_only = if http_only { "; HttpOnly" } else { "" };
+    format!("{name}={value}; Path={path}; Max-Age={max_age}; Secure; SameSite=Strict{http_only}")
+}
+
+/// The tokens of a session, or a login challenge, in the form the client asked for
+pub struct Authenticated(pub AuthMode, pub api::Login);
+
+impl IntoResponse for Authenticated {
+    fn into_response(self) -> Response {
+        let Self(
+            AuthMode::Cookie,
+            api::Login::Token(api::Token {
+                token,
+                refresh_token,
+            }),
+        ) = self
+        else {
+            return Payload(self.1).into_response();
+        };
+
+        let csrf_token = thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(CSRF_LEN)
+            .map(char::from)
+            .collect::<String>();
+
+        // The access cookie outlives its token, so that clients are told it expired
+        (
+            AppendHeaders([
+                (
+                    SET_COOKIE,
+                    set_cookie(ACCESS_COOKIE, &token, PATH, true, REFRESH_TTL),
+                ),
+                (
+                    SET_COOKIE,
+                    set_cookie(REFRESH_COOKIE, &refresh_token, PATH, true, REFRESH_TTL),
+                ),
+                (
+                    SET_COOKIE,
+                    set_cookie(CSRF_COOKIE, &csrf_token, "/", false, REFRESH_TTL),
+                ),
+            ]),
+            Payload(api::Login::Cookie(api::CsrfToken { csrf_token })),
+        )
+            .into_response()
+    }
+}
+
+/// Sends the user to the provider's login page. The hash of the login's state is kept in a
+/// cookie, so that the login can only be completed by the browser that started it, and a
+/// callback URL someone else started can't log the user into their account.
+pub struct OidcStarted(pub Authorization);
+
+impl IntoResponse for OidcStarted {
+    fn into_response(self) -> Response {
+        let Self(Authorization { url, state }) = self;
+        let cookie = set_cookie(
+            OIDC_COOKIE,
+            &session::hash(&state),
+            PATH,
+            true,
+            oidc::LOGIN_TTL,
+        );
+
+        (
+            AppendHeaders([(SET_COOKIE, cookie)]),
+            Payload(api::OidcRedirect { url: url.into() }),
+        )
+            .into_response()
+    }
+}
+
+/// Whether this browser started the single sign-on login with this state, see [`OidcStarted`]
+pub fn started_oidc(headers: &HeaderMap, state: &str) -> bool {
+    headers
+        .typed_get::<Cookie>()
+        .and_then(|cookies| {
+            cookies
+                .get(OIDC_COOKIE)
+                .map(|hash| matches(hash, &session::hash(state)))
+        })
+        .unwrap_or(false)
+}
+
+/// Clears the auth cookies once a session ended, if there are any
+pub struct ClearCookies;
+
+impl IntoResponse for ClearCookies {
+    fn into_response(self) -> Response {
+        AppendHeaders([
+            (SET_COOKIE, set_cookie(ACCESS_COOKIE, "", PATH, true, 0)),
+            (SET_COOKIE, set_cookie(REFRESH_COOKIE, "", PATH, true, 0)),
+            (SET_COOKIE, set_cookie(CSRF_COOKIE, "", "/", false, 0)),
+        ])
+        .into_response()
+    }
+}
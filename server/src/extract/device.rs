@@ -0,0 +1,42 @@
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts},
+};
+use std::{convert::Infallible, future::Future, net::SocketAddr, pin::Pin};
+
+/// User agents are cut off at this many characters before they are stored
+const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Describes the client a session is started from, so users can tell their sessions apart
+pub struct Device {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Device {
+    type Rejection = Infallible;
+
+    fn from_request_parts<'p, 's, 'fut>(
+        parts: &'p mut Parts,
+        _: &'s S,
+    ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
+    where
+        'p: 'fut,
+        's: 'fut,
+        Self: 'fut,
+    {
+        Box::pin(async {
+            let user_agent = parts
+                .headers
+                .get(USER_AGENT)
+                .and_then(|user_agent| user_agent.to_str().ok())
+                .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect());
+            let ip = parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+            Ok(Self { user_agent, ip })
+        })
+    }
+}
@@ -0,0 +1,12 @@
+use crate::schema::api::ApiError;
+use axum::extract::FromRequestParts;
+
+/// Axum's `Path`, rejecting with an [`ApiError`]
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+/// Axum's `Query`, rejecting with an [`ApiError`]
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
@@ -1,18 +1,66 @@
+use crate::{
+    schema::api::{ApiError, ErrorCode},
+    validate::{Errors, Fields, Validate},
+};
 use anyhow::Result;
 use axum::{
     body::Bytes,
     extract::{FromRequest, Request},
-    http::StatusCode,
     response::{IntoResponse, Response},
     Json,
 };
+use schemars::{schema_for, JsonSchema};
 use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 use std::{future::Future, pin::Pin};
 
+/// A JSON body, which has to pass the [`Validate`] rules of its type
 pub struct Payload<T>(pub T);
 
-impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Payload<T> {
-    type Rejection = (StatusCode, String);
+/// Deserializes a payload, reporting every broken field rather than stopping at the first one.
+/// Each field is checked against its type, and is reported if it is required but missing.
+/// Only once the payload deserializes are the [`Validate`] rules checked, as they need its fields.
+fn deserialize<T: DeserializeOwned + JsonSchema + Fields + Validate>(
+    bytes: &[u8],
+) -> Result<T, ApiError> {
+    let value = serde_json::from_slice::<Value>(bytes).map_err(|err| {
+        let message = err.to_string();
+        let message = message
+            .split_once(" at line")
+            .map_or(&*message, |(message, _)| message);
+        ApiError::custom(ErrorCode::InvalidRequest, message)
+    })?;
+
+    if let Value::Object(fields) = &value {
+        let mut errors = Errors::default();
+        let schema = schema_for!(T);
+        let required = schema.get("required").and_then(Value::as_array);
+        for field in required.into_iter().flatten().filter_map(Value::as_str) {
+            if !fields.contains_key(field) {
+                errors.add(field, "Required");
+            }
+        }
+        T::check_fields(fields, &mut errors);
+        errors.into_result()?;
+    }
+
+    let payload = serde_path_to_error::deserialize::<_, T>(&value).map_err(|err| {
+        // The fields were all fine, so this is about the payload as a whole
+        ApiError::custom(ErrorCode::InvalidRequest, err.into_inner().to_string())
+    })?;
+
+    let mut errors = Errors::default();
+    payload.validate(&mut errors);
+    errors.into_result()?;
+    Ok(payload)
+}
+
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    T: DeserializeOwned + JsonSchema + Fields + Validate,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
 
     fn from_request<'s, 'fut>(
         req: Request,
@@ -25,17 +73,9 @@ impl<T: DeserializeOwned, S: Send + Sync> FromRequest<S> for Payload<T> {
         Box::pin(async {
             let bytes = Bytes::from_request(req, state)
                 .await
-                .map_err(|_| (StatusCode::BAD_REQUEST, "No data received".into()))?;
-
-            serde_json::from_slice(&bytes).map(Self).map_err(|err| {
-                let err = err.to_string();
-                let err = err
-                    .split_once("at line")
-                    .map(|(err, _)| err)
-                    .unwrap_or(&err);
+                .map_err(|_| ApiError::new(ErrorCode::InvalidRequest, "No data received"))?;
 
-                (StatusCode::BAD_REQUEST, err.into())
-            })
+            deserialize(&bytes).map(Self)
         })
     }
 }
@@ -45,3 +85,100 @@ impl<T: Serialize> IntoResponse for Payload<T> {
         Json(self.0).into_response()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        schema::api,
+        testing::TestApp,
+        validate::{PASSWORD_REQUIRED, USERNAME_INVALID, USERNAME_TOO_SHORT},
+    };
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use std::fmt::Debug;
+
+    fn errors<T: DeserializeOwned + JsonSchema + Fields + Validate + Debug>(body: Value) -> Value {
+        let err = deserialize::<T>(body.to_string().as_bytes()).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidField, "{}", err.message);
+        err.details.unwrap()
+    }
+
+    fn messages(errors: &Value) -> Vec<&str> {
+        let errors = errors.as_array().unwrap().iter();
+        errors
+            .map(|error| error["message"].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_broken_field() {
+        let details = errors::<api::Credentials>(json!({ "username": "a!", "password": "" }));
+        assert_eq!(
+            messages(&details["username"]),
+            [USERNAME_TOO_SHORT, USERNAME_INVALID],
+        );
+        assert_eq!(messages(&details["password"]), [PASSWORD_REQUIRED]);
+
+        let details = errors::<api::Credentials>(json!({ "username": 1 }));
+        assert!(messages(&details["username"])[0].contains("expected a string"));
+        assert_eq!(messages(&details["password"]), ["Required"]);
+
+        let details = errors::<api::Credentials>(json!({ "username": 1, "password": false }));
+        assert!(messages(&details["username"])[0].contains("expected a string"));
+        assert!(messages(&details["password"])[0].contains("expected a string"));
+    }
+
+    #[test]
+    fn checks_fields_of_any_type() {
+        let details = errors::<api::NewCard>(json!({ "name": "nope", "pos": "top" }));
+        assert!(messages(&details["name"])[0].contains("unknown variant"));
+        assert!(messages(&details["pos"])[0].contains("expected i64"));
+
+        // Nested values are reported by their own path, and defaulted fields aren't required
+        let details = errors::<api::CalcEval>(json!({ "expr": 1, "vars": { "x": 1 } }));
+        assert_eq!(messages(&details["vars.x"]).len(), 1);
+        assert_eq!(messages(&details["expr"]).len(), 1);
+        let details = errors::<api::CalcEval>(json!({ "expr": "", "vars": { "-": "1" } }));
+        assert_eq!(messages(&details["vars.-"]), ["Invalid variable name"]);
+
+        let update = json!({ "dueAt": null });
+        let update = deserialize::<api::TodoUpdate>(update.to_string().as_bytes()).unwrap();
+        assert_eq!(update.due_at, Some(None));
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let err = deserialize::<api::Credentials>(b"{").unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        let err = deserialize::<api::Credentials>(b"[]").unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn reports_weak_passwords_with_the_other_fields() {
+        let app = TestApp::new().await;
+
+        let signup = json!({ "username": "a!", "password": "short" });
+        let (status, res) = app.request("POST", "/signup", None, Some(signup)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(res["code"], "invalidField");
+        assert_eq!(messages(&res["details"]["username"]).len(), 2);
+        assert!(res["details"]["username"][0].get("rule").is_none());
+        assert!(res["details"]["password"].as_array().unwrap().contains(
+            &json!({ "message": "Password must be at least 8 characters", "rule": "minLength" })
+        ));
+
+        let token = app.signup("alice").await;
+        let change = json!({ "oldPassword": "", "newPassword": "password" });
+        let (status, res) = app
+            .request("PATCH", "/account/password", Some(&token), Some(change))
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(res["details"]["newPassword"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({ "message": "Password is too common", "rule": "blocklist" })));
+    }
+}
@@ -1,21 +1,54 @@
-use crate::{jwt::Claim, schema::ids::UserId};
+use super::cookie::{self, ACCESS_COOKIE};
+use crate::{
+    error::MapServerError,
+    jwt::{Challenge, Claim},
+    schema::{
+        api::{self, ApiError, ErrorCode},
+        ids::UserId,
+    },
+    session,
+};
 use axum::{
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
     RequestPartsExt,
 };
 use axum_extra::TypedHeader;
 use headers::{authorization::Bearer, Authorization};
-use std::{future::Future, pin::Pin};
+use jsonwebtoken::errors::ErrorKind;
+use sqlx::{query_scalar, SqlitePool};
+use std::{future::Future, marker::PhantomData, pin::Pin};
+
+/// The claim of a valid access token whose session is still active.
+/// The token comes from the `Authorization: Bearer` header, or else from the access cookie.
+pub struct Session(pub Claim);
 
 pub struct User(pub UserId);
 
-impl<S> FromRequestParts<S> for User {
-    type Rejection = (StatusCode, &'static str);
+/// A role as a type, to name in [`RequireRole`]
+pub trait Role {
+    const ROLE: api::Role;
+}
+
+pub struct Admin;
+
+impl Role for Admin {
+    const ROLE: api::Role = api::Role::Admin;
+}
+
+/// A user with at least the role `R`. The role is looked up on every request,
+/// so that taking it away takes effect immediately.
+pub struct RequireRole<R>(pub UserId, pub PhantomData<R>);
+
+impl<S: Send + Sync> FromRequestParts<S> for Session
+where
+    SqlitePool: FromRef<S>,
+{
+    type Rejection = ApiError;
 
     fn from_request_parts<'p, 's, 'fut>(
         parts: &'p mut Parts,
-        _: &'s S,
+        state: &'s S,
     ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
     where
         'p: 'fut,
@@ -23,15 +56,92 @@ impl<S> FromRequestParts<S> for User {
         Self: 'fut,
     {
         Box::pin(async {
-            let TypedHeader(Authorization(bearer)) = parts
-                .extract::<TypedHeader<Authorization<Bearer>>>()
-                .await
-                .map_err(|_| (StatusCode::UNAUTHORIZED, "No Bearer auth header"))?;
-            let claim = Claim::decode(bearer.token()).map_err(|e| {
-                println!("{e:?}");
-                (StatusCode::UNAUTHORIZED, "Invalid auth header")
+            let token = match parts.extract::<TypedHeader<Authorization<Bearer>>>().await {
+                Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_owned(),
+                Err(_) => cookie::token(&parts.method, &parts.headers, ACCESS_COOKIE)?.ok_or(
+                    ApiError::new(ErrorCode::Unauthenticated, "No Bearer auth header"),
+                )?,
+            };
+            let claim = Claim::decode(&token).map_err(|err| match err.kind() {
+                ErrorKind::ExpiredSignature => {
+                    ApiError::new(ErrorCode::TokenExpired, "Token expired")
+                }
+                _ if Challenge::decode(&token).is_ok() => ApiError::new(
+                    ErrorCode::TotpRequired,
+                    "Two-factor authentication required",
+                ),
+                _ => ApiError::new(ErrorCode::InvalidToken, "Invalid auth header"),
             })?;
+
+            let active = session::touch(&SqlitePool::from_ref(state), &claim)
+                .await
+                .map_server_err("Failed to check session")?;
+
+            if active {
+                Ok(Self(claim))
+            } else {
+                Err(ApiError::new(ErrorCode::TokenRevoked, "Token revoked"))
+            }
+        })
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for User
+where
+    SqlitePool: FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    fn from_request_parts<'p, 's, 'fut>(
+        parts: &'p mut Parts,
+        state: &'s S,
+    ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
+    where
+        'p: 'fut,
+        's: 'fut,
+        Self: 'fut,
+    {
+        Box::pin(async {
+            let Session(claim) = Session::from_request_parts(parts, state).await?;
             Ok(Self(claim.sub))
         })
     }
 }
+
+impl<S: Send + Sync, R: Role> FromRequestParts<S> for RequireRole<R>
+where
+    SqlitePool: FromRef<S>,
+{
+    type Rejection = ApiError;
+
+    fn from_request_parts<'p, 's, 'fut>(
+        parts: &'p mut Parts,
+        state: &'s S,
+    ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
+    where
+        'p: 'fut,
+        's: 'fut,
+        Self: 'fut,
+    {
+        Box::pin(async {
+            let User(user) = User::from_request_parts(parts, state).await?;
+
+            let role = query_scalar!(
+                r#"SELECT role as "role: api::Role" FROM users WHERE id = ?"#,
+                user,
+            )
+            .fetch_optional(&SqlitePool::from_ref(state))
+            .await
+            .map_server_err("Failed to check role")?;
+
+            if role.is_some_and(|role| role >= R::ROLE) {
+                Ok(Self(user, PhantomData))
+            } else {
+                Err(ApiError::new(
+                    ErrorCode::PermissionDenied,
+                    "Permission denied",
+                ))
+            }
+        })
+    }
+}
@@ -0,0 +1,64 @@
+use crate::schema::api::{ApiError, ErrorCode};
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        header::{ETAG, IF_MATCH},
+        request::Parts,
+        HeaderValue,
+    },
+    response::{IntoResponse, Response},
+};
+use std::{future::Future, pin::Pin};
+
+/// A per-user layout version, sent to clients as an ETag
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Version(pub i64);
+
+/// The layout version a write is based on, taken from the If-Match header
+pub struct IfMatch(pub Version);
+
+/// A response tagged with the layout version it reflects
+pub struct Versioned<T>(pub Version, pub T);
+
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = ApiError;
+
+    fn from_request_parts<'p, 's, 'fut>(
+        parts: &'p mut Parts,
+        _: &'s S,
+    ) -> Pin<Box<dyn Future<Output = Result<Self, Self::Rejection>> + Send + 'fut>>
+    where
+        'p: 'fut,
+        's: 'fut,
+        Self: 'fut,
+    {
+        Box::pin(async {
+            let if_match = parts.headers.get(IF_MATCH).ok_or(ApiError::new(
+                ErrorCode::VersionRequired,
+                "No If-Match header",
+            ))?;
+
+            if_match
+                .to_str()
+                .ok()
+                .and_then(|tag| tag.trim().strip_prefix('"')?.strip_suffix('"'))
+                .and_then(|version| version.parse().ok())
+                .map(|version| Self(Version(version)))
+                .ok_or(ApiError::new(
+                    ErrorCode::InvalidRequest,
+                    "Invalid If-Match header",
+                ))
+        })
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for Versioned<T> {
+    fn into_response(self) -> Response {
+        let Self(Version(version), inner) = self;
+        let mut res = inner.into_response();
+        if let Ok(etag) = HeaderValue::from_str(&format!("\"{version}\"")) {
+            res.headers_mut().insert(ETAG, etag);
+        }
+        res
+    }
+}
@@ -1,39 +1,338 @@
-use crate::schema::ids::UserId;
-use anyhow::Result;
-use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
-use serde::{Deserialize, Serialize};
-use std::{env, sync::LazyLock};
-
-static SECRET: LazyLock<Vec<u8>> = LazyLock::new(|| {
-    env::var("JWT_SECRET")
-        .expect("environment variable JWT_SECRET")
-        .into()
-});
+use crate::{
+    extract::payload::Payload,
+    schema::ids::{Id, SessionId, UserId},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    errors::{Error as JwtError, ErrorKind},
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    rsa::PublicKeyComponents,
+    signature::{Ed25519KeyPair, KeyPair, RsaKeyPair},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{env, fs, path::Path, sync::OnceLock};
+
+/// Lifetime of an access token in seconds
+pub const ACCESS_TTL: i64 = 15 * 60;
+/// Lifetime of a login challenge in seconds
+pub const CHALLENGE_TTL: i64 = 5 * 60;
+
+/// A key tokens are signed and verified with
+struct Key {
+    /// Identifies the key in the header of the tokens it signed
+    kid: Option<String>,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    /// The public half of an asymmetric key, which other services may verify tokens with
+    jwk: Option<Jwk>,
+}
+
+/// The first key signs new tokens, the others are only trusted to have signed earlier ones
+static KEYS: OnceLock<Vec<Key>> = OnceLock::new();
+
+/// The `iss` and `aud` claims of tokens when `JWT_ISSUER` and `JWT_AUDIENCE` aren't set
+const DEFAULT_ISSUER: &str = "personal-page";
+
+/// Who tokens are issued by and meant for, so that tokens signed with the same keys for other
+/// services aren't accepted here
+struct Issuer {
+    iss: String,
+    aud: String,
+}
+
+static ISSUER: OnceLock<Issuer> = OnceLock::new();
+
+fn jwk(kid: &str, algorithm: KeyAlgorithm, params: AlgorithmParameters) -> Jwk {
+    Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(algorithm),
+            key_id: Some(kid.into()),
+            ..Default::default()
+        },
+        algorithm: params,
+    }
+}
+
+/// Loads an Ed25519 or RSA private key from a PEM file, identified by the file's name
+fn load_key(path: &Path) -> Result<Key> {
+    let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("key file has no name")?;
+    let pem = fs::read(path)?;
+    let der = pem::parse(&pem)?.into_contents();
+
+    let (algorithm, encoding, jwk) =
+        if let Ok(pair) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der) {
+            let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: URL_SAFE_NO_PAD.encode(pair.public_key()),
+            });
+            let encoding = EncodingKey::from_ed_pem(&pem)?;
+            (
+                Algorithm::EdDSA,
+                encoding,
+                jwk(kid, KeyAlgorithm::EdDSA, params),
+            )
+        } else {
+            let pair = RsaKeyPair::from_pkcs8(&der)
+                .or_else(|_| RsaKeyPair::from_der(&der))
+                .map_err(|err| anyhow!("neither an Ed25519 nor an RSA private key: {err}"))?;
+            let PublicKeyComponents { n, e } = PublicKeyComponents::<Vec<u8>>::from(pair.public());
+            let params = AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n: URL_SAFE_NO_PAD.encode(n),
+                e: URL_SAFE_NO_PAD.encode(e),
+            });
+            let encoding = EncodingKey::from_rsa_pem(&pem)?;
+            (
+                Algorithm::RS256,
+                encoding,
+                jwk(kid, KeyAlgorithm::RS256, params),
+            )
+        };
+
+    Ok(Key {
+        kid: Some(kid.into()),
+        algorithm,
+        encoding,
+        decoding: DecodingKey::from_jwk(&jwk)?,
+        jwk: Some(jwk),
+    })
+}
+
+fn secret_key(secret: &[u8]) -> Key {
+    Key {
+        kid: None,
+        algorithm: Algorithm::HS256,
+        encoding: EncodingKey::from_secret(secret),
+        decoding: DecodingKey::from_secret(secret),
+        jwk: None,
+    }
+}
+
+/// Loads the keys from the comma separated PEM files in `JWT_KEYS`. The first one signs new
+/// tokens, so a key can be rotated by first adding a new one to the end of the list, then moving it
+/// to the front once other services picked it up, and removing the old one once its tokens expired.
+/// Without `JWT_KEYS`, tokens are signed with the HMAC secret in `JWT_SECRET`.
+/// Tokens name `JWT_ISSUER` as their issuer and `JWT_AUDIENCE` as their audience.
+pub fn load_keys() -> Result<()> {
+    let keys = if let Ok(paths) = env::var("JWT_KEYS") {
+        paths
+            .split(',')
+            .map(|path| {
+                load_key(Path::new(path.trim())).with_context(|| format!("invalid JWT key {path}"))
+            })
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        let secret =
+            env::var("JWT_SECRET").context("environment variable JWT_KEYS or JWT_SECRET")?;
+        vec![secret_key(secret.as_bytes())]
+    };
+
+    if keys.is_empty() {
+        bail!("environment variable JWT_KEYS lists no keys");
+    }
+    KEYS.set(keys)
+        .map_err(|_| anyhow!("JWT keys are already loaded"))?;
+
+    let var = |name| env::var(name).unwrap_or_else(|_| DEFAULT_ISSUER.into());
+    let issuer = Issuer {
+        iss: var("JWT_ISSUER"),
+        aud: var("JWT_AUDIENCE"),
+    };
+    ISSUER
+        .set(issuer)
+        .map_err(|_| anyhow!("JWT issuer is already loaded"))
+}
+
+/// Signs the tokens of tests with a fixed secret
+#[cfg(test)]
+pub fn load_test_keys() {
+    KEYS.get_or_init(|| vec![secret_key(b"test")]);
+    ISSUER.get_or_init(|| Issuer {
+        iss: DEFAULT_ISSUER.into(),
+        aud: DEFAULT_ISSUER.into(),
+    });
+}
+
+fn keys() -> &'static [Key] {
+    KEYS.get().expect("JWT keys are loaded on startup")
+}
+
+fn issuer() -> &'static Issuer {
+    ISSUER.get().expect("JWT issuer is loaded on startup")
+}
+
+/// The public keys tokens may be signed with, as a JSON Web Key Set
+pub async fn jwks() -> Payload<JwkSet> {
+    Payload(JwkSet {
+        keys: keys().iter().filter_map(|key| key.jwk.clone()).collect(),
+    })
+}
+
+/// Current unix time in seconds
+pub fn now() -> i64 {
+    jsonwebtoken::get_current_timestamp() as i64
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Claim {
+    pub iss: String,
+    pub aud: String,
     pub sub: UserId,
+    pub sid: SessionId,
+    pub iat: i64,
+    pub exp: i64,
+    pub jti: Id,
 }
 
 impl Claim {
+    pub fn new(id: UserId, session: SessionId) -> Self {
+        let iat = now();
+        Self {
+            iss: issuer().iss.clone(),
+            aud: issuer().aud.clone(),
+            sub: id,
+            sid: session,
+            iat,
+            exp: iat + ACCESS_TTL,
+            jti: Id::default(),
+        }
+    }
+
+    pub fn decode(token: &str) -> Result<Self, JwtError> {
+        decode(token)
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        encode(self)
+    }
+}
+
+/// Proves that a user got their password right, to be exchanged for tokens along with a one-time
+/// code. It has no session, so it is refused wherever a [`Claim`] is expected.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Challenge {
+    pub iss: String,
+    pub aud: String,
+    pub sub: UserId,
+    pub exp: i64,
+    pub jti: Id,
+    /// Tells challenges apart from access tokens, which have no such claim
+    pub challenge: bool,
+}
+
+impl Challenge {
     pub fn new(id: UserId) -> Self {
-        Self { sub: id }
+        Self {
+            iss: issuer().iss.clone(),
+            aud: issuer().aud.clone(),
+            sub: id,
+            exp: now() + CHALLENGE_TTL,
+            jti: Id::default(),
+            challenge: true,
+        }
     }
 
-    pub fn decode(token: &str) -> Result<Self> {
-        let mut validation = Validation::default();
-        validation.required_spec_claims = Default::default();
-        validation.validate_exp = false; // bad practice!
-        let decoded = jsonwebtoken::decode(token, &DecodingKey::from_secret(&SECRET), &validation)?;
-        Ok(decoded.claims)
+    pub fn decode(token: &str) -> Result<Self, JwtError> {
+        let challenge = decode::<Self>(token)?;
+        if challenge.challenge {
+            Ok(challenge)
+        } else {
+            Err(ErrorKind::InvalidToken.into())
+        }
     }
 
     pub fn encode(&self) -> Result<String> {
-        let encoded = jsonwebtoken::encode(
-            &Default::default(),
-            self,
-            &EncodingKey::from_secret(&SECRET),
-        )?;
-        Ok(encoded)
+        encode(self)
+    }
+}
+
+fn decode<T: DeserializeOwned>(token: &str) -> Result<T, JwtError> {
+    let kid = jsonwebtoken::decode_header(token)?.kid;
+    let key = keys()
+        .iter()
+        .find(|key| key.kid == kid)
+        .ok_or(ErrorKind::InvalidToken)?;
+
+    let Issuer { iss, aud } = issuer();
+    let mut validation = Validation::new(key.algorithm);
+    validation.set_issuer(&[iss]);
+    validation.set_audience(&[aud]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+
+    let decoded = jsonwebtoken::decode(token, &key.decoding, &validation)?;
+    Ok(decoded.claims)
+}
+
+fn encode<T: Serialize>(claims: &T) -> Result<String> {
+    let key = &keys()[0];
+    let header = Header {
+        kid: key.kid.clone(),
+        ..Header::new(key.algorithm)
+    };
+
+    Ok(jsonwebtoken::encode(&header, claims, &key.encoding)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn token(iss: Option<&str>, aud: Option<&str>) -> String {
+        load_test_keys();
+        let mut claims = json!({
+            "sub": UserId::default(),
+            "sid": SessionId::default(),
+            "iat": now(),
+            "exp": now() + ACCESS_TTL,
+            "jti": Id::default(),
+        });
+        if let Some(iss) = iss {
+            claims["iss"] = Value::from(iss);
+        }
+        if let Some(aud) = aud {
+            claims["aud"] = Value::from(aud);
+        }
+        encode(&claims).unwrap()
+    }
+
+    #[test]
+    fn names_the_issuer_and_audience() {
+        load_test_keys();
+        let claim = Claim::new(UserId::default(), SessionId::default());
+        let decoded = Claim::decode(&claim.encode().unwrap()).unwrap();
+        assert_eq!(decoded.iss, DEFAULT_ISSUER);
+        assert_eq!(decoded.aud, DEFAULT_ISSUER);
+
+        let challenge = Challenge::new(UserId::default()).encode().unwrap();
+        assert!(Challenge::decode(&challenge).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_issuers_and_audiences() {
+        let valid = Some(DEFAULT_ISSUER);
+        assert!(Claim::decode(&token(valid, valid)).is_ok());
+
+        for (iss, aud) in [
+            (Some("other"), valid),
+            (valid, Some("other")),
+            (None, valid),
+            (valid, None),
+        ] {
+            assert!(Claim::decode(&token(iss, aud)).is_err(), "{iss:?} {aud:?}");
+        }
     }
 }
@@ -0,0 +1,416 @@
+use crate::{
+    jwt,
+    schema::api::{ApiError, ErrorCode},
+};
+use anyhow::Result;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Request},
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use sqlx::{query, SqlitePool};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex, PoisonError},
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+use tracing::error;
+
+/// Buffered request bodies are at most this long, auth payloads are tiny
+const MAX_BODY_LEN: usize = 16 * 1024;
+/// Buckets are only pruned once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+struct Limits {
+    /// Requests that can be made in a burst
+    capacity: f64,
+    /// Seconds it takes to regain a request
+    refill: f64,
+    /// Consecutive failed attempts before a lockout
+    max_failures: i64,
+    /// Seconds a lockout lasts
+    lockout: i64,
+}
+
+const IP_LIMITS: Limits = Limits {
+    capacity: 20.0,
+    refill: 3.0,
+    max_failures: 50,
+    lockout: 15 * 60,
+};
+
+const USERNAME_LIMITS: Limits = Limits {
+    capacity: 5.0,
+    refill: 12.0,
+    max_failures: 5,
+    lockout: 15 * 60,
+};
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Key {
+    Ip(IpAddr),
+    Username(String),
+}
+
+impl Key {
+    fn limits(&self) -> &'static Limits {
+        match self {
+            Self::Ip(_) => &IP_LIMITS,
+            Self::Username(_) => &USERNAME_LIMITS,
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self {
+            Self::Ip(ip) => format!("ip:{ip}"),
+            Self::Username(username) => format!("user:{username}"),
+        }
+    }
+
+    fn decode(key: &str) -> Option<Self> {
+        match key.split_once(':')? {
+            ("ip", ip) => ip.parse().ok().map(Self::Ip),
+            ("user", username) => Some(Self::Username(username.into())),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: i64,
+    failures: i64,
+    locked_until: Option<i64>,
+}
+
+impl Bucket {
+    fn new(limits: &Limits, now: i64) -> Self {
+        Self {
+            tokens: limits.capacity,
+            updated_at: now,
+            failures: 0,
+            locked_until: None,
+        }
+    }
+
+    fn refill(&mut self, limits: &Limits, now: i64) {
+        let elapsed = (now - self.updated_at).max(0);
+        self.tokens = (self.tokens + elapsed as f64 / limits.refill).min(limits.capacity);
+        self.updated_at = now;
+
+        // Failures only count as consecutive while they are less than a lockout apart
+        if elapsed >= limits.lockout {
+            self.failures = 0;
+        }
+
+        if self.locked_until.is_some_and(|until| until <= now) {
+            self.locked_until = None;
+        }
+    }
+
+    /// Seconds until the next request is allowed, if it isn't right now
+    fn retry_after(&self, limits: &Limits, now: i64) -> Option<i64> {
+        if let Some(until) = self.locked_until {
+            Some(until - now)
+        } else if self.tokens < 1.0 {
+            Some(((1.0 - self.tokens) * limits.refill).ceil() as i64)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the bucket is in its initial state and may be forgotten
+    fn idle(&self, limits: &Limits) -> bool {
+        self.tokens >= limits.capacity && self.failures == 0 && self.locked_until.is_none()
+    }
+}
+
+/// Token buckets per client IP and per attempted username, guarding endpoints against password
+/// guessing. Consecutive failures lock a key out for a while. With a pool, the buckets survive
+/// restarts.
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    pool: Option<SqlitePool>,
+}
+
+impl RateLimiter {
+    pub async fn new(pool: Option<SqlitePool>) -> Result<Self> {
+        let mut buckets = HashMap::new();
+
+        if let Some(pool) = &pool {
+            let cutoff = jwt::now() - IP_LIMITS.lockout.max(USERNAME_LIMITS.lockout);
+            query!(
+                "DELETE FROM rate_limits WHERE updated_at < ?1 AND (locked_until IS NULL OR locked_until < ?1)",
+                cutoff,
+            )
+            .execute(pool)
+            .await?;
+
+            let rows =
+                query!("SELECT key, tokens, updated_at, failures, locked_until FROM rate_limits")
+                    .fetch_all(pool)
+                    .await?;
+
+            for row in rows {
+                if let Some(key) = Key::decode(&row.key) {
+                    let bucket = Bucket {
+                        tokens: row.tokens,
+                        updated_at: row.updated_at,
+                        failures: row.failures,
+                        locked_until: row.locked_until,
+                    };
+                    buckets.insert(key, bucket);
+                }
+            }
+        }
+
+        Ok(Self {
+            buckets: Mutex::new(buckets),
+            pool,
+        })
+    }
+
+    /// Takes a request from the bucket of each key, or returns the seconds to wait if any is empty
+    fn acquire(&self, keys: &[Key]) -> Result<(), i64> {
+        let now = jwt::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if buckets.len() > MAX_BUCKETS {
+            buckets.retain(|key, bucket| {
+                bucket.refill(key.limits(), now);
+                !bucket.idle(key.limits())
+            });
+        }
+
+        let mut retry_after = None;
+        for key in keys {
+            let limits = key.limits();
+            let bucket = buckets
+                .entry(key.clone())
+                .or_insert_with(|| Bucket::new(limits, now));
+
+            bucket.refill(limits, now);
+            retry_after = retry_after.max(bucket.retry_after(limits, now));
+        }
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after.max(1));
+        }
+
+        for key in keys {
+            if let Some(bucket) = buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a failed attempt against each key, locking out the ones with too many,
+    /// or clears their failures after a successful one
+    async fn record(&self, keys: &[Key], success: bool) {
+        let now = jwt::now();
+
+        let changed = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+
+            keys.iter()
+                .filter_map(|key| {
+                    let limits = key.limits();
+                    let bucket = buckets.get_mut(key)?;
+
+                    if success && bucket.failures == 0 {
+                        return None;
+                    } else if success {
+                        bucket.failures = 0;
+                    } else {
+                        bucket.failures += 1;
+                        if bucket.failures >= limits.max_failures {
+                            bucket.failures = 0;
+                            bucket.locked_until = Some(now + limits.lockout);
+                        }
+                    }
+                    Some((key.encode(), bucket.clone()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        if let Some(pool) = &self.pool {
+            for (key, bucket) in changed {
+                if let Err(err) = persist(pool, &key, &bucket).await {
+                    error!("Failed to persist rate limit: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+async fn persist(pool: &SqlitePool, key: &str, bucket: &Bucket) -> Result<()> {
+    query!(
+        r#"
+        INSERT INTO rate_limits (key, tokens, updated_at, failures, locked_until)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (key) DO UPDATE
+        SET tokens = ?2, updated_at = ?3, failures = ?4, locked_until = ?5
+        "#,
+        key,
+        bucket.tokens,
+        bucket.updated_at,
+        bucket.failures,
+        bucket.locked_until,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Rate limits the requests to some paths of the service it wraps.
+/// The client IP comes from the `ConnectInfo` the server was started with,
+/// the username from the `username` field of JSON bodies.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    paths: &'static [&'static str],
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter, paths: &'static [&'static str]) -> Self {
+        Self {
+            limiter: Arc::new(limiter),
+            paths,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+#[derive(Deserialize)]
+struct Attempt {
+    username: String,
+}
+
+fn too_many_requests(retry_after: i64) -> Response {
+    (
+        [(RETRY_AFTER, retry_after.to_string())],
+        ApiError::new(ErrorCode::RateLimited, "Too many attempts, try again later"),
+    )
+        .into_response()
+}
+
+impl<S> Service<Request> for RateLimit<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // The clone may not be ready, so keep the service that is
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let RateLimitLayer { limiter, paths } = self.layer.clone();
+
+        Box::pin(async move {
+            if !paths.contains(&req.uri().path()) {
+                return inner.call(req).await;
+            }
+
+            let ip = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip());
+            let (parts, body) = req.into_parts();
+            let Ok(body) = to_bytes(body, MAX_BODY_LEN).await else {
+                let error = ApiError::new(ErrorCode::PayloadTooLarge, "Request body is too long");
+                return Ok(error.into_response());
+            };
+            let username = serde_json::from_slice::<Attempt>(&body)
+                .ok()
+                .map(|attempt| attempt.username);
+
+            let keys = ip
+                .map(Key::Ip)
+                .into_iter()
+                .chain(username.map(Key::Username))
+                .collect::<Vec<_>>();
+            if let Err(retry_after) = limiter.acquire(&keys) {
+                return Ok(too_many_requests(retry_after));
+            }
+
+            let res = inner
+                .call(Request::from_parts(parts, Body::from(body)))
+                .await?;
+
+            let status = res.status();
+            if status.is_success() {
+                limiter.record(&keys, true).await;
+            } else if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+                limiter.record(&keys, false).await;
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Ipv4Addr, panic, thread};
+
+    #[tokio::test]
+    async fn locks_out_after_failures() {
+        let limiter = RateLimiter::new(None).await.unwrap();
+        let keys = [Key::Username("alice".into())];
+
+        for _ in 0..USERNAME_LIMITS.max_failures {
+            assert!(limiter.acquire(&keys).is_ok());
+            limiter.record(&keys, false).await;
+        }
+        let retry_after = limiter.acquire(&keys).unwrap_err();
+        assert!(retry_after > USERNAME_LIMITS.lockout - 5);
+    }
+
+    #[tokio::test]
+    async fn survives_a_poisoned_lock() {
+        let limiter = Arc::new(RateLimiter::new(None).await.unwrap());
+        let poisoner = limiter.clone();
+        let _ = thread::spawn(move || {
+            let _buckets = poisoner.buckets.lock();
+            panic::panic_any("poisoned");
+        })
+        .join();
+        assert!(limiter.buckets.is_poisoned());
+
+        let keys = [Key::Ip(Ipv4Addr::LOCALHOST.into())];
+        assert!(limiter.acquire(&keys).is_ok());
+        limiter.record(&keys, false).await;
+    }
+}
@@ -1,15 +1,35 @@
 mod api;
+mod cards;
+mod cli;
+mod codegen;
+mod error;
+mod events;
 mod extract;
 mod jwt;
+mod limit;
+mod oidc;
+mod openapi;
+mod password;
 mod recompiler;
+mod reset;
 mod schema;
+mod session;
+mod state;
+#[cfg(test)]
+mod testing;
+mod totp;
+mod validate;
 
 use anyhow::Result;
-use axum::Router;
+use axum::{middleware, routing::get, Router};
 use clap::Parser;
+use cli::Command;
+use limit::{RateLimitLayer, RateLimiter};
+use oidc::Oidc;
 use recompiler::Recompiler;
 use sqlx::{migrate, SqlitePool};
-use std::{env, path::PathBuf};
+use state::AppState;
+use std::{env, net::SocketAddr, path::PathBuf};
 use tokio::{net::TcpListener, signal};
 use tower_http::{
     services::{ServeDir, ServeFile},
@@ -27,30 +47,61 @@ struct Args {
     /// Enable watch mode
     #[arg(long)]
     watch: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenvy::dotenv()?;
 
-    let Args { watch } = Args::parse();
-    let _recompiler = watch.then(Recompiler::start).transpose()?;
+    let Args { watch, command } = Args::parse();
 
     let pool = SqlitePool::connect(&env::var("DATABASE_URL")?).await?;
     migrate!().run(&pool).await?;
+    password::load_config()?;
+
+    if let Some(command) = command {
+        return command.run(&pool).await;
+    }
+
+    jwt::load_keys()?;
+    let oidc = Oidc::from_env()?;
+    let _recompiler = watch.then(Recompiler::start).transpose()?;
+
+    let persist_limits = env::var_os("RATE_LIMIT_PERSIST").is_some();
+    let limiter = RateLimiter::new(persist_limits.then(|| pool.clone())).await?;
 
     let dist = PathBuf::from(env::var("DIST")?);
     let index = dist.join(INDEX);
 
-    let routes = Router::new()
-        .nest_service("/", ServeDir::new(dist).fallback(ServeFile::new(index)))
-        .nest("/api", api::routes(pool).layer(TraceLayer::new_for_http()));
+    let state = AppState::new(pool, oidc);
+    // shared by every version, so that switching between them doesn't reset the limits
+    let limit = RateLimitLayer::new(limiter, api::CREDENTIAL_ROUTES);
+    let api_routes = |version| {
+        api::routes(state.clone(), version)
+            .layer(limit.clone())
+            .layer(TraceLayer::new_for_http())
+    };
+
+    let routes = api::VERSIONS.into_iter().fold(
+        Router::new()
+            .route("/.well-known/jwks.json", get(jwt::jwks))
+            .nest_service("/", ServeDir::new(dist).fallback(ServeFile::new(index)))
+            .nest(
+                api::BASE,
+                api_routes(api::UNVERSIONED).layer(middleware::map_response(api::deprecated)),
+            ),
+        |routes, version| routes.nest(&api::base(version), api_routes(version)),
+    );
     Registry::default().with(fmt::layer()).init();
 
     let addr = format!("{IP}:{}", env::var("PORT")?);
     info!("running server on {addr}");
 
-    axum::serve(TcpListener::bind(addr).await?, routes)
+    let app = routes.into_make_service_with_connect_info::<SocketAddr>();
+    axum::serve(TcpListener::bind(addr).await?, app)
         .with_graceful_shutdown(async {
             signal::ctrl_c().await.expect("failed to listen for ctrl-c");
         })
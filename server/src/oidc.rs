@@ -0,0 +1,528 @@
+//! Logging in with an external OpenID Connect provider, through the authorization code flow with
+//! PKCE. The client sends users to the provider, which redirects them back to the client with a
+//! code, which the client passes on to the server to exchange for an ID token.
+
+use crate::{jwt, schema::ids::UserId};
+use anyhow::{anyhow, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::{distributions::Alphanumeric, prelude::*};
+use reqwest::{Client as HttpClient, Url};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::{
+    error::{Error as SqlxError, ErrorKind},
+    query, query_scalar, Connection, SqliteConnection, SqlitePool,
+};
+use std::{env, sync::Arc};
+use tokio::sync::RwLock;
+
+/// Seconds users have to log in with the provider
+pub const LOGIN_TTL: i64 = 10 * 60;
+/// Length of the state, nonce and PKCE verifier, which must be 43 to 128 characters
+const RANDOM_LEN: usize = 43;
+/// Asymmetric ID token algorithms, symmetric ones would be signed with the client secret
+const ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+const DEFAULT_SCOPES: &str = "openid profile";
+/// Attempts at finding a free username for a new user
+const USERNAME_ATTEMPTS: usize = 10;
+
+struct Config {
+    issuer: String,
+    client_id: String,
+    /// Only confidential clients have a secret, PKCE protects the others
+    client_secret: Option<String>,
+    /// The client page the provider redirects back to
+    redirect_uri: String,
+    scopes: String,
+}
+
+/// The endpoints and keys of the provider, from its discovery document
+struct Provider {
+    authorization_endpoint: Url,
+    token_endpoint: String,
+    jwks_uri: String,
+    jwks: JwkSet,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+struct IdClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+}
+
+/// A user of the provider, as vouched for by an ID token
+pub struct Identity {
+    pub subject: String,
+    pub preferred_username: Option<String>,
+}
+
+/// A login started with the provider
+pub struct Authorization {
+    /// The provider's login page to send the user to
+    pub url: Url,
+    /// Identifies the login when the provider redirects back
+    pub state: String,
+}
+
+/// A completed login with the provider
+pub struct Login {
+    /// The user who started the login to link the identity to their account, if any
+    pub user: Option<UserId>,
+    pub identity: Identity,
+}
+
+/// The identity provider, unless OIDC login is disabled
+#[derive(Clone, Default)]
+pub struct Oidc(Option<Arc<Client>>);
+
+impl Oidc {
+    /// Configures the provider from `OIDC_ISSUER`, `OIDC_CLIENT_ID`, `OIDC_REDIRECT_URI`,
+    /// and optionally `OIDC_CLIENT_SECRET` and `OIDC_SCOPES`. Without an issuer, OIDC login is
+    /// disabled. The provider itself is only discovered once someone logs in.
+    pub fn from_env() -> Result<Self> {
+        let Ok(issuer) = env::var("OIDC_ISSUER") else {
+            return Ok(Self(None));
+        };
+
+        let config = Config {
+            issuer,
+            client_id: env::var("OIDC_CLIENT_ID").context("environment variable OIDC_CLIENT_ID")?,
+            client_secret: env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_uri: env::var("OIDC_REDIRECT_URI")
+                .context("environment variable OIDC_REDIRECT_URI")?,
+            scopes: env::var("OIDC_SCOPES").unwrap_or_else(|_| DEFAULT_SCOPES.into()),
+        };
+
+        Ok(Self::new(config))
+    }
+
+    /// The provider at `issuer` as the mock provider of the examples expects its client,
+    /// redirecting back to `redirect_uri`
+    #[cfg(test)]
+    pub fn mock(issuer: String, redirect_uri: &str) -> Self {
+        Self::new(Config {
+            issuer,
+            client_id: "personal-page".into(),
+            client_secret: None,
+            redirect_uri: redirect_uri.into(),
+            scopes: DEFAULT_SCOPES.into(),
+        })
+    }
+
+    fn new(config: Config) -> Self {
+        Self(Some(Arc::new(Client {
+            config,
+            http: HttpClient::new(),
+            provider: RwLock::default(),
+        })))
+    }
+
+    pub fn client(&self) -> Option<&Client> {
+        self.0.as_deref()
+    }
+}
+
+pub struct Client {
+    config: Config,
+    http: HttpClient,
+    provider: RwLock<Option<Arc<Provider>>>,
+}
+
+fn random() -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(RANDOM_LEN)
+        .map(char::from)
+        .collect()
+}
+
+impl Client {
+    async fn discover(&self) -> Result<Provider> {
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/'),
+        );
+        let discovery = self
+            .http
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Discovery>()
+            .await?;
+
+        ensure!(
+            discovery.issuer == self.config.issuer,
+            "discovered issuer {} doesn't match the configured one",
+            discovery.issuer,
+        );
+
+        Ok(Provider {
+            authorization_endpoint: discovery.authorization_endpoint.parse()?,
+            token_endpoint: discovery.token_endpoint,
+            jwks: self.fetch_jwks(&discovery.jwks_uri).await?,
+            jwks_uri: discovery.jwks_uri,
+        })
+    }
+
+    async fn fetch_jwks(&self, jwks_uri: &str) -> Result<JwkSet> {
+        Ok(self
+            .http
+            .get(jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn provider(&self) -> Result<Arc<Provider>> {
+        if let Some(provider) = &*self.provider.read().await {
+            return Ok(provider.clone());
+        }
+
+        let mut cached = self.provider.write().await;
+        if let Some(provider) = &*cached {
+            return Ok(provider.clone());
+        }
+        let provider = Arc::new(self.discover().await?);
+        *cached = Some(provider.clone());
+
+        Ok(provider)
+    }
+
+    /// Refetches the provider's keys, e.g. after it rotated them
+    async fn refresh_jwks(&self, provider: &Provider) -> Result<Arc<Provider>> {
+        let jwks = self.fetch_jwks(&provider.jwks_uri).await?;
+        let provider = Arc::new(Provider {
+            authorization_endpoint: provider.authorization_endpoint.clone(),
+            token_endpoint: provider.token_endpoint.clone(),
+            jwks_uri: provider.jwks_uri.clone(),
+            jwks,
+        });
+        *self.provider.write().await = Some(provider.clone());
+
+        Ok(provider)
+    }
+
+    /// Starts a login, returning the URL of the provider's login page to send the user to.
+    /// Logins started by a user link the identity to their account instead.
+    pub async fn authorize(
+        &self,
+        pool: &SqlitePool,
+        user: Option<UserId>,
+    ) -> Result<Authorization> {
+        let provider = self.provider().await?;
+        let (state, nonce, verifier) = (random(), random(), random());
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        let now = jwt::now();
+        let expires_at = now + LOGIN_TTL;
+
+        query!("DELETE FROM oidc_logins WHERE expires_at <= ?", now)
+            .execute(pool)
+            .await?;
+        query!(
+            "INSERT INTO oidc_logins (state, nonce, verifier, user_id, expires_at) VALUES (?, ?, ?, ?, ?)",
+            state,
+            nonce,
+            verifier,
+            user,
+            expires_at,
+        )
+        .execute(pool)
+        .await?;
+
+        let mut url = provider.authorization_endpoint.clone();
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_uri)
+            .append_pair("scope", &self.config.scopes)
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(Authorization { url, state })
+    }
+
+    /// Completes a login with the code the provider redirected back with,
+    /// returning `None` if the login is unknown or expired
+    pub async fn callback(
+        &self,
+        pool: &SqlitePool,
+        code: &str,
+        state: &str,
+    ) -> Result<Option<Login>> {
+        let now = jwt::now();
+        let Some(login) = query!(
+            r#"
+            DELETE FROM oidc_logins WHERE state = ? AND expires_at > ?
+            RETURNING nonce, verifier, user_id as "user_id: UserId"
+            "#,
+            state,
+            now,
+        )
+        .fetch_optional(pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let provider = self.provider().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_uri),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let TokenResponse { id_token } = self
+            .http
+            .post(&provider.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let claims = self.validate(provider, &id_token).await?;
+        ensure!(
+            claims.nonce.as_deref() == Some(&login.nonce),
+            "ID token nonce doesn't match"
+        );
+
+        Ok(Some(Login {
+            user: login.user_id,
+            identity: Identity {
+                subject: claims.sub,
+                preferred_username: claims.preferred_username,
+            },
+        }))
+    }
+
+    async fn validate(&self, provider: Arc<Provider>, id_token: &str) -> Result<IdClaims> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        ensure!(
+            ALGORITHMS.contains(&header.alg),
+            "unsupported ID token algorithm {:?}",
+            header.alg,
+        );
+
+        let find = |jwks: &JwkSet| match &header.kid {
+            Some(kid) => jwks.find(kid).cloned(),
+            None => jwks.keys.first().cloned(),
+        };
+        let jwk = match find(&provider.jwks) {
+            Some(jwk) => jwk,
+            None => find(&self.refresh_jwks(&provider).await?.jwks)
+                .ok_or_else(|| anyhow!("unknown ID token key {:?}", header.kid))?,
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let decoded = jsonwebtoken::decode(id_token, &DecodingKey::from_jwk(&jwk)?, &validation)?;
+        Ok(decoded.claims)
+    }
+
+    /// Finds the user an identity is linked to, or signs up a new one for it
+    pub async fn user(&self, pool: &SqlitePool, identity: &Identity) -> Result<UserId> {
+        let issuer = &self.config.issuer;
+        let linked = query_scalar!(
+            r#"SELECT user_id as "user_id: UserId" FROM identities WHERE issuer = ? AND subject = ?"#,
+            issuer,
+            identity.subject,
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(user) = linked {
+            return Ok(user);
+        }
+
+        for username in usernames(identity.preferred_username.as_deref()).take(USERNAME_ATTEMPTS) {
+            let user = UserId::default();
+            let (issuer, subject) = (issuer.clone(), identity.subject.clone());
+            let mut conn = pool.acquire().await?;
+
+            let res = conn
+                .transaction(move |transact| {
+                    Box::pin(async move {
+                        // Users who only log in through the provider have no password
+                        query!(
+                            "INSERT INTO users (id, username, password_hash) VALUES (?, ?, '')",
+                            user,
+                            username,
+                        )
+                        .execute(&mut **transact)
+                        .await?;
+
+                        self::link(transact, &issuer, &subject, user).await
+                    })
+                })
+                .await;
+
+            match res {
+                Ok(()) => return Ok(user),
+                Err(SqlxError::Database(err)) if err.kind() == ErrorKind::UniqueViolation => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        Err(anyhow!(
+            "no free username for identity {}",
+            identity.subject
+        ))
+    }
+
+    /// Links an identity to an existing user, returning false if it belongs to someone else
+    pub async fn link(&self, pool: &SqlitePool, identity: &Identity, user: UserId) -> Result<bool> {
+        let mut conn = pool.acquire().await?;
+
+        match link(&mut conn, &self.config.issuer, &identity.subject, user).await {
+            Ok(()) => Ok(true),
+            Err(SqlxError::Database(err)) if err.kind() == ErrorKind::UniqueViolation => {
+                let owner = query_scalar!(
+                    r#"SELECT user_id as "user_id: UserId" FROM identities WHERE issuer = ? AND subject = ?"#,
+                    self.config.issuer,
+                    identity.subject,
+                )
+                .fetch_one(pool)
+                .await?;
+                Ok(owner == user)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+async fn link(
+    conn: &mut SqliteConnection,
+    issuer: &str,
+    subject: &str,
+    user: UserId,
+) -> Result<(), SqlxError> {
+    let now = jwt::now();
+    query!(
+        "INSERT INTO identities (issuer, subject, user_id, created_at) VALUES (?, ?, ?, ?)",
+        issuer,
+        subject,
+        user,
+        now,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Usernames to try for a new user: the one they go by at the provider, made valid,
+/// then variations with random numbers in case it's taken
+fn usernames(preferred: Option<&str>) -> impl Iterator<Item = String> {
+    let mut base = preferred
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_')
+        .take(16)
+        .collect::<String>();
+    if base.len() < 4 {
+        base = "user".into();
+    }
+
+    let prefix = base.chars().take(12).collect::<String>();
+    std::iter::once(base).chain(std::iter::repeat_with(move || {
+        format!("{prefix}{:04}", thread_rng().gen_range(0..10_000))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, TestApp};
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn logs_in_with_the_provider() {
+        let app = TestApp::with_mock_idp().await;
+
+        let token = app.oidc_login("carol").await;
+        let user = testing::user(&token);
+        let row = query!(
+            "SELECT username, password_hash FROM users WHERE id = ?",
+            user
+        )
+        .fetch_one(&app.pool)
+        .await
+        .unwrap();
+        assert_eq!(row.username, "carol");
+        assert_eq!(row.password_hash, "");
+
+        // The identity is linked to the user it signed up
+        let token = app.oidc_login("carol").await;
+        assert_eq!(testing::user(&token), user);
+        let token = app.oidc_login("dave").await;
+        assert_ne!(testing::user(&token), user);
+    }
+
+    #[tokio::test]
+    async fn completes_logins_once() {
+        let app = TestApp::with_mock_idp().await;
+
+        let (callback, cookie) = app.oidc_authorize("carol").await;
+        let (status, res) = app.oidc_callback(callback.clone(), Some(&cookie)).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+
+        let (status, res) = app.oidc_callback(callback, Some(&cookie)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{res}");
+        assert_eq!(res["code"], "loginExpired");
+    }
+
+    #[tokio::test]
+    async fn completes_logins_in_the_browser_that_started_them() {
+        let app = TestApp::with_mock_idp().await;
+
+        // Someone else's login can't be completed here, with or without a login of our own
+        let (callback, cookie) = app.oidc_authorize("mallory").await;
+        let (_, other_cookie) = app.oidc_authorize("carol").await;
+        for cookie in [None, Some(other_cookie.as_str())] {
+            let (status, res) = app.oidc_callback(callback.clone(), cookie).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{res}");
+            assert_eq!(res["code"], "loginExpired");
+        }
+
+        let (status, res) = app.oidc_callback(callback, Some(&cookie)).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+    }
+}
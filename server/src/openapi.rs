@@ -0,0 +1,375 @@
+//! An OpenAPI 3.1 description of the API, put together from the extractors and return types of
+//! each handler, with the JSON schemas of [`api`] types as components
+
+use crate::{
+    extract::{
+        cookie::{AuthMode, Authenticated, ClearCookies, OidcStarted, ACCESS_COOKIE},
+        device::Device,
+        params::{Path, Query},
+        payload::Payload,
+        user::{RequireRole, Session, User},
+        version::{IfMatch, Versioned},
+    },
+    schema::api::{self, ApiError},
+};
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header::CONTENT_TYPE, HeaderMap, Method},
+    response::{sse::Sse, Html},
+    routing::get,
+    Router,
+};
+use schemars::{generate::SchemaSettings, JsonSchema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+use std::future::Future;
+use tower_http::services::ServeFile;
+
+/// The docs page, which renders the document with Scalar
+const DOCS: &str = include_str!("../docs.html");
+
+/// Scalar's bundle, installed with the client's dependencies rather than loaded from a CDN
+const SCALAR: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../client/node_modules/@scalar/api-reference/dist/browser/standalone.js",
+);
+
+/// An operation as it is being described
+#[derive(Default)]
+pub struct Operation {
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+    responses: Map<String, Value>,
+    /// Whether the operation needs an access token
+    authenticated: bool,
+    /// The schema of the parameters in the path, which all have the same type
+    path: Option<Value>,
+}
+
+impl Operation {
+    fn response(&mut self, status: &str, response: Value) {
+        self.responses.insert(status.into(), response);
+    }
+}
+
+/// What an extractor takes from a request
+pub trait DescribeArg {
+    fn describe(_operation: &mut Operation, _gen: &mut SchemaGenerator) {}
+}
+
+/// What a handler responds with
+pub trait DescribeResponse {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator);
+}
+
+/// A handler, described by its arguments and output like axum's `Handler` is implemented
+pub trait Describe<T> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator);
+}
+
+macro_rules! describe_handler {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg,)*> Describe<($($arg,)*)> for F
+        where
+            F: FnOnce($($arg),*) -> Fut,
+            Fut: Future,
+            Fut::Output: DescribeResponse,
+            $($arg: DescribeArg,)*
+        {
+            fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+                $($arg::describe(operation, gen);)*
+                Fut::Output::describe(operation, gen);
+            }
+        }
+    };
+}
+
+describe_handler!();
+describe_handler!(T1);
+describe_handler!(T1, T2);
+describe_handler!(T1, T2, T3);
+describe_handler!(T1, T2, T3, T4);
+describe_handler!(T1, T2, T3, T4, T5);
+describe_handler!(T1, T2, T3, T4, T5, T6);
+describe_handler!(T1, T2, T3, T4, T5, T6, T7);
+describe_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+impl<T> DescribeArg for State<T> {}
+impl DescribeArg for Device {}
+impl DescribeArg for AuthMode {}
+impl DescribeArg for Method {}
+impl DescribeArg for HeaderMap {}
+
+impl DescribeArg for Session {
+    fn describe(operation: &mut Operation, _: &mut SchemaGenerator) {
+        operation.authenticated = true;
+    }
+}
+
+impl DescribeArg for User {
+    fn describe(operation: &mut Operation, _: &mut SchemaGenerator) {
+        operation.authenticated = true;
+    }
+}
+
+impl<R> DescribeArg for RequireRole<R> {
+    fn describe(operation: &mut Operation, _: &mut SchemaGenerator) {
+        operation.authenticated = true;
+    }
+}
+
+impl DescribeArg for IfMatch {
+    fn describe(operation: &mut Operation, _: &mut SchemaGenerator) {
+        operation.parameters.push(json!({
+            "name": "If-Match",
+            "in": "header",
+            "required": true,
+            "description": "The ETag of the layout the write is based on",
+            "schema": { "type": "string" },
+        }));
+    }
+}
+
+impl<T: JsonSchema> DescribeArg for Path<T> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        operation.path = Some(gen.subschema_for::<T>().to_value());
+    }
+}
+
+impl<T: JsonSchema> DescribeArg for Query<T> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        let schema = T::json_schema(gen);
+        let required = schema.get("required").and_then(Value::as_array);
+        let Some(properties) = schema.get("properties").and_then(Value::as_object) else {
+            return;
+        };
+
+        for (name, property) in properties {
+            let required = required.is_some_and(|required| required.contains(&json!(name)));
+            operation.parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": required,
+                "schema": property,
+            }));
+        }
+    }
+}
+
+impl<T: JsonSchema> DescribeArg for Payload<T> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        operation.request_body = Some(json!({
+            "required": true,
+            "content": {
+                "application/json": { "schema": gen.subschema_for::<T>() },
+            },
+        }));
+    }
+}
+
+impl<R: DescribeResponse> DescribeResponse for Result<R, ApiError> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        R::describe(operation, gen);
+        operation.response(
+            "default",
+            json!({
+                "description": "Error",
+                "content": {
+                    "application/json": { "schema": gen.subschema_for::<ApiError>() },
+                },
+            }),
+        );
+    }
+}
+
+impl DescribeResponse for () {
+    fn describe(operation: &mut Operation, _: &mut SchemaGenerator) {
+        operation.response("200", json!({ "description": "Success" }));
+    }
+}
+
+impl<T: JsonSchema> DescribeResponse for Payload<T> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        operation.response(
+            "200",
+            json!({
+                "description": "Success",
+                "content": {
+                    "application/json": { "schema": gen.subschema_for::<T>() },
+                },
+            }),
+        );
+    }
+}
+
+impl<R: DescribeResponse> DescribeResponse for Versioned<R> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        R::describe(operation, gen);
+        if let Some(Value::Object(response)) = operation.responses.get_mut("200") {
+            response.insert(
+                "headers".into(),
+                json!({
+                    "ETag": {
+                        "description": "The layout version, to send back in If-Match",
+                        "schema": { "type": "string" },
+                    },
+                }),
+            );
+        }
+    }
+}
+
+impl DescribeResponse for Authenticated {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        <Payload<api::Login> as DescribeResponse>::describe(operation, gen);
+    }
+}
+
+impl DescribeResponse for OidcStarted {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        <Payload<api::OidcRedirect> as DescribeResponse>::describe(operation, gen);
+    }
+}
+
+impl DescribeResponse for ClearCookies {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        <()>::describe(operation, gen);
+    }
+}
+
+impl<S> DescribeResponse for Sse<S> {
+    fn describe(operation: &mut Operation, gen: &mut SchemaGenerator) {
+        operation.response(
+            "200",
+            json!({
+                "description": "A stream of events",
+                "content": {
+                    "text/event-stream": { "schema": gen.subschema_for::<api::Event>() },
+                },
+            }),
+        );
+    }
+}
+
+/// The operation ID for a route, e.g. `postAdminUsersIdResetCode` for
+/// `post /admin/users/:id/reset-code`
+fn operation_id(method: &str, path: &str) -> String {
+    path.split(['/', '-', ':'])
+        .filter(|word| !word.is_empty())
+        .flat_map(|word| {
+            let mut chars = word.chars();
+            chars
+                .next()
+                .map(|first| first.to_ascii_uppercase())
+                .into_iter()
+                .chain(chars)
+        })
+        .fold(method.to_owned(), |mut id, c| {
+            id.push(c);
+            id
+        })
+}
+
+/// The document, built up as routes are added
+pub struct OpenApi {
+    gen: SchemaGenerator,
+    paths: Map<String, Value>,
+}
+
+impl Default for OpenApi {
+    fn default() -> Self {
+        let settings = SchemaSettings::draft2020_12().with(|settings| {
+            settings.definitions_path = "/components/schemas".into();
+            settings.meta_schema = None;
+        });
+
+        Self {
+            gen: settings.into_generator(),
+            paths: Map::new(),
+        }
+    }
+}
+
+impl OpenApi {
+    /// Describes a route by the types of its handler, with the path in axum's syntax
+    pub fn operation<H: Describe<T>, T>(&mut self, method: &str, path: &str, _: &H) {
+        let mut operation = Operation::default();
+        H::describe(&mut operation, &mut self.gen);
+
+        let mut parameters = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix(':'))
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "schema": operation.path.clone().unwrap_or(json!({ "type": "string" })),
+                })
+            })
+            .collect::<Vec<_>>();
+        parameters.append(&mut operation.parameters);
+
+        let mut description = json!({
+            "operationId": operation_id(method, path),
+            "tags": [path.split('/').find(|segment| !segment.is_empty())],
+            "parameters": parameters,
+            "responses": operation.responses,
+        });
+        if let Some(request_body) = operation.request_body {
+            description["requestBody"] = request_body;
+        }
+        if operation.authenticated {
+            description["security"] = json!([{ "bearer": [] }, { "cookie": [] }]);
+        }
+
+        let template = path
+            .split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => format!("{{{name}}}"),
+                None => segment.into(),
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let item = self.paths.entry(template).or_insert_with(|| json!({}));
+        item[method] = description;
+    }
+
+    pub fn document(mut self, base: &str) -> Value {
+        json!({
+            "openapi": "3.1.0",
+            "info": {
+                "title": "Personal Page",
+                "description": "A personal webpage for practicing web development",
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "servers": [{ "url": base }],
+            "paths": self.paths,
+            "components": {
+                "schemas": self.gen.take_definitions(true),
+                "securitySchemes": {
+                    "bearer": { "type": "http", "scheme": "bearer", "bearerFormat": "JWT" },
+                    "cookie": { "type": "apiKey", "in": "cookie", "name": ACCESS_COOKIE },
+                },
+            },
+        })
+    }
+}
+
+/// Serves the document at `openapi.json`, and in debug builds the docs page at `docs` along with
+/// the script it renders the document with
+pub fn routes<S: Clone + Send + Sync + 'static>(document: &Value) -> Router<S> {
+    let document = Bytes::from(document.to_string());
+    let router = Router::new().route(
+        "/openapi.json",
+        get(|| async move { ([(CONTENT_TYPE, "application/json")], document) }),
+    );
+
+    if cfg!(debug_assertions) {
+        router
+            .route("/docs", get(|| async { Html(DOCS) }))
+            .route_service("/docs/scalar.js", ServeFile::new(SCALAR))
+    } else {
+        router
+    }
+}
@@ -0,0 +1,393 @@
+use crate::schema::api::{FieldError, PasswordRule};
+use anyhow::{anyhow, ensure, Context, Result};
+use argon2::{
+    password_hash::{self, PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version,
+};
+use schemars::Schema;
+use serde_json::json;
+use std::{
+    collections::HashSet,
+    env, fs,
+    str::FromStr,
+    sync::{LazyLock, OnceLock},
+};
+use tokio::task;
+
+/// The common passwords rejected unless `PASSWORD_BLOCKLIST` names another list
+const COMMON_PASSWORDS: &str = include_str!("../common-passwords.txt");
+
+/// Argon2id with the cost parameters from the environment
+static ARGON2: OnceLock<Argon2<'static>> = OnceLock::new();
+
+/// A hash with the current parameters to verify passwords against when there is no user to
+/// verify them for, so that response times don't tell whether a user exists
+static DUMMY_HASH: LazyLock<Option<String>> = LazyLock::new(|| hash_blocking("").ok());
+
+/// Requirements for new passwords, configured through the environment
+struct Policy {
+    min_len: usize,
+    max_len: usize,
+    /// Number of character classes a password has to mix
+    min_classes: usize,
+    /// Minimum estimated entropy in bits
+    min_entropy: f64,
+    /// Lowercased passwords too common to allow
+    blocklist: HashSet<String>,
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+impl Policy {
+    /// What a rule asks of passwords, to tell users which ones theirs broke
+    fn message(&self, rule: &PasswordRule) -> String {
+        match rule {
+            PasswordRule::MinLength => {
+                format!("Password must be at least {} characters", self.min_len)
+            }
+            PasswordRule::MaxLength => {
+                format!("Password must be at most {} characters", self.max_len)
+            }
+            PasswordRule::CharacterClasses => format!(
+                "Password must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                self.min_classes,
+            ),
+            PasswordRule::Entropy => "Password is too easy to guess".into(),
+            PasswordRule::Blocklist => "Password is too common".into(),
+        }
+    }
+
+    /// The JSON schema of new passwords, see [`schema`]
+    fn schema(&self) -> Schema {
+        let mut schema = json!({
+            "type": "string",
+            "minLength": self.min_len,
+            "maxLength": self.max_len,
+            "errorMessage": {
+                "minLength": self.message(&PasswordRule::MinLength),
+                "maxLength": self.message(&PasswordRule::MaxLength),
+            },
+        });
+        // Any password mixes one class
+        if self.min_classes > 1 {
+            schema["pattern"] = classes_pattern(self.min_classes).into();
+            schema["errorMessage"]["pattern"] =
+                self.message(&PasswordRule::CharacterClasses).into();
+        }
+        Schema::try_from(schema).expect("the schema is an object")
+    }
+}
+
+/// Reads a setting through `var`, falling back to a default if it isn't set
+fn setting<T: FromStr>(var: &impl Fn(&str) -> Option<String>, name: &str, default: T) -> Result<T> {
+    var(name).map_or(Ok(default), |value| {
+        value
+            .parse()
+            .map_err(|_| anyhow!("environment variable {name} is invalid"))
+    })
+}
+
+/// Reads the Argon2 parameters and the password policy through `var`, falling back to the
+/// recommended parameters and the built-in blocklist
+fn load(var: impl Fn(&str) -> Option<String>) -> Result<(Argon2<'static>, Policy)> {
+    let params = Params::new(
+        setting(&var, "ARGON2_M_COST", Params::DEFAULT_M_COST)?,
+        setting(&var, "ARGON2_T_COST", Params::DEFAULT_T_COST)?,
+        setting(&var, "ARGON2_P_COST", Params::DEFAULT_P_COST)?,
+        None,
+    )
+    .map_err(|err| anyhow!("invalid Argon2 cost parameters: {err}"))?;
+
+    let blocklist = match var("PASSWORD_BLOCKLIST") {
+        Some(path) => fs::read_to_string(&path)
+            .with_context(|| format!("unreadable PASSWORD_BLOCKLIST file {path}"))?,
+        None => COMMON_PASSWORDS.into(),
+    };
+
+    let policy = Policy {
+        min_len: setting(&var, "PASSWORD_MIN_LEN", 8)?,
+        max_len: setting(&var, "PASSWORD_MAX_LEN", 128)?,
+        min_classes: setting(&var, "PASSWORD_MIN_CLASSES", 1)?,
+        min_entropy: setting(&var, "PASSWORD_MIN_ENTROPY", 35.0)?,
+        blocklist: blocklist
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_lowercase)
+            .collect(),
+    };
+    ensure!(
+        policy.min_len <= policy.max_len,
+        "PASSWORD_MIN_LEN is greater than PASSWORD_MAX_LEN",
+    );
+    ensure!(
+        policy.min_classes <= CLASSES.len(),
+        "PASSWORD_MIN_CLASSES is greater than the {} character classes",
+        CLASSES.len(),
+    );
+    ensure!(
+        policy.min_entropy.is_finite(),
+        "PASSWORD_MIN_ENTROPY is not finite",
+    );
+
+    Ok((
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params),
+        policy,
+    ))
+}
+
+/// Loads the Argon2 cost parameters from `ARGON2_M_COST`, `ARGON2_T_COST` and `ARGON2_P_COST`,
+/// and the password policy from `PASSWORD_MIN_LEN`, `PASSWORD_MAX_LEN`, `PASSWORD_MIN_CLASSES`,
+/// `PASSWORD_MIN_ENTROPY` and the file of common passwords in `PASSWORD_BLOCKLIST`
+pub fn load_config() -> Result<()> {
+    let (argon2, policy) = load(|name| env::var(name).ok())?;
+
+    ARGON2
+        .set(argon2)
+        .map_err(|_| anyhow!("Argon2 parameters are already loaded"))?;
+    POLICY
+        .set(policy)
+        .map_err(|_| anyhow!("password policy is already loaded"))
+}
+
+/// Uses the default parameters and policy in tests
+#[cfg(test)]
+pub fn load_test_config() {
+    let (argon2, policy) = load(|_| None).unwrap();
+    ARGON2.get_or_init(|| argon2);
+    POLICY.get_or_init(|| policy);
+}
+
+fn argon2() -> &'static Argon2<'static> {
+    ARGON2
+        .get()
+        .expect("Argon2 parameters are loaded on startup")
+}
+
+fn policy() -> &'static Policy {
+    POLICY.get().expect("password policy is loaded on startup")
+}
+
+type IsClass = fn(&char) -> bool;
+
+/// Character classes, along with how many characters each has to pick from.
+/// The last one catches the rest of ASCII, i.e. symbols and spaces.
+const CLASSES: [(IsClass, f64); 4] = [
+    (char::is_ascii_lowercase, 26.0),
+    (char::is_ascii_uppercase, 26.0),
+    (char::is_ascii_digit, 10.0),
+    (char::is_ascii, 33.0),
+];
+/// Characters outside of ASCII are counted as drawn from this many
+const OTHER_CLASS: f64 = 100.0;
+
+/// A character of each class as a regex, in the order [`class`] numbers them
+const CLASS_PATTERNS: [&str; CLASSES.len() + 1] = [
+    "[a-z]",
+    "[A-Z]",
+    "[0-9]",
+    r"[\x00-\x2f\x3a-\x40\x5b-\x60\x7b-\x7f]",
+    r"[^\x00-\x7f]",
+];
+
+fn class(c: char) -> usize {
+    CLASSES
+        .iter()
+        .position(|(is_class, _)| is_class(&c))
+        .unwrap_or(CLASSES.len())
+}
+
+/// A rough estimate of the bits of entropy in a password: each character adds the bits of the
+/// pool of classes the password draws from, except ones repeating or continuing the previous one
+fn entropy(password: &str) -> f64 {
+    let pool = password
+        .chars()
+        .map(class)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .map(|class| CLASSES.get(class).map_or(OTHER_CLASS, |(_, size)| *size))
+        .sum::<f64>();
+
+    password
+        .chars()
+        .zip([None].into_iter().chain(password.chars().map(Some)))
+        .map(|(c, prev)| match prev {
+            Some(prev) if (c as i64 - prev as i64).abs() <= 1 => 1.0,
+            _ => pool.log2(),
+        })
+        .sum()
+}
+
+/// Checks a new password against the policy, listing every rule it breaks
+pub fn check(password: &str) -> Result<(), Vec<FieldError>> {
+    let policy = policy();
+    let len = password.chars().count();
+    let mut violations = vec![];
+    let mut violate = |rule| {
+        let message = policy.message(&rule);
+        let rule = Some(rule);
+        violations.push(FieldError { message, rule });
+    };
+
+    if len < policy.min_len {
+        violate(PasswordRule::MinLength);
+    }
+    if len > policy.max_len {
+        violate(PasswordRule::MaxLength);
+    }
+    if password.chars().map(class).collect::<HashSet<_>>().len() < policy.min_classes {
+        violate(PasswordRule::CharacterClasses);
+    }
+    if entropy(password) < policy.min_entropy {
+        violate(PasswordRule::Entropy);
+    }
+    if policy.blocklist.contains(&password.to_lowercase()) {
+        violate(PasswordRule::Blocklist);
+    }
+
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(violations)
+    }
+}
+
+/// A regex for passwords mixing `min` character classes, with a lookahead for each class of
+/// every combination that would do
+fn classes_pattern(min: usize) -> String {
+    let combinations = (0..1u32 << CLASS_PATTERNS.len())
+        .filter(|classes| classes.count_ones() as usize == min)
+        .map(|classes| {
+            CLASS_PATTERNS
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| classes & 1 << i != 0)
+                .map(|(_, class)| format!(r"(?=[\s\S]*{class})"))
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+    format!("^(?:{})", combinations.join("|"))
+}
+
+/// The JSON schema of new passwords, with the rules of the policy clients can check as the
+/// password is typed. Entropy and the blocklist are left to [`check`].
+pub fn schema() -> Schema {
+    policy().schema()
+}
+
+pub enum Verified {
+    Invalid,
+    Valid,
+    /// The password is valid but its hash used outdated parameters, this is a new one
+    Rehashed(String),
+}
+
+fn hash_blocking(password: &str) -> Result<String, password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    Ok(argon2()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_blocking(password: &str, hash: &str) -> Result<Verified, password_hash::Error> {
+    let hash = PasswordHash::new(hash)?;
+
+    match argon2().verify_password(password.as_bytes(), &hash) {
+        Ok(()) if outdated(&hash) => hash_blocking(password).map(Verified::Rehashed),
+        Ok(()) => Ok(Verified::Valid),
+        Err(password_hash::Error::Password) => Ok(Verified::Invalid),
+        Err(err) => Err(err),
+    }
+}
+
+/// Hashes a password with a new salt into a PHC string.
+/// Hashing is deliberately slow, so it runs on the blocking thread pool.
+pub async fn hash(password: &str) -> Result<String> {
+    let password = password.to_owned();
+    Ok(task::spawn_blocking(move || hash_blocking(&password)).await??)
+}
+
+/// Verifies a password against a PHC string, using the parameters it was hashed with
+pub async fn verify(password: &str, hash: &str) -> Result<Verified> {
+    let (password, hash) = (password.to_owned(), hash.to_owned());
+    Ok(task::spawn_blocking(move || verify_blocking(&password, &hash)).await??)
+}
+
+/// Takes as long as [`verify`] does, for users that don't exist or have no password
+pub async fn verify_nothing(password: &str) -> Result<()> {
+    let password = password.to_owned();
+    task::spawn_blocking(move || {
+        if let Some(hash) = &*DUMMY_HASH {
+            let _ = verify_blocking(&password, hash);
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+fn outdated(hash: &PasswordHash) -> bool {
+    let current = argon2().params();
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || Params::try_from(hash).map_or(true, |params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                != (current.m_cost(), current.t_cost(), current.p_cost())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load_with(vars: &[(&str, &str)]) -> Result<(Argon2<'static>, Policy)> {
+        let vars = vars
+            .iter()
+            .map(|&(name, value)| (name, value.to_owned()))
+            .collect::<HashMap<_, _>>();
+        load(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn loads_settings() {
+        let (argon2, policy) =
+            load_with(&[("ARGON2_T_COST", "3"), ("PASSWORD_MIN_LEN", "12")]).unwrap();
+        assert_eq!(argon2.params().t_cost(), 3);
+        assert_eq!(policy.min_len, 12);
+        assert!(policy.blocklist.contains("password"));
+    }
+
+    #[test]
+    fn describes_the_policy_as_a_schema() {
+        let (_, policy) = load_with(&[("PASSWORD_MIN_LEN", "10")]).unwrap();
+        let schema = policy.schema();
+        assert_eq!(schema.get("minLength"), Some(&json!(10)));
+        assert_eq!(schema.get("maxLength"), Some(&json!(128)));
+        assert_eq!(schema.get("pattern"), None);
+
+        // Any two of the five classes will do
+        let (_, policy) = load_with(&[("PASSWORD_MIN_CLASSES", "2")]).unwrap();
+        let schema = policy.schema();
+        let pattern = schema.get("pattern").and_then(|p| p.as_str()).unwrap();
+        assert_eq!(pattern.split('|').count(), 10);
+        assert_eq!(
+            schema.get("errorMessage").unwrap()["pattern"],
+            policy.message(&PasswordRule::CharacterClasses),
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for vars in [
+            [("PASSWORD_MIN_LEN", "eight")],
+            [("PASSWORD_MIN_LEN", "200")],
+            [("PASSWORD_MIN_CLASSES", "5")],
+            [("PASSWORD_MIN_ENTROPY", "inf")],
+            [("ARGON2_M_COST", "1")],
+            [("PASSWORD_BLOCKLIST", "/nonexistent/blocklist.txt")],
+        ] {
+            assert!(load_with(&vars).is_err(), "{vars:?}");
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! One-time codes for setting a new password without the old one, for users to get from an admin
+//! since there is no email to send a reset link to.
+
+use crate::{
+    jwt,
+    schema::{api, ids::UserId},
+    session,
+};
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, prelude::*};
+use sqlx::{query, query_scalar, Connection, SqliteConnection, SqlitePool};
+
+/// Lifetime of a reset code in seconds
+const RESET_TTL: i64 = 24 * 60 * 60;
+const CODE_LEN: usize = 24;
+
+/// Mints a reset code for a user, replacing any unused ones.
+/// Only its hash is stored, so it can't be shown again.
+pub async fn mint(pool: &SqlitePool, user: UserId) -> Result<api::ResetCode> {
+    let code = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(CODE_LEN)
+        .map(char::from)
+        .collect::<String>();
+    let hash = session::hash(&code);
+    let expires_at = jwt::now() + RESET_TTL;
+    let mut conn = pool.acquire().await?;
+
+    conn.transaction(|transact| {
+        Box::pin(async move {
+            query!(
+                "DELETE FROM reset_codes WHERE user_id = ? AND used_at IS NULL",
+                user,
+            )
+            .execute(&mut **transact)
+            .await?;
+
+            query!(
+                "INSERT INTO reset_codes (hash, user_id, expires_at) VALUES (?, ?, ?)",
+                hash,
+                user,
+                expires_at,
+            )
+            .execute(&mut **transact)
+            .await
+        })
+    })
+    .await?;
+
+    Ok(api::ResetCode { code, expires_at })
+}
+
+/// Uses up a reset code, returning the user it was minted for unless it is unknown, used or expired
+pub async fn redeem(conn: &mut SqliteConnection, code: &str) -> Result<Option<UserId>> {
+    let hash = session::hash(code.trim());
+    let now = jwt::now();
+
+    let user = query_scalar!(
+        r#"
+        UPDATE reset_codes SET used_at = ?1
+        WHERE hash = ?2 AND used_at IS NULL AND expires_at > ?1
+        RETURNING user_id as "user_id: UserId"
+        "#,
+        now,
+        hash,
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(user)
+}
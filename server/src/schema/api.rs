@@ -1,57 +1,451 @@
-use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use super::ids::{CalculationId, CardId, NoteId, SessionId, TodoId, UserId};
+use crate::{
+    password,
+    validate::{
+        check_field, Errors, Fields, MAX_USERNAME_LEN, MIN_USERNAME_LEN, PASSWORD_REQUIRED,
+        USERNAME_INVALID, USERNAME_PATTERN, USERNAME_TOO_LONG, USERNAME_TOO_SHORT,
+    },
+};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+use std::{borrow::Cow, collections::HashMap};
 
 macro_rules! schema {
-    ($( $name:item )*) => {
-        $(
-            #[derive(Clone, Serialize, Deserialize, Debug)]
-            #[serde(rename_all = "camelCase")]
-            $name
-        )*
+    ($( $(#[$attr:meta])* pub $kind:ident $name:ident $body:tt )*) => {
+        $(schema_type! { $(#[$attr])* pub $kind $name $body })*
+
+        /// Adds the JSON schemas of every type to a generator's definitions
+        pub fn definitions(gen: &mut SchemaGenerator) {
+            $(gen.subschema_for::<$name>();)*
+        }
     }
 }
 
-fn username<'de, D: Deserializer<'de>>(deser: D) -> Result<String, D::Error> {
-    let username = <&str>::deserialize(deser)?;
+/// A type of the schema, along with the [`Fields`] of structs so payloads are checked field by field
+macro_rules! schema_type {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident { $( $(#[$field_attr:meta])* pub $field:ident: $ty:ty ),* $(,)? }
+    ) => {
+        schema_type! {
+            @derive $(#[$attr])* pub struct $name { $( $(#[$field_attr])* pub $field: $ty ),* }
+        }
 
-    if (4..=16).contains(&username.len())
-        && username
-            .chars()
-            .all(|c| c.is_ascii_alphanumeric() || c == '_')
-    {
-        Ok(username.into())
-    } else {
-        Err(D::Error::custom("Invalid username"))
-    }
+        impl Fields for $name {
+            fn check_fields(fields: &Map<String, Value>, errors: &mut Errors) {
+                $(check_field::<$ty>(fields, stringify!($field), errors);)*
+            }
+        }
+    };
+    ($(#[$attr:meta])* pub enum $name:ident $body:tt) => {
+        schema_type! { @derive $(#[$attr])* pub enum $name $body }
+
+        impl Fields for $name {}
+    };
+    (@derive $(#[$attr:meta])* pub $kind:ident $name:ident $body:tt) => {
+        #[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
+        #[serde(rename_all = "camelCase")]
+        $(#[$attr])*
+        pub $kind $name $body
+    };
 }
 
-fn password<'de, D: Deserializer<'de>>(deser: D) -> Result<String, D::Error> {
-    let password = <&str>::deserialize(deser)?;
+/// The username rules, with the messages clients show for each as an `errorMessage` keyword
+fn username_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "string",
+        "minLength": MIN_USERNAME_LEN,
+        "maxLength": MAX_USERNAME_LEN,
+        "pattern": USERNAME_PATTERN,
+        "errorMessage": {
+            "minLength": USERNAME_TOO_SHORT,
+            "maxLength": USERNAME_TOO_LONG,
+            "pattern": USERNAME_INVALID,
+        },
+    })
+}
 
-    if (6..=16).contains(&password.len()) && !password.chars().any(char::is_whitespace) {
-        Ok(password.into())
-    } else {
-        Err(D::Error::custom("Invalid password"))
-    }
+fn password_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "type": "string",
+        "minLength": 1,
+        "errorMessage": { "minLength": PASSWORD_REQUIRED },
+    })
+}
+
+/// The password policy as it is configured, so that clients generated from the schema follow it
+fn new_password_schema(_: &mut SchemaGenerator) -> Schema {
+    password::schema()
+}
+
+/// Distinguishes an explicit `null` from a missing field
+fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deser: D,
+) -> Result<Option<Option<T>>, D::Error> {
+    Option::deserialize(deser).map(Some)
 }
 
 schema! {
     pub struct Credentials {
-        #[serde(deserialize_with = "username")]
+        #[schemars(schema_with = "username_schema")]
+        pub username: String,
+        #[schemars(schema_with = "password_schema")]
+        pub password: String,
+    }
+
+    /// The credentials of a new account, whose password has to meet the password policy
+    pub struct Signup {
+        #[schemars(schema_with = "username_schema")]
         pub username: String,
-        #[serde(deserialize_with = "password")]
+        #[schemars(schema_with = "new_password_schema")]
         pub password: String,
     }
 
+    pub struct PasswordChange {
+        /// Ignored for accounts without a password, which set one by logging in recently instead
+        pub old_password: String,
+        #[schemars(schema_with = "new_password_schema")]
+        pub new_password: String,
+    }
+
+    pub struct UsernameChange {
+        #[schemars(schema_with = "username_schema")]
+        pub username: String,
+    }
+
+    /// Confirms a sensitive change to an account, such as deleting it
+    pub struct PasswordConfirmation {
+        /// Ignored for accounts without a password, which confirm by logging in recently instead
+        pub password: String,
+    }
+
+    pub enum PasswordRule {
+        MinLength,
+        MaxLength,
+        CharacterClasses,
+        Entropy,
+        Blocklist,
+    }
+
+    /// Something wrong with a field of a request
+    pub struct FieldError {
+        pub message: String,
+        /// The password policy rule a new password broke
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub rule: Option<PasswordRule>,
+    }
+
     pub struct Token {
         pub token: String,
+        pub refresh_token: String,
+    }
+
+    /// The token to repeat in the `X-CSRF-Token` header of requests authenticated by cookie
+    pub struct CsrfToken {
+        pub csrf_token: String,
+    }
+
+    /// The tokens of a new session, or a challenge to complete with a one-time code
+    /// if the account has two-factor authentication enabled
+    #[serde(untagged)]
+    pub enum Login {
+        Token(Token),
+        /// The tokens were set as cookies
+        Cookie(CsrfToken),
+        Challenge { challenge: String },
+    }
+
+    pub struct TotpLogin {
+        pub challenge: String,
+        /// A code from the authenticator app or one of the recovery codes
+        pub code: String,
+    }
+
+    pub struct TotpEnrollment {
+        pub secret: String,
+        pub uri: String,
+    }
+
+    pub struct TotpCode {
+        pub code: String,
+    }
+
+    /// Single-use codes for when the authenticator app is lost, only ever shown once
+    pub struct RecoveryCodes {
+        pub codes: Vec<String>,
+    }
+
+    pub struct Refresh {
+        /// Taken from the cookie if missing
+        pub refresh_token: Option<String>,
+    }
+
+    /// The identity provider's login page to send the user to
+    pub struct OidcRedirect {
+        pub url: String,
+    }
+
+    /// What the identity provider redirected back to the client with
+    pub struct OidcCallback {
+        pub code: String,
+        pub state: String,
+    }
+
+    /// A device the user is logged in on
+    pub struct Session {
+        pub id: SessionId,
+        pub user_agent: Option<String>,
+        pub ip: Option<String>,
+        pub created_at: i64,
+        pub last_seen_at: Option<i64>,
+        /// Whether this is the session the request was made with
+        pub current: bool,
+    }
+
+    /// What a user is allowed to do, each role allowing everything the previous ones do
+    #[derive(Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type, clap::ValueEnum)]
+    #[sqlx(rename_all = "lowercase")]
+    pub enum Role {
+        User,
+        Admin,
+    }
+
+    /// A user as administrators see them
+    pub struct UserSummary {
+        pub id: UserId,
+        pub username: String,
+        pub role: Role,
+        pub disabled_at: Option<i64>,
+        /// Number of cards in their layout
+        pub cards: i64,
+    }
+
+    pub struct UsersQuery {
+        /// Only users whose username contains this
+        pub q: Option<String>,
+        pub limit: Option<i64>,
+        pub offset: Option<i64>,
     }
 
+    pub struct UserUpdate {
+        /// Disabling an account logs it out everywhere and prevents logging in
+        pub disabled: Option<bool>,
+    }
+
+    /// A password set by an administrator
+    pub struct NewPassword {
+        #[schemars(schema_with = "new_password_schema")]
+        pub password: String,
+    }
+
+    /// A single-use code for a user to set a new password with, to be passed on to them
+    pub struct ResetCode {
+        pub code: String,
+        pub expires_at: i64,
+    }
+
+    pub struct PasswordReset {
+        pub code: String,
+        #[schemars(schema_with = "new_password_schema")]
+        pub new_password: String,
+    }
+
+    #[derive(PartialEq, Eq)]
     pub enum CardName {
         Calculator,
+        Notes,
+        Todo,
     }
 
     pub struct Card {
+        pub id: CardId,
+        pub name: CardName,
+        pub state: Option<Value>,
+    }
+
+    /// A user's cards at a layout version
+    pub struct Layout {
+        pub version: i64,
+        pub cards: Vec<Card>,
+    }
+
+    /// A change to a user's cards, pushed to their open event streams
+    #[serde(tag = "type")]
+    pub enum Event {
+        CardAdded { version: i64, pos: i64, card: Card },
+        CardUpdated { version: i64, pos: i64, card: Card },
+        CardRemoved { version: i64, id: CardId },
+        /// The stream fell behind, so the layout has to be refetched
+        Resync,
+    }
+
+    pub struct NewCard {
         pub name: CardName,
-        pub id: i64,
+        pub pos: Option<i64>,
+        pub state: Option<Value>,
+    }
+
+    pub struct CardUpdate {
+        pub pos: Option<i64>,
+        pub state: Option<Value>,
+    }
+
+    pub struct CardAction {
+        pub action: String,
+        #[serde(default)]
+        pub args: Value,
+    }
+
+    pub struct Note {
+        pub id: NoteId,
+        pub card: CardId,
+        pub title: String,
+        pub body: String,
+        pub created_at: i64,
+        pub updated_at: i64,
+    }
+
+    pub struct NotesQuery {
+        pub card: CardId,
+    }
+
+    pub struct NewNote {
+        pub card: CardId,
+        pub title: String,
+        pub body: String,
+    }
+
+    pub struct NoteUpdate {
+        pub title: Option<String>,
+        pub body: Option<String>,
+    }
+
+    pub struct NoteSearch {
+        pub q: String,
+    }
+
+    /// A note matching a search. Its snippets are escaped HTML with the matched words in `<mark>`.
+    pub struct NoteMatch {
+        pub id: NoteId,
+        pub card: CardId,
+        pub title: String,
+        pub body: String,
+        pub rank: f64,
+    }
+
+    pub struct Todo {
+        pub id: TodoId,
+        pub card: CardId,
+        pub text: String,
+        pub due_at: Option<i64>,
+        pub completed_at: Option<i64>,
+        pub created_at: i64,
+    }
+
+    pub struct TodosQuery {
+        pub card: CardId,
+    }
+
+    pub struct NewTodo {
+        pub card: CardId,
+        pub text: String,
+        pub due_at: Option<i64>,
+    }
+
+    pub struct TodoUpdate {
+        pub text: Option<String>,
+        #[serde(default, deserialize_with = "nullable")]
+        pub due_at: Option<Option<i64>>,
+        pub completed: Option<bool>,
+    }
+
+    /// Moves an item to just after another one of the same card, or to the top
+    pub struct TodoMove {
+        pub after: Option<TodoId>,
+    }
+
+    pub struct CalcEval {
+        pub expr: String,
+        /// Values of the variables used in the expression, as decimal strings
+        #[serde(default)]
+        pub vars: HashMap<String, String>,
+    }
+
+    /// An evaluated expression from a user's calculation history
+    pub struct Calculation {
+        pub id: CalculationId,
+        pub expr: String,
+        pub result: String,
+        pub created_at: i64,
+    }
+
+    /// The range of characters of an expression that could not be evaluated
+    pub struct ExprSpan {
+        pub start: usize,
+        pub end: usize,
+    }
+
+    /// What went wrong with a request, for clients to tell errors apart by
+    #[derive(Copy, PartialEq, Eq)]
+    pub enum ErrorCode {
+        Internal,
+        /// The body, path, query or headers could not be parsed
+        InvalidRequest,
+        /// Fields are missing or break rules, details are the [`FieldError`]s of each by JSON path
+        InvalidField,
+        PayloadTooLarge,
+        RateLimited,
+        Unauthenticated,
+        TokenExpired,
+        TokenRevoked,
+        InvalidToken,
+        InvalidRefreshToken,
+        /// The login has to be completed with a code from the authenticator app
+        TotpRequired,
+        CsrfFailed,
+        PermissionDenied,
+        AccountDisabled,
+        InvalidCredentials,
+        InvalidPassword,
+        /// The account has no password, so the change has to be confirmed by logging in again
+        ReauthenticationRequired,
+        UsernameTaken,
+        InvalidCode,
+        LoginExpired,
+        TotpNotEnabled,
+        TotpAlreadyEnabled,
+        NoPendingTotp,
+        InvalidResetCode,
+        OidcDisabled,
+        IdentityLinked,
+        CannotDisableSelf,
+        UserNotFound,
+        SessionNotFound,
+        CardNotFound,
+        NoteNotFound,
+        TodoNotFound,
+        /// The If-Match header is missing
+        VersionRequired,
+        /// The write was based on an outdated layout, details are the current [`Layout`]
+        StaleVersion,
+        CardExists,
+        WrongCardKind,
+        InvalidCardState,
+        CardActionFailed,
+        InvalidMove,
+        /// Details are the span of the expression the message refers to
+        InvalidExpression,
+    }
+
+    /// An error response, along the lines of RFC 7807 problem details
+    pub struct ApiError {
+        pub code: ErrorCode,
+        pub message: Cow<'static, str>,
+        /// The request field the error is about
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub field: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub details: Option<Value>,
     }
 }
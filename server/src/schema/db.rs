@@ -1,17 +1,60 @@
 #![allow(unused)]
-use super::ids::{CardId, UserId};
+use super::{
+    api::Role,
+    ids::{CalculationId, CardId, NoteId, TodoId, UserId},
+};
 
 pub struct User {
     pub id: UserId,
     pub username: String,
+    /// Empty for users who only log in through an identity provider
     pub password_hash: String,
-    pub password_salt_b64: String,
+    pub layout_version: i64,
+    pub role: Role,
+    pub disabled_at: Option<i64>,
+}
+
+pub struct Totp {
+    pub user_id: UserId,
+    pub secret: String,
+    pub confirmed_at: Option<i64>,
+    pub last_step: Option<i64>,
+    pub locked_until: Option<i64>,
 }
 
 pub struct Card {
     pub id: CardId,
     pub user_id: UserId,
     pub name: String,
-    pub client_id: i64,
     pub pos: i64,
+    pub state: Option<String>,
+}
+
+pub struct Note {
+    pub id: NoteId,
+    pub user_id: UserId,
+    pub card_id: CardId,
+    pub title: String,
+    pub body: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+pub struct Todo {
+    pub id: TodoId,
+    pub user_id: UserId,
+    pub card_id: CardId,
+    pub text: String,
+    pub pos: i64,
+    pub due_at: Option<i64>,
+    pub completed_at: Option<i64>,
+    pub created_at: i64,
+}
+
+pub struct Calculation {
+    pub id: CalculationId,
+    pub user_id: UserId,
+    pub expr: String,
+    pub result: String,
+    pub created_at: i64,
 }
@@ -1,14 +1,16 @@
 use core::str;
 use rand::{distributions::Alphanumeric, prelude::*};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
 use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
 use sqlx::encode::IsNull;
 use sqlx::error::BoxDynError;
 use sqlx::sqlite::SqliteArgumentValue;
 use sqlx::{Database, Decode, Encode, Sqlite, Type};
 use std::array;
+use std::borrow::Cow;
 use std::fmt::{self, Display, Formatter};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct Id([u8; 16]);
 
 impl Default for Id {
@@ -71,9 +73,39 @@ impl<'de> Deserialize<'de> for Id {
     }
 }
 
+impl JsonSchema for Id {
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn schema_name() -> Cow<'static, str> {
+        "Id".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "type": "string",
+            "minLength": 16,
+            "maxLength": 16,
+        })
+    }
+}
+
 macro_rules! id_type {
     ($name:ident) => {
-        #[derive(Copy, Clone, Type, Serialize, Deserialize, Default, Debug)]
+        #[derive(
+            Copy,
+            Clone,
+            PartialEq,
+            Eq,
+            Hash,
+            Type,
+            Serialize,
+            Deserialize,
+            JsonSchema,
+            Default,
+            Debug,
+        )]
         #[sqlx(transparent)]
         pub struct $name(pub Id);
 
@@ -87,3 +119,7 @@ macro_rules! id_type {
 
 id_type!(UserId);
 id_type!(CardId);
+id_type!(SessionId);
+id_type!(NoteId);
+id_type!(TodoId);
+id_type!(CalculationId);
@@ -0,0 +1,255 @@
+use crate::{
+    extract::device::Device,
+    jwt::{self, Claim},
+    schema::{
+        api,
+        ids::{SessionId, UserId},
+    },
+};
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, prelude::*};
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_scalar, Connection, SqliteConnection, SqlitePool};
+
+/// Lifetime of a refresh token in seconds
+pub const REFRESH_TTL: i64 = 30 * 24 * 60 * 60;
+const REFRESH_LEN: usize = 48;
+/// Seconds between updates of when a session was last seen, to save writes on every request
+const LAST_SEEN_INTERVAL: i64 = 60;
+
+/// Hashes a random token for storage, which unlike a password needs no salt or slow hash
+pub fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+async fn issue(
+    conn: &mut SqliteConnection,
+    user: UserId,
+    session: SessionId,
+) -> Result<api::Token> {
+    let refresh_token = thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(REFRESH_LEN)
+        .map(char::from)
+        .collect::<String>();
+    let hash = hash(&refresh_token);
+    let expires_at = jwt::now() + REFRESH_TTL;
+
+    query!(
+        "INSERT INTO refresh_tokens (hash, session_id, expires_at) VALUES (?, ?, ?)",
+        hash,
+        session,
+        expires_at,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(api::Token {
+        token: Claim::new(user, session).encode()?,
+        refresh_token,
+    })
+}
+
+/// Starts a new session for the user, i.e. a new refresh token family
+pub async fn start(pool: &SqlitePool, user: UserId, device: Device) -> Result<api::Token> {
+    let Device { user_agent, ip } = device;
+    let mut conn = pool.acquire().await?;
+
+    conn.transaction(|transact| {
+        Box::pin(async move {
+            let session = SessionId::default();
+            let now = jwt::now();
+
+            query!(
+                r#"
+                INSERT INTO sessions (id, user_id, created_at, last_seen_at, user_agent, ip)
+                VALUES (?1, ?2, ?3, ?3, ?4, ?5)
+                "#,
+                session,
+                user,
+                now,
+                user_agent,
+                ip,
+            )
+            .execute(&mut **transact)
+            .await?;
+
+            issue(transact, user, session).await
+        })
+    })
+    .await
+}
+
+/// Rotates a refresh token, returning `None` if it is unknown, expired or revoked.
+/// Presenting an already used token revokes its whole family.
+pub async fn refresh(pool: &SqlitePool, refresh_token: &str) -> Result<Option<api::Token>> {
+    let hash = hash(refresh_token);
+    let now = jwt::now();
+
+    // Using up the token is a single statement, so that concurrent refreshes can't both use it
+    let used = query!(
+        r#"
+        UPDATE refresh_tokens SET used = 1
+        WHERE hash = ? AND used = 0 AND expires_at > ?
+        RETURNING session_id as "session_id: SessionId"
+        "#,
+        hash,
+        now,
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(token) = used else {
+        let reused = query_scalar!(
+            r#"SELECT session_id as "session_id: SessionId" FROM refresh_tokens WHERE hash = ? AND used != 0"#,
+            hash,
+        )
+        .fetch_optional(pool)
+        .await?;
+        if let Some(session) = reused {
+            revoke(&mut *pool.acquire().await?, session).await?;
+        }
+        return Ok(None);
+    };
+
+    let mut conn = pool.acquire().await?;
+    conn.transaction(|transact| {
+        Box::pin(async move {
+            let Some(user) = query_scalar!(
+                r#"
+                UPDATE sessions SET last_seen_at = ?
+                WHERE id = ? AND revoked_at IS NULL
+                RETURNING user_id as "user_id: UserId"
+                "#,
+                now,
+                token.session_id,
+            )
+            .fetch_optional(&mut **transact)
+            .await?
+            else {
+                return Ok(None);
+            };
+
+            issue(transact, user, token.session_id).await.map(Some)
+        })
+    })
+    .await
+}
+
+/// Revokes a session, invalidating its refresh tokens and any access tokens issued for it
+pub async fn revoke(conn: &mut SqliteConnection, session: SessionId) -> Result<()> {
+    let now = jwt::now();
+
+    query!(
+        "UPDATE sessions SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+        now,
+        session,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Revokes all of a user's sessions, e.g. once their credentials changed
+pub async fn revoke_all(conn: &mut SqliteConnection, user: UserId) -> Result<()> {
+    let now = jwt::now();
+
+    query!(
+        "UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL",
+        now,
+        user,
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Checks that the session an access token was issued for has not been revoked,
+/// noting that it was just seen
+pub async fn touch(pool: &SqlitePool, claim: &Claim) -> Result<bool> {
+    let Some(session) = query!(
+        "SELECT last_seen_at FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+        claim.sid,
+        claim.sub,
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    let now = jwt::now();
+    if session
+        .last_seen_at
+        .is_none_or(|last_seen| now - last_seen >= LAST_SEEN_INTERVAL)
+    {
+        query!(
+            "UPDATE sessions SET last_seen_at = ? WHERE id = ?",
+            now,
+            claim.sid,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{TestApp, PASSWORD};
+    use axum::http::StatusCode;
+    use serde_json::json;
+
+    async fn refresh_token(app: &TestApp) -> String {
+        let credentials = json!({ "username": "alice", "password": PASSWORD });
+        let (status, res) = app
+            .request("POST", "/signup", None, Some(credentials))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        res["refreshToken"].as_str().unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn reusing_a_token_revokes_its_family() {
+        let app = TestApp::new().await;
+        let old = json!({ "refreshToken": refresh_token(&app).await });
+
+        let (status, res) = app
+            .request("POST", "/refresh", None, Some(old.clone()))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        let new = json!({ "refreshToken": res["refreshToken"] });
+
+        let (status, _) = app.request("POST", "/refresh", None, Some(old)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = app.request("POST", "/refresh", None, Some(new)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn uses_a_token_once_when_refreshing_concurrently() {
+        let app = TestApp::new().await;
+        let token = json!({ "refreshToken": refresh_token(&app).await });
+
+        let refresh = || app.request("POST", "/refresh", None, Some(token.clone()));
+        let (a, b, c, d) = tokio::join!(refresh(), refresh(), refresh(), refresh());
+        let statuses = [a.0, b.0, c.0, d.0];
+
+        // The losers count as reuse, which revokes whatever the winner got
+        assert!(statuses
+            .iter()
+            .all(|&status| status == StatusCode::OK || status == StatusCode::UNAUTHORIZED));
+        assert!(
+            statuses
+                .iter()
+                .filter(|&&status| status == StatusCode::OK)
+                .count()
+                <= 1
+        );
+        let (status, _) = app.request("POST", "/refresh", None, Some(token)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,20 @@
+use crate::{events::Events, oidc::Oidc};
+use axum::extract::FromRef;
+use sqlx::SqlitePool;
+
+#[derive(Clone, FromRef)]
+pub struct AppState {
+    pub pool: SqlitePool,
+    pub events: Events,
+    pub oidc: Oidc,
+}
+
+impl AppState {
+    pub fn new(pool: SqlitePool, oidc: Oidc) -> Self {
+        Self {
+            pool,
+            events: Events::default(),
+            oidc,
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! An API with a database of its own for tests to send requests to
+
+use crate::{
+    api,
+    jwt::{self, Claim},
+    oidc::Oidc,
+    password,
+    schema::{
+        api::CardName,
+        ids::{CardId, UserId},
+    },
+    state::AppState,
+};
+use axum::{
+    body::{self, Body},
+    http::{header, request, HeaderMap, Request, StatusCode},
+    Router,
+};
+use reqwest::{redirect::Policy, Url};
+use serde_json::{json, Value};
+use sqlx::{migrate, query, sqlite::SqliteConnectOptions, SqlitePool};
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::net::TcpListener;
+use tower::ServiceExt;
+
+#[path = "../examples/mock_idp/idp.rs"]
+mod mock_idp;
+
+/// A password that meets the password policy
+pub const PASSWORD: &str = "correct horse battery staple";
+/// The client page the mock identity provider redirects back to
+const REDIRECT_URI: &str = "http://localhost/login";
+
+pub struct TestApp {
+    pub pool: SqlitePool,
+    router: Router,
+    path: PathBuf,
+}
+
+impl TestApp {
+    pub async fn new() -> Self {
+        Self::with_oidc(Oidc::default()).await
+    }
+
+    /// An app logging in with a mock identity provider, which runs until the test ends
+    pub async fn with_mock_idp() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let idp = mock_idp::router(issuer.clone(), "mock_user".into()).unwrap();
+        tokio::spawn(async move { axum::serve(listener, idp).await });
+
+        Self::with_oidc(Oidc::mock(issuer, REDIRECT_URI)).await
+    }
+
+    pub async fn with_oidc(oidc: Oidc) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        jwt::load_test_keys();
+        password::load_test_config();
+        let path = env::temp_dir().join(format!(
+            "server-test-{}-{}.db",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed),
+        ));
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        migrate!().run(&pool).await.unwrap();
+
+        Self {
+            router: api::routes(AppState::new(pool.clone(), oidc), api::UNVERSIONED),
+            pool,
+            path,
+        }
+    }
+
+    /// Sends a request with an optional access token and JSON body, returning the status and
+    /// the JSON response, which is `null` if there is none
+    pub async fn request(
+        &self,
+        method: &str,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut req = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let (status, _, res) = self.send(req, body).await;
+        (status, res)
+    }
+
+    /// Sends a request with an optional JSON body, returning the status, the headers and
+    /// the JSON response, which is `null` if there is none
+    pub async fn send(
+        &self,
+        req: request::Builder,
+        body: Option<Value>,
+    ) -> (StatusCode, HeaderMap, Value) {
+        let req = match body {
+            Some(body) => req
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => req.body(Body::empty()),
+        };
+
+        let res = self.router.clone().oneshot(req.unwrap()).await.unwrap();
+        let (status, headers) = (res.status(), res.headers().clone());
+        let bytes = body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            headers,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    /// Signs up a user with [`PASSWORD`], returning their access token
+    pub async fn signup(&self, username: &str) -> String {
+        let credentials = json!({ "username": username, "password": PASSWORD });
+        let (status, res) = self
+            .request("POST", "/signup", None, Some(credentials))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        res["token"].as_str().unwrap().into()
+    }
+
+    /// Goes through the mock identity provider's login page as `username`, returning the code
+    /// and state it redirected back to the client with, and the cookie the login was started with
+    pub async fn oidc_authorize(&self, username: &str) -> (Value, String) {
+        let req = Request::builder().method("POST").uri("/oidc/authorize");
+        let (status, headers, res) = self.send(req, None).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        let cookie = headers[header::SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split_once(';').map_or(cookie, |(cookie, _)| cookie);
+        let mut url = res["url"].as_str().unwrap().parse::<Url>().unwrap();
+        url.query_pairs_mut().append_pair("login_hint", username);
+
+        let http = reqwest::Client::builder()
+            .redirect(Policy::none())
+            .build()
+            .unwrap();
+        let res = http.get(url).send().await.unwrap();
+        let location = res.headers()[header::LOCATION].to_str().unwrap();
+        let redirect = location.parse::<Url>().unwrap();
+        assert!(location.starts_with(REDIRECT_URI), "{location}");
+
+        let param = |name| {
+            redirect
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        let callback = json!({ "code": param("code"), "state": param("state") });
+        (callback, cookie.into())
+    }
+
+    /// Completes a login with the provider, sending along a cookie if there is one
+    pub async fn oidc_callback(
+        &self,
+        callback: Value,
+        cookie: Option<&str>,
+    ) -> (StatusCode, Value) {
+        let mut req = Request::builder().method("POST").uri("/oidc/callback");
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        let (status, _, res) = self.send(req, Some(callback)).await;
+        (status, res)
+    }
+
+    /// Logs in with the mock identity provider as `username`, returning the access token
+    pub async fn oidc_login(&self, username: &str) -> String {
+        let (callback, cookie) = self.oidc_authorize(username).await;
+        let (status, res) = self.oidc_callback(callback, Some(&cookie)).await;
+        assert_eq!(status, StatusCode::OK, "{res}");
+        res["token"].as_str().unwrap().into()
+    }
+
+    /// Puts a card at the top of a user's layout, with the card's default state
+    pub async fn add_card(&self, token: &str, name: CardName) -> CardId {
+        let user = user(token);
+        let id = CardId::default();
+        let state = name.card().default_state().map(|state| state.to_string());
+        let name = serde_json::to_string(&name).unwrap();
+
+        query!(
+            "INSERT INTO cards (id, user_id, name, pos, state) VALUES (?, ?, ?, 0, ?)",
+            id,
+            user,
+            name,
+            state,
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+
+        id
+    }
+}
+
+/// The user an access token belongs to
+pub fn user(token: &str) -> UserId {
+    Claim::decode(token).unwrap().sub
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.path.clone().into_os_string();
+            path.push(suffix);
+            let _ = fs::remove_file(path);
+        }
+    }
+}
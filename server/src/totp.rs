@@ -0,0 +1,190 @@
+//! Time-based one-time passwords (RFC 6238) as generated by authenticator apps.
+//! Everything takes the current time as an argument, so that codes can be checked against a fixed clock.
+
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, prelude::*};
+use sha1::Sha1;
+
+/// Seconds each code is valid for
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+/// Codes from this many steps before or after the current one are accepted to allow for clock drift
+const SKEW: i64 = 1;
+const SECRET_LEN: usize = 20;
+const ISSUER: &str = "Personal Page";
+
+pub const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Generates a new shared secret, base32 encoded as authenticator apps expect it
+pub fn secret() -> String {
+    let mut secret = [0u8; SECRET_LEN];
+    thread_rng().fill(&mut secret);
+
+    let mut encoded = String::new();
+    for chunk in secret.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |bits, &byte| bits << 8 | byte as u64);
+
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            encoded.push(BASE32[(bits >> (35 - i * 5)) as usize & 31] as char);
+        }
+    }
+    encoded
+}
+
+fn decode(secret: &str) -> Option<Vec<u8>> {
+    let mut decoded = vec![];
+    let (mut bits, mut len) = (0u64, 0);
+
+    for c in secret.bytes() {
+        let value = BASE32.iter().position(|&b| b == c.to_ascii_uppercase())?;
+        bits = bits << 5 | value as u64;
+        len += 5;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+/// The `otpauth://` URI authenticator apps enroll with, usually shown as a QR code
+pub fn uri(secret: &str, username: &str) -> String {
+    let issuer = ISSUER.replace(' ', "%20");
+    format!(
+        "otpauth://totp/{issuer}:{username}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP}"
+    )
+}
+
+/// The code for a time step, i.e. the unix time divided by [`STEP`]
+fn code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let truncated = u32::from_be_bytes(hash[offset..offset + 4].try_into().unwrap()) & 0x7fff_ffff;
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Checks a code at the unix time `now`, returning the time step it belongs to.
+/// Steps up to `last_step` were already used and are rejected, so that codes can't be replayed.
+pub fn verify(secret: &str, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let secret = decode(secret)?;
+    let code = code.trim();
+    let current = now.div_euclid(STEP);
+
+    (current - SKEW..=current + SKEW)
+        .filter(|&step| last_step.is_none_or(|last| step > last))
+        .find(|&step| self::code(&secret, step) == code)
+}
+
+/// The code an authenticator app shows at the unix time `now`
+#[cfg(test)]
+pub fn generate(secret: &str, now: i64) -> String {
+    code(&decode(secret).unwrap(), now.div_euclid(STEP))
+}
+
+/// Generates single-use codes for logging in without the authenticator app
+pub fn recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = thread_rng()
+                .sample_iter(Alphanumeric)
+                .take(RECOVERY_CODE_LEN)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect::<String>();
+            let (first, second) = code.split_at(RECOVERY_CODE_LEN / 2);
+            format!("{first}-{second}")
+        })
+        .collect()
+}
+
+/// Undoes the formatting of a recovery code, which is how it is hashed
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ASCII secret "12345678901234567890" of the RFC 6238 test vectors
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // The SHA-1 vectors are 8 digits long, 6 digit codes are their last 6
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        assert_eq!(decode(RFC_SECRET).unwrap(), b"12345678901234567890");
+        for (now, expected) in vectors {
+            let expected = &expected[2..];
+            assert_eq!(generate(RFC_SECRET, now), expected, "at {now}");
+            assert_eq!(
+                verify(RFC_SECRET, expected, now, None),
+                Some(now / STEP),
+                "at {now}",
+            );
+        }
+    }
+
+    #[test]
+    fn allows_for_skew() {
+        let now = 1234567890;
+        let step = now / STEP;
+
+        for skew in [-SKEW, 0, SKEW] {
+            let code = generate(RFC_SECRET, now + skew * STEP);
+            assert_eq!(verify(RFC_SECRET, &code, now, None), Some(step + skew));
+        }
+        for skew in [-SKEW - 1, SKEW + 1] {
+            let code = generate(RFC_SECRET, now + skew * STEP);
+            assert_eq!(verify(RFC_SECRET, &code, now, None), None);
+        }
+    }
+
+    #[test]
+    fn rejects_replayed_codes() {
+        let now = 1234567890;
+        let code = generate(RFC_SECRET, now);
+        let step = verify(RFC_SECRET, &code, now, None).unwrap();
+
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(step)), None);
+        assert_eq!(verify(RFC_SECRET, &code, now + STEP, Some(step)), None);
+
+        // Codes from before the last used step are as good as used
+        let earlier = generate(RFC_SECRET, now - STEP);
+        assert_eq!(verify(RFC_SECRET, &earlier, now, Some(step)), None);
+
+        let next = generate(RFC_SECRET, now + STEP);
+        assert_eq!(verify(RFC_SECRET, &next, now, Some(step)), Some(step + 1));
+    }
+
+    #[test]
+    fn round_trips_secrets() {
+        let secret = secret();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(decode(&secret).unwrap().len(), SECRET_LEN);
+        assert_eq!(decode("not base32!"), None);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    cards::{calculator::eval, notes, todo},
+    password,
+    schema::api::{self, ApiError, ErrorCode, FieldError},
+};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+use std::collections::HashMap;
+
+pub const MIN_USERNAME_LEN: usize = 4;
+pub const MAX_USERNAME_LEN: usize = 16;
+/// What usernames consist of, as a regex for clients
+pub const USERNAME_PATTERN: &str = "^[a-zA-Z0-9_]*$";
+
+pub const USERNAME_TOO_SHORT: &str = "Username must be at least 4 characters";
+pub const USERNAME_TOO_LONG: &str = "Username is too long";
+pub const USERNAME_INVALID: &str = "Username must only contain letters, numbers, and underscores";
+pub const PASSWORD_REQUIRED: &str = "Password is required";
+
+/// The rules a request payload has to follow beyond the shape its type gives it
+pub trait Validate {
+    /// Adds an error for every rule a field breaks, rather than stopping at the first one
+    fn validate(&self, _errors: &mut Errors) {}
+}
+
+/// The fields of a type of the schema, which the `schema!` macro lists for structs
+pub trait Fields {
+    /// Adds an error for every field of a JSON object that doesn't have the type it should,
+    /// rather than stopping at the first one. Missing fields are left to the JSON schema.
+    fn check_fields(_fields: &Map<String, Value>, _errors: &mut Errors) {}
+}
+
+/// Checks that a field has the type it should, if it is there. The field is named as in Rust,
+/// payloads name it in camel case.
+pub fn check_field<T: DeserializeOwned>(
+    fields: &Map<String, Value>,
+    field: &str,
+    errors: &mut Errors,
+) {
+    let mut words = field.split('_');
+    let name = words.next().unwrap_or_default().to_owned()
+        + &words
+            .map(|word| word[..1].to_uppercase() + &word[1..])
+            .collect::<String>();
+    let Some(value) = fields.get(&name) else {
+        return;
+    };
+
+    if let Err(err) = serde_path_to_error::deserialize::<_, T>(value) {
+        let path = err
+            .path()
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Seq { index } => Some(index.to_string()),
+                Segment::Map { key } => Some(key.clone()),
+                Segment::Enum { variant } => Some(variant.clone()),
+                Segment::Unknown => None,
+            })
+            .fold(name, |path, segment| format!("{path}.{segment}"));
+        errors.add(path, err.into_inner().to_string());
+    }
+}
+
+/// Field errors keyed by JSON path, in the dotted form zod reports them in, e.g. `vars.x`
+#[derive(Default)]
+pub struct Errors(Vec<(String, FieldError)>);
+
+impl Errors {
+    pub fn add(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        let error = FieldError {
+            message: message.into(),
+            rule: None,
+        };
+        self.push(path, error);
+    }
+
+    pub fn push(&mut self, path: impl Into<String>, error: FieldError) {
+        self.0.push((path.into(), error));
+    }
+
+    /// Rejects the request if there were any errors. The first is the message and field,
+    /// the details list the errors of every field.
+    pub fn into_result(self) -> Result<(), ApiError> {
+        let Some((field, error)) = self.0.first().cloned() else {
+            return Ok(());
+        };
+
+        let mut fields = HashMap::<_, Vec<_>>::new();
+        for (path, error) in self.0 {
+            fields.entry(path).or_default().push(error);
+        }
+
+        Err(ApiError::custom(ErrorCode::InvalidField, error.message)
+            .field(field)
+            .details(fields))
+    }
+}
+
+/// The rules of the username schema clients validate with, see [`USERNAME_PATTERN`]
+fn username(errors: &mut Errors, path: &str, username: &str) {
+    if username.len() < MIN_USERNAME_LEN {
+        errors.add(path, USERNAME_TOO_SHORT);
+    }
+    if username.len() > MAX_USERNAME_LEN {
+        errors.add(path, USERNAME_TOO_LONG);
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        errors.add(path, USERNAME_INVALID);
+    }
+}
+
+/// The password policy, for passwords being set rather than checked
+fn new_password(errors: &mut Errors, path: &str, password: &str) {
+    if let Err(violations) = password::check(password) {
+        for violation in violations {
+            errors.push(path, violation);
+        }
+    }
+}
+
+impl Validate for api::Credentials {
+    fn validate(&self, errors: &mut Errors) {
+        username(errors, "username", &self.username);
+        if self.password.is_empty() {
+            errors.add("password", PASSWORD_REQUIRED);
+        }
+    }
+}
+
+impl Validate for api::Signup {
+    fn validate(&self, errors: &mut Errors) {
+        username(errors, "username", &self.username);
+        new_password(errors, "password", &self.password);
+    }
+}
+
+impl Validate for api::UsernameChange {
+    fn validate(&self, errors: &mut Errors) {
+        username(errors, "username", &self.username);
+    }
+}
+
+fn note(errors: &mut Errors, title: Option<&str>, body: Option<&str>) {
+    if title.is_some_and(|title| title.len() > notes::MAX_TITLE_LEN) {
+        errors.add("title", "Note title is too long");
+    }
+    if body.is_some_and(|body| body.len() > notes::MAX_BODY_LEN) {
+        errors.add("body", "Note is too long");
+    }
+    let markers = [notes::MARKERS.0, notes::MARKERS.1];
+    if title.is_some_and(|title| title.contains(markers)) {
+        errors.add("title", "Note title contains invalid characters");
+    }
+    if body.is_some_and(|body| body.contains(markers)) {
+        errors.add("body", "Note contains invalid characters");
+    }
+}
+
+impl Validate for api::NewNote {
+    fn validate(&self, errors: &mut Errors) {
+        note(errors, Some(&self.title), Some(&self.body));
+    }
+}
+
+impl Validate for api::NoteUpdate {
+    fn validate(&self, errors: &mut Errors) {
+        note(errors, self.title.as_deref(), self.body.as_deref());
+    }
+}
+
+fn todo(errors: &mut Errors, text: Option<&str>) {
+    if text.is_some_and(str::is_empty) {
+        errors.add("text", "Todo item is empty");
+    }
+    if text.is_some_and(|text| text.len() > todo::MAX_TEXT_LEN) {
+        errors.add("text", "Todo item is too long");
+    }
+}
+
+impl Validate for api::NewTodo {
+    fn validate(&self, errors: &mut Errors) {
+        todo(errors, Some(&self.text));
+    }
+}
+
+impl Validate for api::TodoUpdate {
+    fn validate(&self, errors: &mut Errors) {
+        todo(errors, self.text.as_deref());
+    }
+}
+
+impl Validate for api::CalcEval {
+    fn validate(&self, errors: &mut Errors) {
+        if self.expr.trim().is_empty() {
+            errors.add("expr", "Expression is empty");
+        }
+        if self.expr.chars().count() > eval::MAX_EXPR_LEN {
+            errors.add("expr", "Expression is too long");
+        }
+
+        for (name, value) in &self.vars {
+            if !eval::variable_name(name) {
+                errors.add(format!("vars.{name}"), "Invalid variable name");
+            }
+            if eval::parse_number(value).is_none() {
+                errors.add(format!("vars.{name}"), "Invalid number");
+            }
+        }
+    }
+}
+
+impl Validate for api::PasswordChange {
+    fn validate(&self, errors: &mut Errors) {
+        new_password(errors, "newPassword", &self.new_password);
+    }
+}
+
+impl Validate for api::PasswordReset {
+    fn validate(&self, errors: &mut Errors) {
+        new_password(errors, "newPassword", &self.new_password);
+    }
+}
+
+impl Validate for api::NewPassword {
+    fn validate(&self, errors: &mut Errors) {
+        new_password(errors, "password", &self.password);
+    }
+}
+
+impl Validate for api::PasswordConfirmation {}
+impl Validate for api::TotpLogin {}
+impl Validate for api::TotpCode {}
+impl Validate for api::Refresh {}
+impl Validate for api::OidcCallback {}
+impl Validate for api::UserUpdate {}
+impl Validate for api::NewCard {}
+impl Validate for api::CardUpdate {}
+impl Validate for api::CardAction {}
+impl Validate for api::TodoMove {}